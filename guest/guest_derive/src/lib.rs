@@ -1,4 +1,4 @@
-#![doc(html_root_url = "https://docs.rs/wasm_plugin_guest_derive/0.1.5")]
+#![doc(html_root_url = "https://docs.rs/wasm_plugin_guest_derive/0.2.0")]
 #![deny(missing_docs)]
 
 //! This crate provides attribute macros used by [wasm_plugin_guest](https://crates.io/crates/wasm_plugin_guest)
@@ -10,70 +10,930 @@ use quote::{format_ident, quote};
 /// Builds an extern function which will handle serializing and
 /// deserializing of arguments and return values of the function it is applied
 /// to. The function must take only deserializable arguments and return
-/// a serializable result.
+/// a serializable result. Several values can be returned at once as a tuple,
+/// like `(String, Vec<i32>, bool)`, which the host receives as the same tuple.
 ///
 /// The name of the exported function will be mangled to
 /// `wasm_plugin_exported__ORIGINAL_NAME` The exported function is only
 /// intended to be used by [wasm_plugin_host](https://crates.io/crates/wasm_plugin_host)
+///
+/// `async fn`s are supported when wasm_plugin_guest's `async` feature is
+/// enabled. The exported function blocks on the future until it completes
+/// since calls into WASM are always synchronous from the host's perspective.
+///
+/// Arguments are deserialized into owned values so they can't be references.
+/// With `#[export_function(clone_args)]` the function may take `&str`, `&[T]`
+/// or `&T` and is passed a reference to a deserialized `String`, `Vec<T>` or
+/// `T`.
+///
+/// ```rust,ignore
+/// #[export_function(clone_args)]
+/// fn shout(text: &str) -> String {
+///     text.to_uppercase()
+/// }
+/// ```
+///
+/// With `#[export_function(argument_list)]` each argument is received as a
+/// separately serialized message, framed by its length as a little endian
+/// `u32`, rather than as a single tuple. This is what the host sends with
+/// `WasmPlugin::call_function_with_argument_list`, which lets it forward
+/// arguments it only has in serialized form.
+///
+/// With `#[export_function(raw_bytes)]` the function's return value is sent
+/// to the host as it is rather than serialized. The function must return a
+/// type which converts into `Vec<u8>`, like `Vec<u8>` or `String`. This
+/// avoids the overhead of serializing data which is already encoded, like
+/// cached JSON or image data, and the host receives exactly those bytes with
+/// `WasmPlugin::call_function_returning_raw_bytes`.
+///
+/// ```rust,ignore
+/// #[export_function(raw_bytes)]
+/// fn thumbnail() -> Vec<u8> {
+///     THUMBNAIL_PNG.to_vec()
+/// }
+/// ```
+///
+/// With `#[export_function(scalar)]` a function returning a single `i32`,
+/// `u32`, `i64`, `u64`, `f32` or `f64` is also exported so that it returns
+/// the number directly as a WASM value, without serializing it or writing it
+/// into a message buffer. The host's `call_function` methods use this
+/// automatically and deserialize the number as whatever type the call site
+/// expects, so hosts which don't know about the option still work.
+///
+/// ```rust,ignore
+/// #[export_function(scalar)]
+/// fn favorite_number_count() -> u32 {
+///     FAVORITE_NUMBERS.len() as u32
+/// }
+/// ```
+///
+/// Options can be combined, like `#[export_function(clone_args, argument_list)]`.
+#[proc_macro_attribute]
+pub fn export_function(args: TokenStream, input: TokenStream) -> TokenStream {
+    let options = match ExportOptions::parse(args) {
+        Ok(options) => options,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let ast = syn::parse_macro_input!(input as syn::ItemFn);
+    let remote_name = format_ident!("wasm_plugin_exported__{}", ast.sig.ident);
+
+    impl_function_export(&ast, &remote_name, options)
+}
+
+// The options given to `export_function`
+#[derive(Clone, Copy, Default)]
+struct ExportOptions {
+    clone_args: bool,
+    argument_list: bool,
+    raw_bytes: bool,
+    scalar: bool,
+}
+
+impl ExportOptions {
+    fn parse(args: TokenStream) -> syn::Result<Self> {
+        use syn::parse::Parser;
+        let args = syn::punctuated::Punctuated::<syn::Ident, syn::Token![,]>::parse_terminated
+            .parse(args)?;
+        let mut options = ExportOptions::default();
+        for arg in args {
+            if arg == "clone_args" {
+                options.clone_args = true;
+            } else if arg == "argument_list" {
+                options.argument_list = true;
+            } else if arg == "raw_bytes" {
+                options.raw_bytes = true;
+            } else if arg == "scalar" {
+                options.scalar = true;
+            } else {
+                return Err(syn::Error::new_spanned(
+                    arg,
+                    "the options are `clone_args`, `argument_list`, `raw_bytes` and `scalar`",
+                ));
+            }
+        }
+        if options.raw_bytes && options.scalar {
+            return Err(syn::Error::new(
+                proc_macro2::Span::call_site(),
+                "`scalar` can't be combined with `raw_bytes`",
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// Marks a function as the plugin's reset hook. The function must take no
+/// arguments and is exported under the canonical name
+/// `wasm_plugin_exported__reset` regardless of what it is called.
+///
+/// [wasm_plugin_host](https://crates.io/crates/wasm_plugin_host) calls the
+/// hook from `WasmPlugin::reset` to clear any global state the plugin has
+/// accumulated without reloading it.
+#[proc_macro_attribute]
+pub fn reset_function(_args: TokenStream, input: TokenStream) -> TokenStream {
+    let ast = syn::parse_macro_input!(input as syn::ItemFn);
+    if !ast.sig.inputs.is_empty() {
+        return syn::Error::new_spanned(
+            &ast.sig.inputs,
+            "a reset function must not take arguments",
+        )
+        .to_compile_error()
+        .into();
+    }
+    let remote_name = format_ident!("wasm_plugin_exported__reset");
+
+    impl_function_export(&ast, &remote_name, ExportOptions::default())
+}
+
+/// Marks a function as the plugin's init hook. The function must take a
+/// single deserializable argument and is exported under the canonical name
+/// `wasm_plugin_exported__init` regardless of what it is called.
+///
+/// [wasm_plugin_host](https://crates.io/crates/wasm_plugin_host) calls the
+/// hook once the plugin is instantiated with the config given to
+/// `WasmPluginBuilder::with_init_config`. It isn't called if no config was
+/// given.
+///
+/// ```rust,ignore
+/// #[init_function]
+/// fn init(config: Config) {
+///     CONFIG.with(|c| *c.borrow_mut() = Some(config));
+/// }
+/// ```
 #[proc_macro_attribute]
-pub fn export_function(_args: TokenStream, input: TokenStream) -> TokenStream {
+pub fn init_function(_args: TokenStream, input: TokenStream) -> TokenStream {
     let ast = syn::parse_macro_input!(input as syn::ItemFn);
+    if ast.sig.inputs.len() != 1 {
+        return syn::Error::new_spanned(
+            &ast.sig.inputs,
+            "an init function must take exactly one argument",
+        )
+        .to_compile_error()
+        .into();
+    }
+    let remote_name = format_ident!("wasm_plugin_exported__init");
 
-    impl_function_export(&ast)
+    impl_function_export(&ast, &remote_name, ExportOptions::default())
+}
+
+/// Marks the function which creates the state declared with
+/// `wasm_plugin_guest::global_state!`. It must take no arguments and return
+/// the state's type. It is called the first time the state is used.
+///
+/// ```rust,ignore
+/// #[plugin_init]
+/// fn new_counter() -> Counter {
+///     Counter { count: 0 }
+/// }
+///
+/// global_state!(Counter);
+/// ```
+#[proc_macro_attribute]
+pub fn plugin_init(_args: TokenStream, input: TokenStream) -> TokenStream {
+    let ast = syn::parse_macro_input!(input as syn::ItemFn);
+    impl_plugin_init(&ast).unwrap_or_else(|e| e.to_compile_error().into())
 }
 
-fn impl_function_export(ast: &syn::ItemFn) -> TokenStream {
+fn impl_plugin_init(ast: &syn::ItemFn) -> syn::Result<TokenStream> {
+    let sig = &ast.sig;
+    if !sig.inputs.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &sig.inputs,
+            "a plugin_init function must not take arguments",
+        ));
+    }
+    let ty = match &sig.output {
+        syn::ReturnType::Type(_, ty) => ty,
+        syn::ReturnType::Default => {
+            return Err(syn::Error::new_spanned(
+                sig,
+                "a plugin_init function must return the plugin's state",
+            ))
+        }
+    };
+    let name = &sig.ident;
+
+    Ok(quote! {
+        #ast
+
+        fn wasm_plugin_state_init() -> #ty {
+            #name()
+        }
+    }
+    .into())
+}
+
+/// Exports a function which only takes and returns numbers as a plain WASM
+/// function under its own name, without any serialization or message
+/// buffers. The host calls it with `WasmPlugin::call_function_scalar`, which
+/// is much faster than going through `export_function` for numerical
+/// kernels and other small functions called very often.
+///
+/// Arguments must be `i32`, `u32`, `i64`, `u64`, `f32` or `f64` and the
+/// function must return one of those or nothing. Since there is no message
+/// to report errors in the function can't fail other than by panicking.
+///
+/// ```rust,ignore
+/// #[export_scalar_function]
+/// fn hypotenuse(a: f64, b: f64) -> f64 {
+///     (a * a + b * b).sqrt()
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn export_scalar_function(_args: TokenStream, input: TokenStream) -> TokenStream {
+    let ast = syn::parse_macro_input!(input as syn::ItemFn);
+    impl_scalar_function_export(ast).unwrap_or_else(|e| e.to_compile_error().into())
+}
+
+fn impl_scalar_function_export(mut ast: syn::ItemFn) -> syn::Result<TokenStream> {
+    let sig = &ast.sig;
+    if let Some(asyncness) = &sig.asyncness {
+        return Err(syn::Error::new_spanned(
+            asyncness,
+            "a scalar function can't be async",
+        ));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &sig.generics,
+            "a scalar function can't be generic",
+        ));
+    }
+    for input in &sig.inputs {
+        let ty = match input {
+            syn::FnArg::Typed(t) => &t.ty,
+            syn::FnArg::Receiver(r) => {
+                return Err(syn::Error::new_spanned(
+                    r,
+                    "a scalar function can't take `self`",
+                ))
+            }
+        };
+        if scalar_type(ty).is_none() {
+            return Err(syn::Error::new_spanned(
+                ty,
+                "scalar function arguments must be `i32`, `u32`, `i64`, `u64`, `f32` or `f64`",
+            ));
+        }
+    }
+    if let syn::ReturnType::Type(..) = sig.output {
+        scalar_return_type(&sig.output)?;
+    }
+    ast.vis = syn::parse_quote!(pub);
+    ast.sig.abi = Some(syn::parse_quote!(extern "C"));
+    Ok(quote! {
+        #[no_mangle]
+        #ast
+    }
+    .into())
+}
+
+/// Exports a function which returns an iterator so that the host can
+/// receive its items one at a time with `WasmPlugin::call_function_streaming`
+/// rather than all at once. The items must be serializable.
+///
+/// Calling the function starts a new stream, replacing any unfinished one.
+/// Alongside it `wasm_plugin_exported__NAME_has_next` and
+/// `wasm_plugin_exported__NAME_next` are exported for the host to pull items
+/// from the stream with.
+///
+/// ```rust,ignore
+/// #[export_streaming_function]
+/// fn tokens(text: String) -> impl Iterator<Item = String> {
+///     text.split_whitespace()
+///         .map(|token| token.to_string())
+///         .collect::<Vec<_>>()
+///         .into_iter()
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn export_streaming_function(_args: TokenStream, input: TokenStream) -> TokenStream {
+    let ast = syn::parse_macro_input!(input as syn::ItemFn);
+    impl_streaming_function_export(&ast).unwrap_or_else(|e| e.to_compile_error().into())
+}
+
+fn impl_streaming_function_export(ast: &syn::ItemFn) -> syn::Result<TokenStream> {
+    let sig = &ast.sig;
+    if let Some(asyncness) = &sig.asyncness {
+        return Err(syn::Error::new_spanned(
+            asyncness,
+            "a streaming function can't be async",
+        ));
+    }
+    let name = &sig.ident;
+    let mut params = quote!();
+    let mut args = quote!();
+    for (i, input) in sig.inputs.iter().enumerate() {
+        let ty = match input {
+            syn::FnArg::Typed(t) => &t.ty,
+            syn::FnArg::Receiver(r) => {
+                return Err(syn::Error::new_spanned(
+                    r,
+                    "a streaming function can't take self",
+                ))
+            }
+        };
+        let arg = format_ident!("arg{}", i);
+        params = quote!(#params #arg: #ty,);
+        args = quote!(#args #arg,);
+    }
+
+    // The exported function only starts the stream, so it's exported with the
+    // signature of a function which takes the same arguments and returns ()
+    let mut start_sig = sig.clone();
+    start_sig.output = syn::ReturnType::Default;
+    let start = export_shim(
+        &start_sig,
+        &format_ident!("wasm_plugin_exported__{}", name),
+        quote!(),
+        quote!(wasm_plugin_stream_start),
+        ExportOptions::default(),
+    )?;
+    let has_next = format_ident!("wasm_plugin_exported__{}_has_next", name);
+    let next = format_ident!("wasm_plugin_exported__{}_next", name);
+
+    Ok(quote! {
+        #ast
+
+        const _: () = {
+            type WasmPluginStream = ::std::iter::Peekable<
+                ::std::boxed::Box<
+                    dyn ::std::iter::Iterator<
+                        Item = ::std::result::Result<(usize, usize), wasm_plugin_guest::MessageError>,
+                    >,
+                >,
+            >;
+
+            ::std::thread_local! {
+                static WASM_PLUGIN_STREAM: ::std::cell::RefCell<::std::option::Option<WasmPluginStream>> =
+                    ::std::cell::RefCell::new(::std::option::Option::None);
+            }
+
+            fn wasm_plugin_stream_start(#params) {
+                let stream: ::std::boxed::Box<dyn ::std::iter::Iterator<Item = _>> =
+                    ::std::boxed::Box::new(#name(#args).map(|item| wasm_plugin_guest::write_message(&item)));
+                WASM_PLUGIN_STREAM.with(|s| *s.borrow_mut() = ::std::option::Option::Some(stream.peekable()));
+            }
+
+            #start
+
+            #[no_mangle]
+            pub extern "C" fn #has_next() -> i32 {
+                WASM_PLUGIN_STREAM.with(|s| {
+                    s.borrow_mut()
+                        .as_mut()
+                        .map_or(false, |s| s.peek().is_some()) as i32
+                })
+            }
+
+            #[no_mangle]
+            pub extern "C" fn #next() -> u64 {
+                let item = WASM_PLUGIN_STREAM.with(|s| s.borrow_mut().as_mut().and_then(|s| s.next()));
+                let (ptr, len) = match item {
+                    ::std::option::Option::Some(::std::result::Result::Ok(message)) => message,
+                    ::std::option::Option::Some(::std::result::Result::Err(e)) => {
+                        return wasm_plugin_guest::report_error(e)
+                    }
+                    ::std::option::Option::None => {
+                        return wasm_plugin_guest::report_error(wasm_plugin_guest::MessageError::new(
+                            "the stream has no more items",
+                        ))
+                    }
+                };
+                let mut fat = wasm_plugin_guest::FatPointer(0);
+                fat.set_ptr(ptr as u32);
+                fat.set_len(len as u32);
+                fat.0
+            }
+        };
+    }
+    .into())
+}
+
+fn impl_function_export(
+    ast: &syn::ItemFn,
+    remote_name: &syn::Ident,
+    options: ExportOptions,
+) -> TokenStream {
     let name = &ast.sig.ident;
-    let remote_name = format_ident!("wasm_plugin_exported__{}", name);
-    let gen = if ast.sig.inputs.is_empty() {
-        quote! {
+    match export_shim(&ast.sig, remote_name, quote!(), quote!(#name), options) {
+        Ok(gen) => quote!(#gen #ast).into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+// The type an argument of type `ty` is deserialized as and whether the
+// function is passed a reference to it rather than the value itself.
+// References are only allowed with `clone_args`.
+fn argument_type(ty: &syn::Type, clone_args: bool) -> syn::Result<(syn::Type, bool)> {
+    let r = match ty {
+        syn::Type::Reference(r) => r,
+        _ => return Ok((ty.clone(), false)),
+    };
+    let (owned, suggestion): (syn::Type, _) = match r.elem.as_ref() {
+        syn::Type::Path(p) if p.path.is_ident("str") => (
+            syn::parse_quote!(::std::string::String),
+            "String".to_string(),
+        ),
+        syn::Type::Slice(s) => {
+            let elem = &s.elem;
+            (
+                syn::parse_quote!(::std::vec::Vec<#elem>),
+                format!("Vec<{}>", quote!(#elem)),
+            )
+        }
+        elem => (elem.clone(), quote!(#elem).to_string()),
+    };
+    if r.mutability.is_some() {
+        return Err(syn::Error::new_spanned(
+            ty,
+            "exported functions can't take mutable references",
+        ));
+    }
+    if !clone_args {
+        return Err(syn::Error::new_spanned(
+            ty,
+            format!(
+                "exported functions can't take references, take `{}` instead or use `#[export_function(clone_args)]`",
+                suggestion
+            ),
+        ));
+    }
+    Ok((owned, true))
+}
+
+// Builds the extern function which reads the arguments for `sig` out of a
+// message, calls `callee` with them and writes the result back. `setup` runs
+// before the call and can bind anything `callee` refers to.
+fn export_shim(
+    sig: &syn::Signature,
+    remote_name: &syn::Ident,
+    setup: proc_macro2::TokenStream,
+    callee: proc_macro2::TokenStream,
+    options: ExportOptions,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let invoke = |args| {
+        if sig.asyncness.is_some() {
+            quote!(wasm_plugin_guest::block_on(#callee(#args)))
+        } else {
+            quote!(#callee(#args))
+        }
+    };
+    let write = |call| {
+        if options.raw_bytes {
+            quote!(wasm_plugin_guest::write_raw_message(::std::convert::Into::<::std::vec::Vec<u8>>::into(#call)))
+        } else {
+            quote!(wasm_plugin_guest::write_message(&#call))
+        }
+    };
+    let inputs: Vec<_> = sig
+        .inputs
+        .iter()
+        .filter_map(|arg| match arg {
+            syn::FnArg::Typed(t) => Some(t),
+            syn::FnArg::Receiver(_) => None,
+        })
+        .collect();
+    // With `scalar` a second export returns the number directly
+    let scalar_shim = |params, read, call| -> syn::Result<proc_macro2::TokenStream> {
+        if !options.scalar {
+            return Ok(quote!());
+        }
+        let (kind, wasm_type) = scalar_return_type(&sig.output)?;
+        let scalar_name = format_ident!(
+            "{}",
+            remote_name.to_string().replacen(
+                "wasm_plugin_exported__",
+                &format!("wasm_plugin_exported_{}__", kind),
+                1
+            )
+        );
+        Ok(quote! {
+            #[no_mangle]
+            pub extern "C" fn #scalar_name(#params) -> #wasm_type {
+                #read
+                #setup
+                #call as #wasm_type
+            }
+        })
+    };
+    if inputs.is_empty() {
+        let scalar = scalar_shim(quote!(), quote!(), invoke(quote!()))?;
+        let write = write(invoke(quote!()));
+        Ok(quote! {
+            #scalar
+
             #[no_mangle]
             pub extern "C" fn #remote_name() -> u64 {
-                let (ptr, len) = wasm_plugin_guest::write_message(&#name());
+                #setup
+                let (ptr, len) = match #write {
+                    Ok(message) => message,
+                    Err(e) => return wasm_plugin_guest::report_error(e),
+                };
                 let mut fat = wasm_plugin_guest::FatPointer(0);
                 fat.set_ptr(ptr as u32);
                 fat.set_len(len as u32);
                 fat.0
             }
-        }
+        })
     } else {
         let mut argument_types = quote!();
+        let mut next_arguments = quote!();
         let mut call = quote!();
-        if ast.sig.inputs.len() == 1 {
-            if let syn::FnArg::Typed(t) = &ast.sig.inputs[0] {
-                let ty = &t.ty;
-                argument_types = quote!(#ty);
+        if inputs.len() == 1 {
+            let (ty, borrow) = argument_type(&inputs[0].ty, options.clone_args)?;
+            argument_types = quote!(#ty);
+            next_arguments = quote!(arguments.next::<#ty>()?);
+            call = if borrow {
+                quote!(&message)
             } else {
-                panic!();
-            }
-            call = quote!(message);
+                quote!(message)
+            };
         } else {
-            for (i, arg) in ast.sig.inputs.iter().enumerate() {
+            for (i, arg) in inputs.iter().enumerate() {
                 let i = syn::Index::from(i);
-                call = quote!(#call message.#i,);
-                if let syn::FnArg::Typed(t) = arg {
-                    let ty = &t.ty;
-                    argument_types = quote!(#argument_types #ty,);
+                let (ty, borrow) = argument_type(&arg.ty, options.clone_args)?;
+                call = if borrow {
+                    quote!(#call &message.#i,)
                 } else {
-                    panic!();
-                }
+                    quote!(#call message.#i,)
+                };
+                argument_types = quote!(#argument_types #ty,);
+                next_arguments = quote!(#next_arguments arguments.next::<#ty>()?,);
             }
             argument_types = quote! { (#argument_types) };
+            next_arguments = quote! { (#next_arguments) };
         }
-        quote! {
+        let read = if options.argument_list {
+            quote! {
+                (|| {
+                    let mut arguments = wasm_plugin_guest::ArgumentList::read(ptr as usize, len as usize)?;
+                    let message: #argument_types = #next_arguments;
+                    arguments.finish()?;
+                    ::std::result::Result::Ok::<_, wasm_plugin_guest::MessageError>(message)
+                })()
+            }
+        } else {
+            quote!(wasm_plugin_guest::read_message(ptr as usize, len as usize))
+        };
+        let scalar = scalar_shim(
+            quote!(ptr: u32, len: u32),
+            quote! {
+                let message:#argument_types = match #read {
+                    Ok(message) => message,
+                    Err(e) => wasm_plugin_guest::fail_call(e),
+                };
+            },
+            invoke(call.clone()),
+        )?;
+        let write = write(invoke(call));
+        Ok(quote! {
+            #scalar
+
             #[no_mangle]
             pub extern "C" fn #remote_name(ptr: u32, len: u32) -> u64 {
-                let message:#argument_types = wasm_plugin_guest::read_message(ptr as usize, len as usize);
+                let message:#argument_types = match #read {
+                    Ok(message) => message,
+                    Err(e) => return wasm_plugin_guest::report_error(e),
+                };
+                #setup
 
-                let (ptr, len) = wasm_plugin_guest::write_message(&#name(#call));
+                let (ptr, len) = match #write {
+                    Ok(message) => message,
+                    Err(e) => return wasm_plugin_guest::report_error(e),
+                };
                 let mut fat = wasm_plugin_guest::FatPointer(0);
                 fat.set_ptr(ptr as u32);
                 fat.set_len(len as u32);
                 fat.0
             }
+        })
+    }
+}
+
+// The name of `ty` if it's a scalar type, as used in the names of scalar
+// exports, and the WASM type it's passed as
+fn scalar_type(ty: &syn::Type) -> Option<(String, syn::Type)> {
+    let ident = match ty {
+        syn::Type::Path(p) => p.path.get_ident()?,
+        _ => return None,
+    };
+    let wasm_type = match ident.to_string().as_str() {
+        "i32" | "u32" => syn::parse_quote!(i32),
+        "i64" | "u64" => syn::parse_quote!(i64),
+        "f32" => syn::parse_quote!(f32),
+        "f64" => syn::parse_quote!(f64),
+        _ => return None,
+    };
+    Some((ident.to_string(), wasm_type))
+}
+
+// The scalar type `output` returns, see `scalar_type`
+fn scalar_return_type(output: &syn::ReturnType) -> syn::Result<(String, syn::Type)> {
+    match output {
+        syn::ReturnType::Type(_, ty) => scalar_type(ty),
+        syn::ReturnType::Default => None,
+    }
+    .ok_or_else(|| {
+        syn::Error::new_spanned(
+            output,
+            "scalar functions must return one of `i32`, `u32`, `i64`, `u64`, `f32` or `f64`",
+        )
+    })
+}
+
+/// Exports every `pub fn` in an `impl` block just like `export_function`
+/// would, under the function's name. This keeps related entry points
+/// together without annotating each one.
+///
+/// Only associated functions without a receiver can be exported this way,
+/// use `export_plugin_impl` for methods which need `self`. Functions which
+/// aren't `pub` are left alone.
+///
+/// ```rust,ignore
+/// struct Geometry;
+///
+/// #[export_functions]
+/// impl Geometry {
+///     pub fn area(size: (f32, f32)) -> f32 {
+///         size.0 * size.1
+///     }
+///
+///     pub fn perimeter(size: (f32, f32)) -> f32 {
+///         2.0 * (size.0 + size.1)
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn export_functions(_args: TokenStream, input: TokenStream) -> TokenStream {
+    let ast = syn::parse_macro_input!(input as syn::ItemImpl);
+    impl_functions_export(&ast).unwrap_or_else(|e| e.to_compile_error().into())
+}
+
+fn impl_functions_export(ast: &syn::ItemImpl) -> syn::Result<TokenStream> {
+    if !ast.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &ast.generics,
+            "export_functions does not support generic types",
+        ));
+    }
+    let self_ty = &ast.self_ty;
+    let mut shims = quote!();
+    for item in &ast.items {
+        let method = match item {
+            syn::ImplItem::Method(method) if matches!(method.vis, syn::Visibility::Public(_)) => {
+                method
+            }
+            _ => continue,
+        };
+        let sig = &method.sig;
+        if let Some(receiver) = sig.receiver() {
+            return Err(syn::Error::new_spanned(
+                receiver,
+                "export_functions can't export methods which take self, use export_plugin_impl instead",
+            ));
         }
+        let name = &sig.ident;
+        let remote_name = format_ident!("wasm_plugin_exported__{}", name);
+        let gen = export_shim(
+            sig,
+            &remote_name,
+            quote!(),
+            quote!(<#self_ty>::#name),
+            ExportOptions::default(),
+        )?;
+        shims = quote!(#shims #gen);
+    }
+
+    Ok(quote! {
+        #ast
+        #shims
+    }
+    .into())
+}
+
+/// Exports methods of a type which holds the plugin's state between calls.
+/// It is applied to an `impl` block and exports every method in it marked
+/// with `#[export_method]` just like `export_function` would, under the
+/// method's name.
+///
+/// A single instance of the type is kept in a static `Mutex` and created on
+/// first use by the associated function marked with `#[plugin_init]`, or by
+/// `Default::default()` if there is none. Methods that take `&self` or
+/// `&mut self` are called on that instance while methods without a receiver
+/// are exported like free functions.
+///
+/// ```rust,ignore
+/// struct Counter {
+///     count: u32,
+/// }
+///
+/// #[export_plugin_impl]
+/// impl Counter {
+///     #[plugin_init]
+///     fn new() -> Self {
+///         Counter { count: 0 }
+///     }
+///
+///     #[export_method]
+///     fn increment(&mut self, by: u32) -> u32 {
+///         self.count += by;
+///         self.count
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn export_plugin_impl(_args: TokenStream, input: TokenStream) -> TokenStream {
+    let ast = syn::parse_macro_input!(input as syn::ItemImpl);
+    impl_plugin_impl_export(ast, false).unwrap_or_else(|e| e.to_compile_error().into())
+}
+
+/// Exports every method of a trait implementation, which lets a plugin
+/// implement a trait shared with the host. The host side is generated from
+/// the same trait by `wasm_plugin_host::wasm_plugin_client`.
+///
+/// It works like `export_plugin_impl` except that no `#[export_method]`
+/// markers are needed: each method of the trait is exported under its own
+/// name. The implementing type must implement `Default`, which is used to
+/// create the instance that methods taking `&self` or `&mut self` are called
+/// on.
+///
+/// ```rust,ignore
+/// // In a crate shared by the host and the plugin
+/// pub trait Geometry {
+///     fn area(&self, width: f32, height: f32) -> f32;
+/// }
+///
+/// // In the plugin
+/// #[derive(Default)]
+/// struct Plugin;
+///
+/// #[wasm_plugin_server]
+/// impl Geometry for Plugin {
+///     fn area(&self, width: f32, height: f32) -> f32 {
+///         width * height
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn wasm_plugin_server(_args: TokenStream, input: TokenStream) -> TokenStream {
+    let ast = syn::parse_macro_input!(input as syn::ItemImpl);
+    if ast.trait_.is_none() {
+        return syn::Error::new_spanned(
+            &ast.self_ty,
+            "wasm_plugin_server must be applied to a trait implementation, use export_plugin_impl for inherent impls",
+        )
+        .to_compile_error()
+        .into();
+    }
+    impl_plugin_impl_export(ast, true).unwrap_or_else(|e| e.to_compile_error().into())
+}
+
+// Exports the methods of `ast` marked with `#[export_method]` or, if
+// `export_all` is set, every method.
+fn impl_plugin_impl_export(mut ast: syn::ItemImpl, export_all: bool) -> syn::Result<TokenStream> {
+    if !ast.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &ast.generics,
+            "export_plugin_impl does not support generic types",
+        ));
+    }
+    let self_ty = ast.self_ty.clone();
+    // Trait methods are only callable with the trait in scope.
+    let (trait_use, qualified_self) = match &ast.trait_ {
+        Some((_, path, _)) => (quote!(use #path as _;), quote!(<#self_ty as #path>)),
+        None => (quote!(), quote!(<#self_ty>)),
     };
-    quote!(#gen #ast).into()
+    let mut init = None;
+    let mut shims = quote!();
+    for item in &mut ast.items {
+        let method = match item {
+            syn::ImplItem::Method(method) => method,
+            _ => continue,
+        };
+        let is_export = take_attribute(&mut method.attrs, "export_method") || export_all;
+        let is_init = take_attribute(&mut method.attrs, "plugin_init");
+        let sig = &method.sig;
+        let name = &sig.ident;
+        if is_init {
+            if !sig.inputs.is_empty() {
+                return Err(syn::Error::new_spanned(
+                    &sig.inputs,
+                    "a plugin_init function must not take arguments",
+                ));
+            }
+            if init.is_some() {
+                return Err(syn::Error::new_spanned(
+                    sig,
+                    "only one function may be marked plugin_init",
+                ));
+            }
+            init = Some(quote!(<#self_ty>::#name));
+        }
+        if is_export {
+            let remote_name = format_ident!("wasm_plugin_exported__{}", name);
+            let gen = match sig.receiver() {
+                None => export_shim(
+                    sig,
+                    &remote_name,
+                    quote!(),
+                    quote!(#qualified_self::#name),
+                    ExportOptions::default(),
+                )?,
+                Some(syn::FnArg::Receiver(r)) if r.reference.is_some() => {
+                    let setup = quote! {
+                        #trait_use
+                        let mut state = WASM_PLUGIN_STATE.lock().unwrap();
+                        let state = state.get_or_insert_with(wasm_plugin_state_init);
+                    };
+                    export_shim(
+                        sig,
+                        &remote_name,
+                        setup,
+                        quote!(state.#name),
+                        ExportOptions::default(),
+                    )?
+                }
+                Some(receiver) => {
+                    return Err(syn::Error::new_spanned(
+                        receiver,
+                        "exported methods must take self by reference",
+                    ))
+                }
+            };
+            shims = quote!(#shims #gen);
+        }
+    }
+    let init = init.unwrap_or_else(|| quote!(<#self_ty as ::std::default::Default>::default));
+
+    Ok(quote! {
+        #ast
+
+        const _: () = {
+            static WASM_PLUGIN_STATE: ::std::sync::Mutex<::std::option::Option<#self_ty>> =
+                ::std::sync::Mutex::new(::std::option::Option::None);
+
+            fn wasm_plugin_state_init() -> #self_ty {
+                #init()
+            }
+            #shims
+        };
+    }
+    .into())
+}
+
+// Removes the marker attribute `name` from `attrs`, returning whether it was
+// present.
+fn take_attribute(attrs: &mut Vec<syn::Attribute>, name: &str) -> bool {
+    let len = attrs.len();
+    attrs.retain(|attr| !attr.path.is_ident(name));
+    attrs.len() != len
+}
+
+// The expression sending the argument `ident` of type `ty` to the host. `&str`
+// arguments are sent as `String`s, which every serialization format supports
+// and which the host can receive as `String` or, with
+// `import_function_by_ref`, as `&str`.
+fn import_argument(ident: &syn::PatIdent, ty: &syn::Type) -> proc_macro2::TokenStream {
+    let ident = &ident.ident;
+    match ty {
+        syn::Type::Reference(r) if matches!(r.elem.as_ref(), syn::Type::Path(p) if p.path.is_ident("str")) =>
+        {
+            quote!(::std::string::String::from(#ident))
+        }
+        _ => quote!(#ident),
+    }
+}
+
+// The message an imported function sends to the host: its only argument or
+// a tuple of all of them.
+fn import_message(f: &syn::Signature) -> proc_macro2::TokenStream {
+    let mut message = quote!();
+    if f.inputs.len() == 1 {
+        if let syn::FnArg::Typed(syn::PatType { pat: p, ty, .. }) = &f.inputs[0] {
+            if let syn::Pat::Ident(i) = p.as_ref() {
+                message = import_argument(i, ty);
+            } else {
+                unimplemented!("unsupported argument type");
+            }
+        } else {
+            unimplemented!("unsupported argument type");
+        }
+    } else {
+        for item in &f.inputs {
+            if let syn::FnArg::Typed(syn::PatType { pat: p, ty, .. }) = item {
+                if let syn::Pat::Ident(i) = p.as_ref() {
+                    let arg = import_argument(i, ty);
+                    message = quote!(#message #arg,);
+                } else {
+                    unimplemented!("unsupported argument type");
+                }
+            } else {
+                unimplemented!("unsupported argument type");
+            }
+        }
+        message = quote!((#message));
+    }
+    message
 }
 
 struct FnImports {
@@ -93,31 +953,114 @@ impl syn::parse::Parse for FnImports {
 
 /// Import functions from the host program. The function's arguments an return
 /// type must all be serializable. Several functions can be imported at once
-/// by listing their signatures seperated by `;`
+/// by listing their signatures seperated by `;`. Arguments may also be
+/// `&str`, which are sent to the host as `String`s.
 ///
-/// ```rust
+/// ```rust,ignore
 /// import_functions! {
 ///     fn my_function();
 ///     fn my_other_function(s: String) -> Vec<u8>;
+///     fn log_message(message: &str);
+/// }
+/// ```
+/// Functions with several arguments send them to the host as a single tuple,
+/// the same message `WasmPlugin::call_function_with_argument` sends for a
+/// tuple argument, so the host imports them as closures taking the same
+/// arguments, like `|a: i32, b: i32| a + b`.
+///
+/// ```rust,ignore
+/// import_functions! {
+///     fn add(a: i32, b: i32) -> i32;
+///     fn interpolate(x: f64, y: f64, t: f64) -> f64;
 /// }
+///
+/// assert_eq!(add(2, 3), 5);
+/// let halfway = interpolate(0.0, 10.0, 0.5);
 /// ```
+///
 /// The macro creates a safe wrapper function using the given name which can
 /// be called in the plugin code. The actual imported function, which normal
 /// code will never need to access, will have a mangled name:
 /// `wasm_plugin_imported__ORIGINAL_NAME` and is only intended to be called by
 /// by host code using [wasm_plugin_host](https://crates.io/crates/wasm_plugin_host)
+///
+/// A host function which may fail can be declared as returning
+/// `Result<T, E>`, matching a host closure which returns the same `Result`.
+/// The error arrives as a value the plugin can handle, which needs a serde
+/// based serialization feature. To abort instead, see
+/// `import_function_with_result!`.
+///
+/// ```rust,ignore
+/// import_functions! {
+///     fn load_texture(path: &str) -> Result<Vec<u8>, String>;
+/// }
+///
+/// let texture = load_texture("grass.png").unwrap_or_default();
+/// ```
 #[proc_macro]
 pub fn import_functions(input: TokenStream) -> TokenStream {
     let ast = syn::parse_macro_input!(input as FnImports);
-    impl_import_functions(&ast)
+    impl_import_functions(&ast, false)
+}
+
+/// Import functions from the host program which may fail. They are declared
+/// like they are for `import_functions!`, with the type they return when
+/// they succeed, and the host provides them with
+/// `WasmPluginBuilder::import_function_returning_result`.
+///
+/// ```rust,ignore
+/// import_function_with_result! {
+///     fn load_texture(path: &str) -> Vec<u8>;
+///     fn save_settings(settings: Settings);
+/// }
+/// ```
+/// If the host function returns an error the wrapper panics with a message
+/// containing the error's `Display` output, which the host will see as a
+/// trap.
+#[proc_macro]
+pub fn import_function_with_result(input: TokenStream) -> TokenStream {
+    let ast = syn::parse_macro_input!(input as FnImports);
+    impl_import_functions(&ast, true)
 }
 
-fn impl_import_functions(ast: &FnImports) -> TokenStream {
+fn impl_import_functions(ast: &FnImports, fallible: bool) -> TokenStream {
     let mut remote_fns = quote!();
     let mut local_fns = quote!();
     for f in ast.functions.iter().cloned() {
         let remote_name = format_ident!("wasm_plugin_imported__{}", f.ident);
-        let gen = if f.inputs.is_empty() {
+        let gen = if fallible {
+            let name = f.ident.to_string();
+            let ty = match &f.output {
+                syn::ReturnType::Default => quote!(()),
+                syn::ReturnType::Type(_, ty) => quote!(#ty),
+            };
+            let (send, call) = if f.inputs.is_empty() {
+                (quote!(), quote!(#remote_name()))
+            } else {
+                let message = import_message(&f);
+                (
+                    quote! {
+                        let (ptr, len) = wasm_plugin_guest::write_message(&(#message))
+                            .unwrap_or_else(|e| panic!("Failed to send the arguments to the host: {}", e));
+                    },
+                    quote!(#remote_name(ptr as u32, len as u32)),
+                )
+            };
+            quote! {
+                #f {
+                    #send
+                    let fat_ptr = unsafe {
+                        #call
+                    };
+                    let fat_ptr = wasm_plugin_guest::FatPointer(fat_ptr);
+                    match wasm_plugin_guest::read_result_message::<#ty>(fat_ptr.ptr() as usize, fat_ptr.len() as usize) {
+                        Ok(Ok(message)) => message,
+                        Ok(Err(e)) => panic!("The host function '{}' failed: {}", #name, e),
+                        Err(e) => panic!("Failed to read the value returned by the host: {}", e),
+                    }
+                }
+            }
+        } else if f.inputs.is_empty() {
             match &f.output {
                 syn::ReturnType::Default => {
                     quote! {
@@ -135,43 +1078,21 @@ fn impl_import_functions(ast: &FnImports) -> TokenStream {
                                 #remote_name()
                             };
                             let fat_ptr = wasm_plugin_guest::FatPointer(fat_ptr);
-                            let message:(#ty) = wasm_plugin_guest::read_message(fat_ptr.ptr() as usize, fat_ptr.len() as usize);
+                            let message:(#ty) = wasm_plugin_guest::read_message(fat_ptr.ptr() as usize, fat_ptr.len() as usize)
+                                .unwrap_or_else(|e| panic!("Failed to read the value returned by the host: {}", e));
                             message
                         }
                     }
                 }
             }
         } else {
-            let mut message = quote!();
-            if f.inputs.len() == 1 {
-                if let syn::FnArg::Typed(syn::PatType { pat: p, .. }) = &f.inputs[0] {
-                    if let syn::Pat::Ident(i) = p.as_ref() {
-                        message = quote!(#i);
-                    } else {
-                        unimplemented!("unsupported argument type");
-                    }
-                } else {
-                    unimplemented!("unsupported argument type");
-                }
-            } else {
-                for item in &f.inputs {
-                    if let syn::FnArg::Typed(syn::PatType { pat: p, .. }) = item {
-                        if let syn::Pat::Ident(i) = p.as_ref() {
-                            message = quote!(#i,);
-                        } else {
-                            unimplemented!("unsupported argument type");
-                        }
-                    } else {
-                        unimplemented!("unsupported argument type");
-                    }
-                }
-                message = quote!((#message));
-            }
+            let message = import_message(&f);
             match &f.output {
                 syn::ReturnType::Default => {
                     quote! {
                         #f {
-                            let (ptr, len) = wasm_plugin_guest::write_message(&#message);
+                            let (ptr, len) = wasm_plugin_guest::write_message(&#message)
+                                .unwrap_or_else(|e| panic!("Failed to send the arguments to the host: {}", e));
                             unsafe {
                                 #remote_name(ptr as u32, len as u32);
                             }
@@ -181,12 +1102,14 @@ fn impl_import_functions(ast: &FnImports) -> TokenStream {
                 syn::ReturnType::Type(_, ty) => {
                     quote! {
                         #f {
-                            let (ptr, len) = wasm_plugin_guest::write_message(&(#message));
+                            let (ptr, len) = wasm_plugin_guest::write_message(&(#message))
+                                .unwrap_or_else(|e| panic!("Failed to send the arguments to the host: {}", e));
                             let fat_ptr = unsafe {
                                 #remote_name(ptr as u32, len as u32)
                             };
                             let fat_ptr = wasm_plugin_guest::FatPointer(fat_ptr);
-                            let message:(#ty) = wasm_plugin_guest::read_message(fat_ptr.ptr() as usize, fat_ptr.len() as usize);
+                            let message:(#ty) = wasm_plugin_guest::read_message(fat_ptr.ptr() as usize, fat_ptr.len() as usize)
+                                .unwrap_or_else(|e| panic!("Failed to read the value returned by the host: {}", e));
                             message
                         }
                     }
@@ -197,7 +1120,19 @@ fn impl_import_functions(ast: &FnImports) -> TokenStream {
             #local_fns
             #gen
         };
-        let gen = if f.inputs.is_empty() {
+        let gen = if fallible {
+            // Fallible functions always return a message so the host can
+            // say whether they succeeded
+            if f.inputs.is_empty() {
+                quote! {
+                    fn #remote_name() -> u64;
+                }
+            } else {
+                quote! {
+                    fn #remote_name(ptr: u32, len: u32) -> u64;
+                }
+            }
+        } else if f.inputs.is_empty() {
             match &f.output {
                 syn::ReturnType::Default => {
                     quote! {
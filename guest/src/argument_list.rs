@@ -0,0 +1,58 @@
+use crate::{deserialize_message, read_bytes, serialization::Deserializable, MessageError};
+
+/// The arguments of a function exported with
+/// `#[export_function(argument_list)]`, which the host sends with
+/// `WasmPlugin::call_function_with_argument_list`. Each argument is
+/// serialized separately and framed by its length as a little endian `u32`.
+/// You should never need to use this directly.
+///
+/// ```rust
+/// # use wasm_plugin_guest::ArgumentList;
+/// // A single empty argument, as sent for `()`
+/// let message = [0, 0, 0, 0];
+/// let mut arguments = ArgumentList::read(message.as_ptr() as usize, message.len()).unwrap();
+/// arguments.next::<()>().unwrap();
+/// arguments.finish().unwrap();
+/// ```
+pub struct ArgumentList {
+    data: std::borrow::Cow<'static, [u8]>,
+    offset: usize,
+}
+
+impl ArgumentList {
+    /// Read the framed arguments from a message sent by the host.
+    pub fn read(ptr: usize, len: usize) -> Result<Self, MessageError> {
+        Ok(ArgumentList {
+            data: read_bytes(ptr, len)?,
+            offset: 0,
+        })
+    }
+
+    /// Deserialize the next argument.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next<T: Deserializable>(&mut self) -> Result<T, MessageError> {
+        let rest = &self.data[self.offset..];
+        if rest.len() < 4 {
+            return Err(MessageError::new(
+                "the host sent fewer arguments than the function takes",
+            ));
+        }
+        let len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let argument = rest[4..]
+            .get(..len)
+            .ok_or_else(|| MessageError::new("an argument's length is longer than the message"))?;
+        self.offset += 4 + len;
+        deserialize_message(argument)
+    }
+
+    /// Check that every argument has been read.
+    pub fn finish(self) -> Result<(), MessageError> {
+        if self.offset == self.data.len() {
+            Ok(())
+        } else {
+            Err(MessageError::new(
+                "the host sent more arguments than the function takes",
+            ))
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::borrow::Cow;
+#[cfg(any(feature = "compress_zstd", feature = "compress_messages"))]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Compressed messages start with one of these headers so the reader can tell
+// them apart from raw ones. They must match the ones used by wasm_plugin_host.
+#[cfg(feature = "compress_zstd")]
+const ZSTD_MAGIC: &[u8] = b"\0WPZSTD\0";
+#[cfg(feature = "compress_messages")]
+const LZ4_MAGIC: &[u8] = b"\0WPLZ4F\0";
+
+#[cfg(any(feature = "compress_zstd", feature = "compress_messages"))]
+static THRESHOLD: AtomicUsize = AtomicUsize::new(4 * 1024);
+
+#[cfg(any(feature = "compress_zstd", feature = "compress_messages"))]
+pub(crate) fn set_threshold(bytes: usize) {
+    THRESHOLD.store(bytes, Ordering::Relaxed);
+}
+
+#[cfg(feature = "compress_zstd")]
+pub(crate) fn compress(message: Vec<u8>) -> Result<Vec<u8>, String> {
+    if message.len() < THRESHOLD.load(Ordering::Relaxed) {
+        return Ok(message);
+    }
+    let mut buffer = ZSTD_MAGIC.to_vec();
+    buffer.extend(zstd::bulk::compress(&message, 0).map_err(|e| e.to_string())?);
+    Ok(buffer)
+}
+
+#[cfg(all(feature = "compress_messages", not(feature = "compress_zstd")))]
+pub(crate) fn compress(message: Vec<u8>) -> Result<Vec<u8>, String> {
+    if message.len() < THRESHOLD.load(Ordering::Relaxed) {
+        return Ok(message);
+    }
+    let mut encoder = lz4_flex::frame::FrameEncoder::new(LZ4_MAGIC.to_vec());
+    std::io::Write::write_all(&mut encoder, &message).map_err(|e| e.to_string())?;
+    encoder.finish().map_err(|e| e.to_string())
+}
+
+#[cfg(not(any(feature = "compress_zstd", feature = "compress_messages")))]
+pub(crate) fn compress(message: Vec<u8>) -> Result<Vec<u8>, String> {
+    Ok(message)
+}
+
+pub(crate) fn decompress(message: &[u8]) -> Result<Cow<'_, [u8]>, String> {
+    #[cfg(feature = "compress_zstd")]
+    if let Some(compressed) = message.strip_prefix(ZSTD_MAGIC) {
+        let mut buffer = vec![];
+        zstd::Decoder::new(compressed)
+            .and_then(|mut decoder| std::io::Read::read_to_end(&mut decoder, &mut buffer))
+            .map_err(|e| e.to_string())?;
+        return Ok(Cow::Owned(buffer));
+    }
+    #[cfg(feature = "compress_messages")]
+    if let Some(compressed) = message.strip_prefix(LZ4_MAGIC) {
+        let mut buffer = vec![];
+        std::io::Read::read_to_end(
+            &mut lz4_flex::frame::FrameDecoder::new(compressed),
+            &mut buffer,
+        )
+        .map_err(|e| e.to_string())?;
+        return Ok(Cow::Owned(buffer));
+    }
+    Ok(Cow::Borrowed(message))
+}
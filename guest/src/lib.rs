@@ -1,4 +1,4 @@
-#![doc(html_root_url = "https://docs.rs/wasm_plugin_guest/0.1.5")]
+#![doc(html_root_url = "https://docs.rs/wasm_plugin_guest/0.2.0")]
 #![deny(missing_docs)]
 
 //! A low-ish level tool for easily writing WASM based plugins to be hosted by
@@ -13,19 +13,112 @@
 //! `serialize_bincode`: Uses serde and bincode. It is selected by default.
 //! `serialize_json`: Uses serde and serde_json.
 //! `serialize_nanoserde_json': Uses nanoserde.
+//! `serialize_cbor`: Uses serde and ciborium to produce CBOR (RFC 8949).
+//! `serialize_protobuf`: Uses prost to produce protocol buffers.
+//! `serialize_raw`: Passes raw bytes through untouched.
 //!
 //! Bincode is likely the best choice if all plugins the system uses will be
 //! written in Rust. Json is useful if a mix or languages will be used.
+//! CBOR is a good fit for plugins embedded in systems which already speak it,
+//! such as CoAP or COSE based ones.
+//! Protocol buffers suit plugin ecosystems which share `.proto` schemas
+//! across languages. With `serialize_protobuf` every argument and return
+//! type must be a `prost::Message`, such as a type generated by
+//! `prost-build`, `String`, `Vec<u8>`, a scalar or `()`. Tuples aren't
+//! messages so a plugin function takes at most one argument, unless it is
+//! exported with `#[export_function(argument_list)]` and called with
+//! `WasmPlugin::call_function_with_argument_list`.
+//!
+//! `serialize_raw` is for the smallest possible plugins. Select it with
+//! `default-features = false` and no serialization library is built into the
+//! plugin at all. `Vec<u8>`, `&[u8]`, `String`, `&str` and `()` are sent as
+//! their bytes and any other argument or return type must implement
+//! `serialization::Serializable` or `serialization::Deserializable` by hand.
+//! The host calls these functions with
+//! `WasmPlugin::call_function_returning_raw_bytes`. `host_logging` needs one
+//! of the other serialization features.
+//!
+//! If the `async` feature is selected then `export_function` can also be
+//! applied to `async fn`s. The exported function runs the future to
+//! completion on a simple single threaded executor before returning.
+//!
+//! If the `compress_zstd` feature is selected then every message the plugin
+//! sends is compressed with zstd, and compressed messages from the host are
+//! understood. The host needs its own `compress_zstd` feature to match.
+//!
+//! If the `compress_messages` feature is selected then messages are
+//! compressed as lz4 frames instead, which is faster but compresses less. The
+//! host needs its own `compress_messages` feature to match. With either
+//! feature messages smaller than 4 KiB are sent uncompressed, see
+//! `set_compression_threshold`.
+//!
+//! If the `either` feature is selected then `Either` is re-exported so
+//! exported functions can return `Either<A, B>` when they produce one of two
+//! types. It works with any of the serde based serialization features.
+//!
+//! If the `host_logging` feature is selected then the `log!`, `error!`,
+//! `warn!`, `info!`, `debug!` and `trace!` macros send log records to the
+//! host, which handles them with the logger it registered with
+//! `WasmPluginBuilder::with_logger`.
+//!
+//! If the `serialize_rkyv` feature is selected then exported functions can
+//! wrap their return value in `ZeroCopy` to send it as an rkyv archive, which
+//! the host reads in place with `WasmPlugin::call_function_zero_copy`. The
+//! usual serialization feature is still used for everything else.
+//!
+//! Functions which produce their results bit by bit can return an iterator
+//! and be exported with `export_streaming_function`, so the host can process
+//! each item as soon as it's ready.
+//!
+//! Related functions can be grouped in an `impl` block and exported together
+//! with `export_functions`.
+//!
+//! Plugins which keep state between calls can put it in a type and export
+//! its methods with `export_plugin_impl` instead of managing globals by hand.
+//! Alternatively `global_state!` declares accessors for a single global
+//! instance of the type which any function can use.
+//!
+//! When the host and the plugin share a trait describing the plugin's API,
+//! `wasm_plugin_server` exports a plugin's implementation of it and the host
+//! can call it through a client generated by
+//! `wasm_plugin_host::wasm_plugin_client`.
 //!
 //! Plugins are meant to be run using [wasm_plugin_host](https://crates.io/crates/wasm_plugin_host)
 
 use std::mem::ManuallyDrop;
 
-mod serialization;
-pub use wasm_plugin_guest_derive::{export_function, import_functions};
+mod argument_list;
+mod compression;
+#[cfg(feature = "host_logging")]
+#[doc(hidden)]
+pub mod logging;
+pub mod serialization;
+mod state;
+#[cfg(feature = "serialize_rkyv")]
+mod zero_copy;
+#[doc(hidden)]
+pub use argument_list::ArgumentList;
+pub use wasm_plugin_guest_derive::{
+    export_function, export_functions, export_plugin_impl, export_scalar_function,
+    export_streaming_function, import_function_with_result, import_functions, init_function,
+    plugin_init, reset_function, wasm_plugin_server,
+};
+#[cfg(feature = "serialize_rkyv")]
+pub use zero_copy::ZeroCopy;
+
+#[cfg(feature = "async")]
+#[doc(hidden)]
+pub use futures_executor::block_on;
+
+#[cfg(feature = "either")]
+pub use either::Either;
+
+#[cfg(feature = "host_logging")]
+pub use logging::LogLevel;
 
 bitfield::bitfield! {
     #[doc(hidden)]
+    #[allow(clippy::len_without_is_empty)]
     pub struct FatPointer(u64);
     u32;
     #[doc(hidden)]
@@ -34,25 +127,195 @@ bitfield::bitfield! {
     pub len, set_len: 63, 32;
 }
 
+/// A message couldn't be serialized or deserialized. This usually means the
+/// host and the plugin disagree about a function's signature.
+#[derive(Debug)]
+pub struct MessageError(String);
+
+impl std::fmt::Display for MessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+impl MessageError {
+    #[doc(hidden)]
+    pub fn new(message: impl Into<String>) -> Self {
+        MessageError(message.into())
+    }
+}
+
+/// The most bytes that will be read when deserializing a single message
+/// from the host. With bincode this stops a message which claims to hold a
+/// huge collection from exhausting the plugin's memory.
+pub const DESERIALIZATION_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// The version of the protocol used to talk to the host. It changes whenever
+/// a release of wasm_plugin_guest can no longer talk to the matching release
+/// of wasm_plugin_host, which checks it with `WasmPlugin::verify_abi_version`.
+pub const ABI_VERSION: u32 = 1;
+
 /// Read a message from a buffer created with `allocate_message_buffer`. You should
 /// never need to call this directly.
-pub fn read_message<T: serialization::Deserializable>(ptr: usize, len: usize) -> T {
-    let buf = unsafe { std::slice::from_raw_parts(ptr as *const u8, len as usize) };
-    T::deserialize(buf)
+///
+/// The host doesn't write anything for zero sized values, such as `()`, so
+/// an empty message produces one of those. Any other type, including `None`
+/// and empty collections, always arrives as a non-empty message and an empty
+/// one is an error rather than being mistaken for an empty value. The
+/// exception is `serialize_raw`, where an empty `Vec<u8>` or `String` is
+/// sent as an empty message.
+///
+/// ```rust
+/// # use wasm_plugin_guest::read_message;
+/// let unit: () = read_message(0, 0).unwrap();
+/// assert_eq!(unit, ());
+/// assert!(read_message::<Option<u32>>(0, 0).is_err());
+/// assert!(read_message::<Vec<u32>>(0, 0).is_err());
+/// ```
+pub fn read_message<T: serialization::Deserializable>(
+    ptr: usize,
+    len: usize,
+) -> Result<T, MessageError> {
+    deserialize_message(&read_bytes(ptr, len)?)
+}
+
+/// Read the message returned by a host function imported with
+/// `import_function_with_result!`. It starts with a byte which is `0` if the
+/// function succeeded, followed by the value it returned, or `1` if it
+/// failed, followed by the error's description as utf8. You should never need
+/// to call this directly.
+#[doc(hidden)]
+pub fn read_result_message<T: serialization::Deserializable>(
+    ptr: usize,
+    len: usize,
+) -> Result<Result<T, String>, MessageError> {
+    let buf = read_bytes(ptr, len)?;
+    match buf.split_first() {
+        Some((0, value)) => deserialize_message(value).map(Ok),
+        Some((1, error)) => Ok(Err(String::from_utf8_lossy(error).into_owned())),
+        _ => Err(MessageError(
+            "expected the host to say whether the function succeeded".to_string(),
+        )),
+    }
+}
+
+// Read the raw, decompressed, bytes of a message
+fn read_bytes(ptr: usize, len: usize) -> Result<std::borrow::Cow<'static, [u8]>, MessageError> {
+    if len == 0 {
+        // Fat pointers to empty messages may be null, which isn't a valid
+        // slice even when it has no elements
+        return Ok(std::borrow::Cow::Borrowed(&[]));
+    }
+    let buf = unsafe { std::slice::from_raw_parts(ptr as *const u8, len) };
+    compression::decompress(buf).map_err(MessageError)
+}
+
+fn deserialize_message<T: serialization::Deserializable>(buf: &[u8]) -> Result<T, MessageError> {
+    if buf.is_empty() {
+        T::deserialize_empty().map_err(|e| {
+            MessageError(format!(
+                "expected a {} but the message was empty: {}",
+                std::any::type_name::<T>(),
+                e
+            ))
+        })
+    } else {
+        T::deserialize(buf).map_err(MessageError)
+    }
 }
 
 /// Write a message to the buffer used to communicate with the host. You should
 /// never need to call this directly.
-pub fn write_message<U>(message: &U) -> (usize, usize)
+pub fn write_message<U>(message: &U) -> Result<(usize, usize), MessageError>
 where
     U: serialization::Serializable,
 {
-    let message: Vec<u8> = message.serialize();
-    let local_len = message.len();
-    (
-        ManuallyDrop::new(message).as_mut_ptr() as *const usize as usize,
-        local_len,
-    )
+    write_raw_message(message.serialize().map_err(MessageError)?)
+}
+
+/// Write bytes to the buffer used to communicate with the host as they are,
+/// without serializing them. You should never need to call this directly.
+pub fn write_raw_message(message: Vec<u8>) -> Result<(usize, usize), MessageError> {
+    let message = compression::compress(message).map_err(MessageError)?;
+    // The host frees messages with free_message_buffer, which needs the
+    // capacity to be exactly the length
+    let mut message = ManuallyDrop::new(message.into_boxed_slice());
+    Ok((message.as_mut_ptr() as *const usize as usize, message.len()))
+}
+
+/// Exports a `version` function returning the given version string, which
+/// the host can read with `WasmPlugin::plugin_version`. Without an argument
+/// the plugin crate's own version from its `Cargo.toml` is used.
+///
+/// ```rust,ignore
+/// wasm_plugin_guest::export_version!();
+/// ```
+#[macro_export]
+macro_rules! export_version {
+    () => {
+        $crate::export_version!(env!("CARGO_PKG_VERSION"));
+    };
+    ($version:expr) => {
+        #[$crate::export_function]
+        fn version() -> ::std::string::String {
+            ::std::string::String::from($version)
+        }
+    };
+}
+
+/// Only compress messages sent to the host which are at least `bytes` long.
+/// The default is 4 KiB, below which compression usually costs more than it
+/// saves.
+#[cfg(any(feature = "compress_zstd", feature = "compress_messages"))]
+pub fn set_compression_threshold(bytes: usize) {
+    compression::set_threshold(bytes);
+}
+
+thread_local! {
+    static LAST_ERROR: std::cell::RefCell<Option<String>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Record an error for the host to pick up with `wasm_plugin_last_error` and
+/// return the sentinel fat pointer which tells it to look. You should never
+/// need to call this directly.
+#[doc(hidden)]
+pub fn report_error(error: MessageError) -> u64 {
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(error.0));
+    // A real message is never written at address 0
+    0
+}
+
+/// Record an error for the host like `report_error` and abort the call, for
+/// exports which can't return the sentinel fat pointer because they return a
+/// number. You should never need to call this directly.
+#[doc(hidden)]
+pub fn fail_call(error: MessageError) -> ! {
+    report_error(error);
+    panic!("an exported function failed, the host has the error")
+}
+
+/// Returns the `ABI_VERSION` this plugin was built with.
+#[no_mangle]
+pub extern "C" fn wasm_plugin_abi_version() -> u32 {
+    ABI_VERSION
+}
+
+/// Returns the error recorded by the last exported function that failed, as
+/// raw utf8 rather than a serialized message so reporting it can't fail too.
+#[no_mangle]
+pub extern "C" fn wasm_plugin_last_error() -> u64 {
+    let error = LAST_ERROR
+        .with(|last_error| last_error.borrow_mut().take())
+        .unwrap_or_default();
+    // A boxed slice's capacity is exactly its length, which is what
+    // free_message_buffer expects
+    let error = ManuallyDrop::new(error.into_bytes().into_boxed_slice());
+    let mut fat = FatPointer(0);
+    fat.set_ptr(error.as_ptr() as u32);
+    fat.set_len(error.len() as u32);
+    fat.0
 }
 
 #[cfg(feature = "inject_getrandom")]
@@ -84,8 +347,15 @@ pub extern "C" fn allocate_message_buffer(len: u32) -> u32 {
     buffer.as_mut_ptr() as *const u32 as u32
 }
 
-/// Frees a previously allocated buffer.
+/// Frees a previously allocated buffer. `len` must be the length the buffer
+/// was allocated with, which is also its capacity.
 #[no_mangle]
 pub extern "C" fn free_message_buffer(ptr: u32, len: u32) {
-    unsafe { drop(Vec::from_raw_parts(ptr as *mut u8, 0, len as usize)) }
+    unsafe {
+        drop(Vec::from_raw_parts(
+            ptr as *mut u8,
+            len as usize,
+            len as usize,
+        ))
+    }
 }
@@ -0,0 +1,100 @@
+//! Sending log records to the host.
+
+/// The severity of a log record. The levels mean the same as they do in the
+/// `log` and `tracing` crates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    /// Something has failed
+    Error = 1,
+    /// Something may be wrong
+    Warn,
+    /// Useful information
+    Info,
+    /// Information useful when debugging
+    Debug,
+    /// Very detailed information
+    Trace,
+}
+
+// Log records are sent as a tuple, which protocol buffers can't encode, so
+// with `serialize_protobuf` they are sent as a message with the same fields.
+// This must match wasm_plugin_host.
+#[cfg(feature = "serialize_protobuf")]
+#[derive(Clone, PartialEq, prost::Message)]
+struct LogRecord {
+    #[prost(uint32, tag = "1")]
+    level: u32,
+    #[prost(string, tag = "2")]
+    target: String,
+    #[prost(string, tag = "3")]
+    message: String,
+}
+
+extern "C" {
+    fn wasm_plugin_imported____log(ptr: u32, len: u32);
+}
+
+/// Send a log record to the host. You should normally use `log!` or one of
+/// the macros for a specific level instead.
+#[doc(hidden)]
+pub fn host_log(level: LogLevel, target: &str, message: String) {
+    // Levels are sent as numbers so they work with every serialization format
+    #[cfg(not(feature = "serialize_protobuf"))]
+    let record = (level as u8, target.to_string(), message);
+    #[cfg(feature = "serialize_protobuf")]
+    let record = LogRecord {
+        level: level as u32,
+        target: target.to_string(),
+        message,
+    };
+    // There is nowhere to report a failure to log
+    if let Ok((ptr, len)) = crate::write_message(&record) {
+        unsafe {
+            wasm_plugin_imported____log(ptr as u32, len as u32);
+        }
+    }
+}
+
+/// Log a message at the given level, formatted like `format!`. The record is
+/// sent to the logger the host registered with
+/// `WasmPluginBuilder::with_logger`.
+///
+/// ```rust,ignore
+/// wasm_plugin_guest::log!(LogLevel::Info, "loaded {} items", items.len());
+/// ```
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        $crate::logging::host_log($level, ::std::module_path!(), ::std::format!($($arg)+))
+    };
+}
+
+/// Log a message at the `Error` level, formatted like `format!`.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::LogLevel::Error, $($arg)+) };
+}
+
+/// Log a message at the `Warn` level, formatted like `format!`.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::LogLevel::Warn, $($arg)+) };
+}
+
+/// Log a message at the `Info` level, formatted like `format!`.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::LogLevel::Info, $($arg)+) };
+}
+
+/// Log a message at the `Debug` level, formatted like `format!`.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::LogLevel::Debug, $($arg)+) };
+}
+
+/// Log a message at the `Trace` level, formatted like `format!`.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::LogLevel::Trace, $($arg)+) };
+}
@@ -1,43 +1,179 @@
+//! The traits used to send values to and receive them from the host. They are
+//! implemented by the serialization feature that is selected, or by hand with
+//! `serialize_raw`.
+
+/// Types which can be sent to the host.
 pub trait Serializable {
-    fn serialize(&self) -> Vec<u8>;
+    /// Encode the value as the bytes of a message.
+    fn serialize(&self) -> Result<Vec<u8>, String>;
 }
 #[cfg(feature = "serialize_bincode")]
 impl<T: serde::Serialize> Serializable for T {
-    fn serialize(&self) -> Vec<u8> {
-        bincode::serialize(self).unwrap()
+    fn serialize(&self) -> Result<Vec<u8>, String> {
+        bincode::serialize(self).map_err(|e| e.to_string())
     }
 }
 #[cfg(feature = "serialize_json")]
 impl<T: serde::Serialize> Serializable for T {
-    fn serialize(&self) -> Vec<u8> {
-        serde_json::to_vec(self).unwrap()
+    fn serialize(&self) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(self).map_err(|e| e.to_string())
+    }
+}
+#[cfg(feature = "serialize_cbor")]
+impl<T: serde::Serialize> Serializable for T {
+    fn serialize(&self) -> Result<Vec<u8>, String> {
+        let mut buffer = vec![];
+        ciborium::ser::into_writer(self, &mut buffer).map_err(|e| e.to_string())?;
+        Ok(buffer)
     }
 }
 #[cfg(feature = "serialize_nanoserde_json")]
 impl<T: nanoserde::SerJson> Serializable for T {
-    fn serialize(&self) -> Vec<u8> {
-        nanoserde::SerJson::serialize_json(self).as_bytes().to_vec()
+    fn serialize(&self) -> Result<Vec<u8>, String> {
+        Ok(nanoserde::SerJson::serialize_json(self).as_bytes().to_vec())
+    }
+}
+#[cfg(feature = "serialize_protobuf")]
+impl<T: prost::Message> Serializable for T {
+    fn serialize(&self) -> Result<Vec<u8>, String> {
+        Ok(self.encode_to_vec())
     }
 }
 
-pub trait Deserializable {
-    fn deserialize(data: &[u8]) -> Self;
+/// Types which can be received from the host.
+pub trait Deserializable: Sized {
+    /// Decode a value from the bytes of a message.
+    fn deserialize(data: &[u8]) -> Result<Self, String>;
+
+    /// Construct a value from an empty message, which the host sends for
+    /// zero sized values such as `()`. Fails for types which can't be empty.
+    #[doc(hidden)]
+    fn deserialize_empty() -> Result<Self, String> {
+        Self::deserialize(&[])
+    }
 }
 #[cfg(feature = "serialize_bincode")]
 impl<T: serde::de::DeserializeOwned + Clone> Deserializable for T {
-    fn deserialize(data: &[u8]) -> Self {
-        bincode::deserialize(data).unwrap()
+    fn deserialize(data: &[u8]) -> Result<Self, String> {
+        use bincode::Options;
+        // The same encoding as `bincode::deserialize`, plus the limit
+        bincode::options()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(crate::DESERIALIZATION_LIMIT)
+            .deserialize(data)
+            .map_err(|e| e.to_string())
+    }
+
+    fn deserialize_empty() -> Result<Self, String> {
+        deserialize_unit()
     }
 }
 #[cfg(feature = "serialize_json")]
 impl<T: serde::de::DeserializeOwned + Clone> Deserializable for T {
-    fn deserialize(data: &[u8]) -> Self {
-        serde_json::from_slice(data).unwrap()
+    fn deserialize(data: &[u8]) -> Result<Self, String> {
+        serde_json::from_slice(data).map_err(|e| e.to_string())
     }
+
+    fn deserialize_empty() -> Result<Self, String> {
+        deserialize_unit()
+    }
+}
+#[cfg(feature = "serialize_cbor")]
+impl<T: serde::de::DeserializeOwned + Clone> Deserializable for T {
+    fn deserialize(data: &[u8]) -> Result<Self, String> {
+        ciborium::de::from_reader(data).map_err(|e| e.to_string())
+    }
+
+    fn deserialize_empty() -> Result<Self, String> {
+        deserialize_unit()
+    }
+}
+#[cfg(any(
+    feature = "serialize_bincode",
+    feature = "serialize_json",
+    feature = "serialize_cbor"
+))]
+fn deserialize_unit<T: serde::de::DeserializeOwned>() -> Result<T, String> {
+    use serde::de::IntoDeserializer;
+    // Serde would happily turn the unit into `None`, but `None` is never
+    // sent as an empty message
+    if std::mem::size_of::<T>() != 0 {
+        return Err("only zero sized values are sent as empty messages".to_string());
+    }
+    let deserializer: serde::de::value::UnitDeserializer<serde::de::value::Error> =
+        ().into_deserializer();
+    T::deserialize(deserializer).map_err(|e| e.to_string())
 }
 #[cfg(feature = "serialize_nanoserde_json")]
 impl<T: nanoserde::DeJson> Deserializable for T {
-    fn deserialize(data: &[u8]) -> Self {
-        nanoserde::DeJson::deserialize_json(std::str::from_utf8(data).unwrap()).unwrap()
+    fn deserialize(data: &[u8]) -> Result<Self, String> {
+        let data = std::str::from_utf8(data).map_err(|e| e.to_string())?;
+        nanoserde::DeJson::deserialize_json(data).map_err(|e| e.to_string())
+    }
+}
+#[cfg(feature = "serialize_protobuf")]
+impl<T: prost::Message + Default> Deserializable for T {
+    fn deserialize(data: &[u8]) -> Result<Self, String> {
+        T::decode(data).map_err(|e| e.to_string())
+    }
+}
+
+// With `serialize_raw` messages are passed through untouched and other types
+// implement the traits by hand.
+#[cfg(feature = "serialize_raw")]
+impl Serializable for Vec<u8> {
+    fn serialize(&self) -> Result<Vec<u8>, String> {
+        Ok(self.clone())
+    }
+}
+#[cfg(feature = "serialize_raw")]
+impl Serializable for &[u8] {
+    fn serialize(&self) -> Result<Vec<u8>, String> {
+        Ok(self.to_vec())
+    }
+}
+#[cfg(feature = "serialize_raw")]
+impl Serializable for String {
+    fn serialize(&self) -> Result<Vec<u8>, String> {
+        Ok(self.as_bytes().to_vec())
+    }
+}
+#[cfg(feature = "serialize_raw")]
+impl Serializable for &str {
+    fn serialize(&self) -> Result<Vec<u8>, String> {
+        Ok(self.as_bytes().to_vec())
+    }
+}
+#[cfg(feature = "serialize_raw")]
+impl Serializable for () {
+    fn serialize(&self) -> Result<Vec<u8>, String> {
+        Ok(vec![])
+    }
+}
+#[cfg(feature = "serialize_raw")]
+impl Deserializable for Vec<u8> {
+    fn deserialize(data: &[u8]) -> Result<Self, String> {
+        Ok(data.to_vec())
+    }
+
+    fn deserialize_empty() -> Result<Self, String> {
+        Ok(vec![])
+    }
+}
+#[cfg(feature = "serialize_raw")]
+impl Deserializable for String {
+    fn deserialize(data: &[u8]) -> Result<Self, String> {
+        String::from_utf8(data.to_vec()).map_err(|e| e.to_string())
+    }
+
+    fn deserialize_empty() -> Result<Self, String> {
+        Ok(String::new())
+    }
+}
+#[cfg(feature = "serialize_raw")]
+impl Deserializable for () {
+    fn deserialize(_data: &[u8]) -> Result<Self, String> {
+        Ok(())
     }
 }
@@ -0,0 +1,68 @@
+/// Declares global state for a plugin which keeps data between calls,
+/// without needing `unsafe` or `static mut`.
+///
+/// `global_state!(MyPlugin)` defines `plugin_state()` and
+/// `plugin_state_mut()` which borrow the single instance of `MyPlugin`. It is
+/// created on first use by the function marked with `#[plugin_init]`, which
+/// must be declared in the same module and take no arguments.
+///
+/// The state is borrowed like a `RefCell`, so calling `plugin_state_mut()`
+/// while another borrow is still alive panics.
+///
+/// ```rust
+/// use wasm_plugin_guest::{global_state, plugin_init};
+///
+/// struct Counter {
+///     count: u32,
+/// }
+///
+/// #[plugin_init]
+/// fn new_counter() -> Counter {
+///     Counter { count: 0 }
+/// }
+///
+/// global_state!(Counter);
+///
+/// fn increment(by: u32) -> u32 {
+///     let mut counter = plugin_state_mut();
+///     counter.count += by;
+///     counter.count
+/// }
+///
+/// assert_eq!(increment(2), 2);
+/// assert_eq!(increment(3), 5);
+/// assert_eq!(plugin_state().count, 5);
+/// ```
+#[macro_export]
+macro_rules! global_state {
+    ($ty:ty) => {
+        ::std::thread_local! {
+            // Plugins are single threaded so leaking the state once gives it
+            // the 'static lifetime the accessors need
+            static WASM_PLUGIN_GLOBAL_STATE: ::std::cell::OnceCell<&'static ::std::cell::RefCell<$ty>> =
+                const { ::std::cell::OnceCell::new() };
+        }
+
+        fn wasm_plugin_global_state() -> &'static ::std::cell::RefCell<$ty> {
+            WASM_PLUGIN_GLOBAL_STATE.with(|state| {
+                *state.get_or_init(|| {
+                    ::std::boxed::Box::leak(::std::boxed::Box::new(::std::cell::RefCell::new(
+                        wasm_plugin_state_init(),
+                    )))
+                })
+            })
+        }
+
+        /// Borrow the plugin's global state.
+        #[allow(dead_code)]
+        fn plugin_state() -> ::std::cell::Ref<'static, $ty> {
+            wasm_plugin_global_state().borrow()
+        }
+
+        /// Mutably borrow the plugin's global state.
+        #[allow(dead_code)]
+        fn plugin_state_mut() -> ::std::cell::RefMut<'static, $ty> {
+            wasm_plugin_global_state().borrow_mut()
+        }
+    };
+}
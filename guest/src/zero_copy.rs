@@ -0,0 +1,41 @@
+use rkyv::{api::high::HighSerializer, rancor, ser::allocator::ArenaHandle, util::AlignedVec};
+
+use crate::serialization::Serializable;
+
+/// Wraps a value returned from an exported function so that it is sent to
+/// the host as an [rkyv](https://crates.io/crates/rkyv) archive instead of
+/// with the usual serialization feature. The host can then read it in place
+/// with `WasmPlugin::call_function_zero_copy` without deserializing it, which
+/// saves a lot of work for large values the host only looks at part of.
+///
+/// The host must use the same type definition and a compatible version of
+/// rkyv.
+///
+/// ```rust
+/// # use wasm_plugin_guest::ZeroCopy;
+/// #[derive(rkyv::Archive, rkyv::Serialize)]
+/// struct Mesh {
+///     vertices: Vec<[f32; 3]>,
+/// }
+///
+/// #[wasm_plugin_guest::export_function]
+/// fn mesh() -> ZeroCopy<Mesh> {
+///     ZeroCopy(Mesh {
+///         vertices: vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]],
+///     })
+/// }
+/// ```
+pub struct ZeroCopy<T>(pub T);
+
+impl<T> Serializable for ZeroCopy<T>
+where
+    T: for<'a> rkyv::Serialize<HighSerializer<AlignedVec, ArenaHandle<'a>, rancor::Error>>,
+{
+    fn serialize(&self) -> Result<Vec<u8>, String> {
+        // Messages are freed by the host as plain byte buffers, so the
+        // archive can't stay in its aligned allocation
+        rkyv::to_bytes::<rancor::Error>(&self.0)
+            .map(|bytes| bytes.to_vec())
+            .map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,46 @@
+#![cfg(feature = "async")]
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+
+use wasm_plugin_guest::export_function;
+
+static YIELDS: AtomicUsize = AtomicUsize::new(0);
+
+// Like `async_std::task::yield_now`: pending once, waking itself straight away
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            YIELDS.fetch_add(1, Ordering::SeqCst);
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+fn yield_now() -> YieldNow {
+    YieldNow(false)
+}
+
+// The scalar export returns the number directly, so it can be checked
+// without reading a message out of the plugin's memory
+#[export_function(scalar)]
+async fn answer() -> u32 {
+    yield_now().await;
+    42
+}
+
+#[test]
+fn an_async_export_is_run_to_completion() {
+    assert_eq!(wasm_plugin_exported_u32__answer(), 42);
+    assert_eq!(YIELDS.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,50 @@
+#![cfg(feature = "serialize_cbor")]
+
+use wasm_plugin_guest::serialization::{Deserializable, Serializable};
+
+// Fixtures from RFC 8949, Appendix A
+
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+struct Fixture {
+    a: u32,
+    b: Vec<u32>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn values_are_encoded_as_standard_cbor() {
+    assert_eq!(hex(&100u32.serialize().unwrap()), "1864");
+    assert_eq!(hex(&(-1000i32).serialize().unwrap()), "3903e7");
+    assert_eq!(hex(&"IETF".to_string().serialize().unwrap()), "6449455446");
+    let nested: (u32, Vec<u32>, Vec<u32>) = (1, vec![2, 3], vec![4, 5]);
+    assert_eq!(hex(&nested.serialize().unwrap()), "8301820203820405");
+    let map = Fixture {
+        a: 1,
+        b: vec![2, 3],
+    };
+    assert_eq!(hex(&map.serialize().unwrap()), "a26161016162820203");
+}
+
+#[test]
+fn standard_cbor_is_decoded() {
+    assert_eq!(u32::deserialize(&unhex("1864")).unwrap(), 100);
+    assert_eq!(i32::deserialize(&unhex("3903e7")).unwrap(), -1000);
+    assert_eq!(String::deserialize(&unhex("6449455446")).unwrap(), "IETF");
+    assert_eq!(
+        Fixture::deserialize(&unhex("a26161016162820203")).unwrap(),
+        Fixture {
+            a: 1,
+            b: vec![2, 3]
+        }
+    );
+}
@@ -0,0 +1,23 @@
+#![cfg(any(feature = "serialize_bincode", feature = "serialize_raw"))]
+
+use wasm_plugin_guest::read_message;
+
+// Fat pointers to empty messages may be null
+#[test]
+fn an_empty_message_is_a_unit() {
+    read_message::<()>(0, 0).unwrap();
+}
+
+#[cfg(feature = "serialize_bincode")]
+#[test]
+fn an_empty_message_is_not_a_number() {
+    let error = read_message::<u32>(0, 0).unwrap_err();
+    assert!(error.to_string().contains("u32"), "{}", error);
+}
+
+#[cfg(feature = "serialize_raw")]
+#[test]
+fn an_empty_raw_message_is_an_empty_buffer() {
+    assert_eq!(read_message::<Vec<u8>>(0, 0).unwrap(), Vec::<u8>::new());
+    assert_eq!(read_message::<String>(0, 0).unwrap(), "");
+}
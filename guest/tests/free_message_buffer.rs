@@ -0,0 +1,87 @@
+// `free_message_buffer` takes 32 bit pointers, as it does in WASM, so the
+// buffers this tests are allocated below 4GiB where the pointers survive
+#![cfg(all(target_os = "linux", target_arch = "x86_64"))]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use wasm_plugin_guest::{free_message_buffer, write_raw_message};
+
+extern "C" {
+    fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+}
+
+const PROT_READ_WRITE: i32 = 0x1 | 0x2;
+const MAP_PRIVATE_ANONYMOUS_32BIT: i32 = 0x02 | 0x20 | 0x40;
+const ARENA_SIZE: usize = 1 << 20;
+
+thread_local! {
+    // Whether allocations on this thread come from the arena
+    static TRACKING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Hands out allocations made while tracking from an arena below 4GiB, and
+// counts the bytes allocated and freed there. Everything else goes to the
+// system allocator.
+struct CountingAllocator;
+
+static ARENA: AtomicUsize = AtomicUsize::new(0);
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static FREED: AtomicUsize = AtomicUsize::new(0);
+
+fn in_arena(ptr: *mut u8) -> bool {
+    let arena = ARENA.load(Ordering::SeqCst);
+    arena != 0 && (arena..arena + ARENA_SIZE).contains(&(ptr as usize))
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !TRACKING.with(Cell::get) {
+            return System.alloc(layout);
+        }
+        if ARENA.load(Ordering::SeqCst) == 0 {
+            let arena = mmap(
+                std::ptr::null_mut(),
+                ARENA_SIZE,
+                PROT_READ_WRITE,
+                MAP_PRIVATE_ANONYMOUS_32BIT,
+                -1,
+                0,
+            );
+            assert_ne!(arena as isize, -1, "couldn't map an arena below 4GiB");
+            ARENA.store(arena as usize, Ordering::SeqCst);
+            NEXT.store(arena as usize, Ordering::SeqCst);
+        }
+        let start = (NEXT.load(Ordering::SeqCst) + layout.align() - 1) & !(layout.align() - 1);
+        assert!(start + layout.size() <= ARENA.load(Ordering::SeqCst) + ARENA_SIZE);
+        NEXT.store(start + layout.size(), Ordering::SeqCst);
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        start as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if in_arena(ptr) {
+            FREED.fetch_add(layout.size(), Ordering::SeqCst);
+        } else {
+            System.dealloc(ptr, layout);
+        }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn a_message_buffer_is_freed_with_the_size_it_was_allocated_with() {
+    TRACKING.with(|tracking| tracking.set(true));
+    let (ptr, len) = write_raw_message(vec![1, 2, 3, 4, 5]).unwrap();
+    TRACKING.with(|tracking| tracking.set(false));
+    assert!(ptr < u32::MAX as usize);
+    assert_eq!(len, 5);
+    assert_eq!(ALLOCATED.load(Ordering::SeqCst), 5);
+
+    free_message_buffer(ptr as u32, len as u32);
+    assert_eq!(FREED.load(Ordering::SeqCst), 5);
+}
@@ -0,0 +1,47 @@
+#![cfg(feature = "serialize_json")]
+
+use wasm_plugin_guest::serialization::{Deserializable, Serializable};
+
+// The same fixture is checked against wasm_plugin_host's serialization in its
+// own tests, so both sides agree on the encoding
+const FIXTURE: &str =
+    r#"{"name":"intro","layers":[{"id":1,"tags":["sky"]},{"id":2,"tags":[]}],"origin":[3,-4]}"#;
+
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+struct Scene {
+    name: String,
+    layers: Vec<Layer>,
+    origin: Option<(i32, i32)>,
+}
+
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+struct Layer {
+    id: u32,
+    tags: Vec<String>,
+}
+
+fn scene() -> Scene {
+    Scene {
+        name: "intro".to_string(),
+        layers: vec![
+            Layer {
+                id: 1,
+                tags: vec!["sky".to_string()],
+            },
+            Layer {
+                id: 2,
+                tags: vec![],
+            },
+        ],
+        origin: Some((3, -4)),
+    }
+}
+
+#[test]
+fn a_nested_struct_is_encoded_as_json() {
+    assert_eq!(
+        String::from_utf8(scene().serialize().unwrap()).unwrap(),
+        FIXTURE
+    );
+    assert_eq!(Scene::deserialize(FIXTURE.as_bytes()).unwrap(), scene());
+}
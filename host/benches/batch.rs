@@ -0,0 +1,47 @@
+//! `call_function_batch` against calling `call_function_with_argument` in a
+//! loop over the same inputs.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+// Doubles a bincode encoded u32
+const PLUGIN: &str = r#"
+  (func (export "wasm_plugin_exported__double") (param $ptr i32) (param $len i32) (result i64)
+    (local $out i32)
+    (local.set $out (call $alloc (i32.const 4)))
+    (i32.store (local.get $out) (i32.mul (i32.load (local.get $ptr)) (i32.const 2)))
+    (i64.or (i64.const 0x400000000) (i64.extend_i32_u (local.get $out))))
+"#;
+
+fn batch(c: &mut Criterion) {
+    let plugin = common::plugin(PLUGIN);
+    let mut group = c.benchmark_group("batch");
+    for size in [10u32, 100, 1000] {
+        let inputs: Vec<u32> = (0..size).collect();
+        group.bench_with_input(BenchmarkId::new("loop", size), &inputs, |b, inputs| {
+            b.iter(|| {
+                inputs
+                    .iter()
+                    .map(|x| {
+                        plugin
+                            .call_function_with_argument::<u32, u32>("double", x)
+                            .unwrap()
+                    })
+                    .collect::<Vec<_>>()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("batch", size), &inputs, |b, inputs| {
+            b.iter(|| {
+                plugin
+                    .call_function_batch::<u32, u32>("double", inputs)
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, batch);
+criterion_main!(benches);
@@ -0,0 +1,59 @@
+//! Calls with arguments of a few sizes, with and without
+//! `WasmPluginBuilder::with_buffer_pool`. Before timing them the number of
+//! buffers the plugin had to allocate for a thousand calls is printed, which
+//! is what the pool saves.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use wasm_plugin_host::WasmPlugin;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+// Takes any argument and returns `()`
+const PLUGIN: &str = r#"
+  (func (export "wasm_plugin_exported__consume") (param i32 i32) (result i64)
+    i64.const 1024)
+"#;
+
+fn plugin(pooled: bool) -> WasmPlugin {
+    let builder = common::builder("", PLUGIN);
+    if pooled {
+        builder.with_buffer_pool(4).finish().unwrap()
+    } else {
+        builder.finish().unwrap()
+    }
+}
+
+fn buffer_pool(c: &mut Criterion) {
+    let mut group = c.benchmark_group("buffer_pool");
+    for size in [64usize, 4096, 65536] {
+        let argument = vec![7u8; size];
+        for (name, pooled) in [("allocating", false), ("pooled", true)] {
+            let plugin = plugin(pooled);
+            let before: i32 = plugin.get_global("allocs").unwrap();
+            for _ in 0..1000 {
+                plugin
+                    .call_function_with_argument::<(), _>("consume", &argument)
+                    .unwrap();
+            }
+            let after: i32 = plugin.get_global("allocs").unwrap();
+            println!(
+                "buffer_pool/{}/{}: {} allocations in 1000 calls",
+                name,
+                size,
+                after - before
+            );
+            group.bench_with_input(BenchmarkId::new(name, size), &argument, |b, argument| {
+                b.iter(|| {
+                    plugin
+                        .call_function_with_argument::<(), _>("consume", argument)
+                        .unwrap()
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, buffer_pool);
+criterion_main!(benches);
@@ -0,0 +1,74 @@
+//! Loading and calling the same plugin compiled with Cranelift and with
+//! Singlepass. Singlepass should load much faster and run slower.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use wasm_plugin_host::{Compiler, WasmPluginBuilder};
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+const COMPILERS: [(&str, Compiler); 2] = [
+    ("cranelift", Compiler::Cranelift),
+    ("singlepass", Compiler::Singlepass),
+];
+
+// A plugin with enough code for compiling it to take a while, and a function
+// which sums the numbers below its argument to time running it
+fn source() -> String {
+    let mut body = String::from(
+        r#"(func (export "sum") (param $n i32) (result i64)
+             (local $i i32)
+             (local $total i64)
+             (block $done
+               (loop $next
+                 (br_if $done (i32.ge_u (local.get $i) (local.get $n)))
+                 (local.set $total
+                   (i64.add (local.get $total) (i64.extend_i32_u (local.get $i))))
+                 (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                 (br $next)))
+             (local.get $total))"#,
+    );
+    for i in 0..200 {
+        body.push_str(&format!(
+            r#"(func (export "filler_{i}") (param $x i32) (result i32)
+                 (i32.add (i32.mul (local.get $x) (i32.const {i})) (i32.rotl (local.get $x) (i32.const 3))))"#,
+            i = i
+        ));
+    }
+    common::module("", &body)
+}
+
+fn load(c: &mut Criterion) {
+    let source = source();
+    let mut group = c.benchmark_group("load");
+    for (name, compiler) in COMPILERS {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                WasmPluginBuilder::from_source_with_compiler(source.as_bytes(), compiler)
+                    .unwrap()
+                    .finish()
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn call(c: &mut Criterion) {
+    let source = source();
+    let mut group = c.benchmark_group("call");
+    for (name, compiler) in COMPILERS {
+        let plugin = WasmPluginBuilder::from_source_with_compiler(source.as_bytes(), compiler)
+            .unwrap()
+            .finish()
+            .unwrap();
+        let sum = plugin.scalar_function::<i32, i64>("sum").unwrap();
+        group.bench_with_input(BenchmarkId::new(name, 100_000), &100_000, |b, &n| {
+            b.iter(|| sum.call(n).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, load, call);
+criterion_main!(benches);
@@ -0,0 +1,76 @@
+//! Plugins which call imports returning messages many times, run on several
+//! threads at once. Every message an import returns is a buffer which has to
+//! be freed at the end of the call, so this measures how well collecting
+//! them scales when nothing is shared between the threads.
+
+use std::sync::{Arc, Barrier};
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+// Calls `value` a hundred times and returns nothing
+const PLUGIN: &str = r#"
+  (func (export "wasm_plugin_exported__call_imports") (result i64)
+    (local $i i32)
+    (loop $again
+      call $value
+      drop
+      local.get $i
+      i32.const 1
+      i32.add
+      local.tee $i
+      i32.const 100
+      i32.lt_u
+      br_if $again)
+    ;; An empty message at a non-null address is `()`
+    i64.const 1024)
+"#;
+
+const IMPORTS: &str = r#"(import "env" "wasm_plugin_imported__value" (func $value (result i64)))"#;
+
+// Make `iters` calls on each of `threads` threads, each with its own plugin,
+// and return how long the slowest thread took
+fn run(threads: usize, iters: u64) -> Duration {
+    let start_together = Arc::new(Barrier::new(threads));
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let start_together = start_together.clone();
+            std::thread::spawn(move || {
+                let mut plugin = common::builder(IMPORTS, PLUGIN)
+                    .import_function("value", || 7u32)
+                    .finish()
+                    .unwrap();
+                start_together.wait();
+                let start = Instant::now();
+                for _ in 0..iters {
+                    plugin.call_function::<()>("call_imports").unwrap();
+                }
+                start.elapsed()
+            })
+        })
+        .collect();
+    handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .max()
+        .unwrap()
+}
+
+fn import_buffers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("import_buffers");
+    group.sample_size(20);
+    for threads in [1, 2, 4, 8] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| b.iter_custom(|iters| run(threads, iters)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, import_buffers);
+criterion_main!(benches);
@@ -0,0 +1,153 @@
+#![doc(html_root_url = "https://docs.rs/wasm_plugin_host_derive/0.2.0")]
+#![deny(missing_docs)]
+
+//! This crate provides attribute macros used by [wasm_plugin_host](https://crates.io/crates/wasm_plugin_host)
+
+use proc_macro::TokenStream;
+extern crate proc_macro;
+use quote::{format_ident, quote};
+
+/// Generates a typed client for a plugin from a trait describing the
+/// functions it exports. Applied to `trait Echo` it creates a struct
+/// `EchoClient` which wraps a `WasmPlugin` and has a method for each of the
+/// trait's methods, calling the plugin function of the same name with the
+/// same argument and return types. Each method takes `&mut self` and returns
+/// `wasm_plugin_host::errors::Result` of the trait method's return type.
+///
+/// The plugin implements the same trait with wasm_plugin_guest's
+/// `#[wasm_plugin_server]`, so keeping the trait in a crate shared by the
+/// host and the plugin means their function names and signatures can't drift
+/// apart. Since the shared crate is also built for the plugin, apply the
+/// attribute only outside of WASM:
+///
+/// ```rust,ignore
+/// #[cfg_attr(not(target_arch = "wasm32"), wasm_plugin_host::wasm_plugin_client)]
+/// pub trait Echo {
+///     fn echo(&self, message: String) -> String;
+///     fn count(&self) -> u32;
+/// }
+/// ```
+/// ```rust,ignore
+/// let mut client = EchoClient::new(WasmPluginBuilder::from_file("echo.wasm")?.finish()?);
+/// let response = client.echo("hello".to_string())?;
+/// ```
+///
+/// Methods may take any number of arguments, which are sent as a tuple when
+/// there is more than one, just like `export_function` expects.
+#[proc_macro_attribute]
+pub fn wasm_plugin_client(_args: TokenStream, input: TokenStream) -> TokenStream {
+    let ast = syn::parse_macro_input!(input as syn::ItemTrait);
+    impl_plugin_client(&ast).unwrap_or_else(|e| e.to_compile_error().into())
+}
+
+fn impl_plugin_client(ast: &syn::ItemTrait) -> syn::Result<TokenStream> {
+    if !ast.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &ast.generics,
+            "wasm_plugin_client does not support generic traits",
+        ));
+    }
+    let vis = &ast.vis;
+    let client = format_ident!("{}Client", ast.ident);
+    let doc = format!(
+        "A client for plugins which implement `{}`, generated by `wasm_plugin_client`.",
+        ast.ident
+    );
+    let mut methods = quote!();
+    for item in &ast.items {
+        let method = match item {
+            syn::TraitItem::Method(method) => method,
+            _ => continue,
+        };
+        let method = client_method(vis, &method.sig)?;
+        methods = quote!(#methods #method);
+    }
+
+    Ok(quote! {
+        #ast
+
+        #[doc = #doc]
+        #vis struct #client {
+            plugin: ::wasm_plugin_host::WasmPlugin,
+        }
+
+        impl #client {
+            /// Wrap a plugin which implements the trait.
+            #vis fn new(plugin: ::wasm_plugin_host::WasmPlugin) -> Self {
+                Self { plugin }
+            }
+
+            /// The wrapped plugin.
+            #vis fn plugin(&self) -> &::wasm_plugin_host::WasmPlugin {
+                &self.plugin
+            }
+
+            /// The wrapped plugin, for calls the trait doesn't cover.
+            #vis fn plugin_mut(&mut self) -> &mut ::wasm_plugin_host::WasmPlugin {
+                &mut self.plugin
+            }
+
+            /// Unwrap the plugin.
+            #vis fn into_inner(self) -> ::wasm_plugin_host::WasmPlugin {
+                self.plugin
+            }
+
+            #methods
+        }
+    }
+    .into())
+}
+
+// A method of the client calling the plugin function with the signature `sig`
+fn client_method(
+    vis: &syn::Visibility,
+    sig: &syn::Signature,
+) -> syn::Result<proc_macro2::TokenStream> {
+    if !sig.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &sig.generics,
+            "wasm_plugin_client does not support generic methods",
+        ));
+    }
+    if let Some(asyncness) = &sig.asyncness {
+        return Err(syn::Error::new_spanned(
+            asyncness,
+            "wasm_plugin_client does not support async methods",
+        ));
+    }
+    let name = &sig.ident;
+    let fn_name = name.to_string();
+    let mut args = vec![];
+    let mut idents = vec![];
+    for (i, input) in sig.inputs.iter().enumerate() {
+        if let syn::FnArg::Typed(arg) = input {
+            // Patterns like `(a, b): (u32, u32)` are replaced by a plain name
+            let ident = match arg.pat.as_ref() {
+                syn::Pat::Ident(p) => p.ident.clone(),
+                _ => format_ident!("arg{}", i),
+            };
+            let ty = &arg.ty;
+            args.push(quote!(#ident: #ty));
+            idents.push(ident);
+        }
+    }
+    let return_type = match &sig.output {
+        syn::ReturnType::Default => quote!(()),
+        syn::ReturnType::Type(_, ty) => quote!(#ty),
+    };
+    let call = match idents.len() {
+        0 => quote!(self.plugin.call_function(#fn_name)),
+        1 => {
+            let ident = &idents[0];
+            quote!(self.plugin.call_function_with_argument(#fn_name, &#ident))
+        }
+        _ => quote!(self.plugin.call_function_with_argument(#fn_name, &(#(#idents),*))),
+    };
+    let doc = format!("Call the plugin's `{}` function.", fn_name);
+    Ok(quote! {
+        #[doc = #doc]
+        #vis fn #name(&mut self, #(#args),*) -> ::wasm_plugin_host::errors::Result<#return_type> {
+            #call
+        }
+    })
+}
@@ -0,0 +1,61 @@
+use crate::FatPointer;
+
+// Buffers are allocated in powers of two, no smaller than this, so that
+// messages of similar sizes can share them
+const MIN_BUFFER_SIZE: u32 = 256;
+
+// Buffers in the plugin's memory which are kept between calls for passing
+// arguments, rather than allocating and freeing one for every call. The fat
+// pointers hold each buffer's address and capacity.
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    buffers: Vec<FatPointer>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    pub(crate) fn new(max_buffers: usize) -> Self {
+        Self {
+            buffers: vec![],
+            max_buffers,
+        }
+    }
+
+    // The size to allocate for a new buffer holding a message of `len` bytes
+    pub(crate) fn capacity_for(len: u32) -> u32 {
+        len.max(MIN_BUFFER_SIZE)
+            .checked_next_power_of_two()
+            .unwrap_or(len)
+    }
+
+    // Take the smallest pooled buffer which can hold `len` bytes
+    pub(crate) fn take(&mut self, len: u32) -> Option<FatPointer> {
+        let (i, _) = self
+            .buffers
+            .iter()
+            .enumerate()
+            .filter(|(_, buffer)| buffer.len() >= len)
+            .min_by_key(|(_, buffer)| buffer.len())?;
+        Some(self.buffers.swap_remove(i))
+    }
+
+    // Return a buffer to the pool. If the pool is full the smallest buffer is
+    // returned so the caller can free it.
+    pub(crate) fn give_back(&mut self, buffer: FatPointer) -> Option<FatPointer> {
+        self.buffers.push(buffer);
+        if self.buffers.len() <= self.max_buffers {
+            return None;
+        }
+        let (i, _) = self
+            .buffers
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, buffer)| buffer.len())?;
+        Some(self.buffers.swap_remove(i))
+    }
+
+    // Forget every buffer, for when the memory they were in is gone
+    pub(crate) fn clear(&mut self) {
+        self.buffers.clear();
+    }
+}
@@ -0,0 +1,45 @@
+//! Request scoped data passed from the caller of a plugin function to the
+//! imports the plugin calls while it runs.
+
+use std::{any::TypeId, cell::RefCell};
+
+thread_local! {
+    // The context given to the call in progress on this thread and its type
+    static CALL_CONTEXT: RefCell<Option<(TypeId, *const ())>> = const { RefCell::new(None) };
+}
+
+// Puts back the context of an outer call once a nested one finishes, even if
+// it panics
+struct Restore(Option<(TypeId, *const ())>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CALL_CONTEXT.with(|context| *context.borrow_mut() = previous);
+    }
+}
+
+// Make `ctx` available to imports on this thread while `call` runs
+pub(crate) fn with_call_context<Ctx: 'static, R>(ctx: &Ctx, call: impl FnOnce() -> R) -> R {
+    let previous = CALL_CONTEXT.with(|context| {
+        context
+            .borrow_mut()
+            .replace((TypeId::of::<Ctx>(), ctx as *const Ctx as *const ()))
+    });
+    let _restore = Restore(previous);
+    call()
+}
+
+// Pass the context of the call in progress to `f`, if there is one of type
+// `Ctx`
+pub(crate) fn current_call_context<Ctx: 'static, R>(f: impl FnOnce(Option<&Ctx>) -> R) -> R {
+    let current = CALL_CONTEXT.with(|context| *context.borrow());
+    match current {
+        Some((type_id, ptr)) if type_id == TypeId::of::<Ctx>() => {
+            // The pointer was made from a reference which `with_call_context`
+            // holds for as long as the context is set
+            f(Some(unsafe { &*(ptr as *const Ctx) }))
+        }
+        _ => f(None),
+    }
+}
@@ -0,0 +1,69 @@
+//! Giving up on calls to a plugin which are still running.
+
+use std::{
+    cell::RefCell,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// Cancels the calls it is given to, see
+/// `WasmPlugin::call_function_with_cancel_token`.
+///
+/// Tokens are cheap to clone and can be sent to other threads. Every clone
+/// cancels the same calls, so one can be kept by whatever notices that the
+/// result is no longer needed, like the task serving a request whose client
+/// has disconnected.
+///
+/// ```rust
+/// use wasm_plugin_host::CancelToken;
+///
+/// let token = CancelToken::new();
+/// let on_disconnect = token.clone();
+/// on_disconnect.cancel();
+/// assert!(token.is_cancelled());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Create a token which hasn't been cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel every call made with this token or one of its clones,
+    /// including ones made later.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+thread_local! {
+    // The token of the call running on this thread, checked by imports
+    static CANCEL_TOKEN: RefCell<Option<CancelToken>> = const { RefCell::new(None) };
+}
+
+// Run `call` with `token` as the token of the call running on this thread
+pub(crate) fn with_cancel_token<R>(token: CancelToken, call: impl FnOnce() -> R) -> R {
+    CANCEL_TOKEN.with(|current| *current.borrow_mut() = Some(token));
+    let result = call();
+    CANCEL_TOKEN.with(|current| *current.borrow_mut() = None);
+    result
+}
+
+// Whether the call running on this thread has been cancelled
+pub(crate) fn is_current_call_cancelled() -> bool {
+    CANCEL_TOKEN.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .is_some_and(CancelToken::is_cancelled)
+    })
+}
@@ -0,0 +1,106 @@
+use std::borrow::Cow;
+
+use crate::errors;
+
+// Compressed messages start with one of these headers so the reader can tell
+// them apart from raw ones. They must match the ones used by wasm_plugin_guest.
+#[cfg(feature = "compress_zstd")]
+const ZSTD_MAGIC: &[u8] = b"\0WPZSTD\0";
+#[cfg(feature = "compress_messages")]
+const LZ4_MAGIC: &[u8] = b"\0WPLZ4F\0";
+
+/// Messages smaller than this many bytes are not compressed unless
+/// `WasmPluginBuilder::with_compression_threshold` says otherwise.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 4 * 1024;
+
+/// How messages sent from the host to the plugin are compressed.
+///
+/// Messages from the plugin are compressed according to the features
+/// wasm_plugin_guest was built with, and the host decompresses them whatever
+/// this is set to. Plugins must be built with the matching feature to read
+/// compressed messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Messages are sent as is. This is the default unless the
+    /// `compress_messages` feature is selected.
+    #[default]
+    None,
+    /// Messages are compressed with zstd. This needs the `compress_zstd`
+    /// feature on both the host and the guest.
+    ///
+    /// Compression pays off for large, repetitive payloads like JSON. Small
+    /// messages cost more to compress than they save in copying.
+    #[cfg(feature = "compress_zstd")]
+    Zstd,
+    /// Messages are compressed as lz4 frames. This needs the
+    /// `compress_messages` feature on both the host and the guest and is the
+    /// default when it is selected.
+    ///
+    /// lz4 compresses less than zstd but is fast enough to be worth it for
+    /// most large payloads, like images or arrays of numbers.
+    #[cfg(feature = "compress_messages")]
+    Lz4,
+}
+
+impl Compression {
+    pub(crate) fn default_for_features() -> Self {
+        #[cfg(feature = "compress_messages")]
+        return Compression::Lz4;
+        #[cfg(not(feature = "compress_messages"))]
+        Compression::None
+    }
+}
+
+pub(crate) fn compress(
+    compression: Compression,
+    threshold: usize,
+    message: &[u8],
+) -> errors::Result<Cow<'_, [u8]>> {
+    if message.len() < threshold {
+        return Ok(Cow::Borrowed(message));
+    }
+    match compression {
+        Compression::None => Ok(Cow::Borrowed(message)),
+        #[cfg(feature = "compress_zstd")]
+        Compression::Zstd => {
+            let mut buffer = ZSTD_MAGIC.to_vec();
+            buffer.extend(zstd::bulk::compress(message, 0)?);
+            Ok(Cow::Owned(buffer))
+        }
+        #[cfg(feature = "compress_messages")]
+        Compression::Lz4 => {
+            let mut encoder = lz4_flex::frame::FrameEncoder::new(LZ4_MAGIC.to_vec());
+            std::io::Write::write_all(&mut encoder, message)?;
+            Ok(Cow::Owned(encoder.finish().map_err(std::io::Error::from)?))
+        }
+    }
+}
+
+#[cfg_attr(
+    not(any(feature = "compress_zstd", feature = "compress_messages")),
+    allow(unused_variables)
+)]
+pub(crate) fn decompress(message: Vec<u8>, limit: usize) -> errors::Result<Vec<u8>> {
+    #[cfg(feature = "compress_zstd")]
+    if let Some(compressed) = message.strip_prefix(ZSTD_MAGIC) {
+        // A small message can decompress into a huge one so the limit is
+        // enforced again here
+        return Ok(zstd::bulk::decompress(compressed, limit)?);
+    }
+    #[cfg(feature = "compress_messages")]
+    if let Some(compressed) = message.strip_prefix(LZ4_MAGIC) {
+        use std::io::Read;
+        let mut buffer = vec![];
+        lz4_flex::frame::FrameDecoder::new(compressed)
+            .take(limit as u64 + 1)
+            .read_to_end(&mut buffer)?;
+        if buffer.len() > limit {
+            return Err(errors::WasmPluginError::MessageTooLarge {
+                size: buffer.len(),
+                limit,
+            });
+        }
+        return Ok(buffer);
+    }
+    Ok(message)
+}
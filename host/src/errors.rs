@@ -1,29 +1,173 @@
 /// Error returned by WasmPlugin when loading plugins or calling functions.
+///
+/// New variants may be added in minor releases so matches on it need a
+/// wildcard arm.
+#[non_exhaustive]
 pub enum WasmPluginError {
     /// A problem compiling the plugin's WASM source
     WasmerCompileError(wasmer::CompileError),
-    /// A problem instantiating the Wasmer runtime
-    WasmerInstantiationError(wasmer::InstantiationError),
-    /// A problem interacting with the plugin
+    /// A problem instantiating the Wasmer runtime. It's boxed because it's
+    /// much larger than the other errors.
+    WasmerInstantiationError(Box<wasmer::InstantiationError>),
+    /// A problem interacting with the plugin, including the plugin trapping.
+    /// See `WasmPluginError::trap_code`.
     WasmerRuntimeError(wasmer::RuntimeError),
     /// A problem getting an export from the plugin
     WasmerExportError(wasmer::ExportError),
+    /// A problem creating the memory for a plugin which imports its memory
+    WasmerMemoryError(wasmer::MemoryError),
     /// A problem loading the plugin's source from disk
     IoError(std::io::Error),
+    /// The source given to `WasmPluginBuilder::from_source_verified` isn't a
+    /// valid WASM module. Contains a description of the problem.
+    InvalidWasm(String),
     /// A problems serializing an argument to send to one of the plugin's
-    /// functions.
-    SerializationError,
+    /// functions. Contains the serializer's description of the problem.
+    SerializationError(String),
     /// A problem deserializing the return value of a call to one of the
     /// plugin's functions. This almost always represents a type mismatch
     /// between the callsite in the host and the function signature in the
     /// plugin.
-    DeserializationError,
+    DeserializationError {
+        /// The type the data was being deserialized into
+        type_name: &'static str,
+        /// The length of the data
+        bytes_received: usize,
+        /// Up to the first 64 bytes of the data
+        first_bytes: Vec<u8>,
+        /// The deserializer's description of the problem
+        reason: String,
+    },
+    /// The plugin couldn't deserialize the argument it was sent or serialize
+    /// its return value. This almost always represents a type mismatch
+    /// between the host and the plugin.
+    GuestMessageError(String),
+    /// The plugin function returned an empty message but the call site
+    /// expected a value which isn't zero sized.
+    EmptyReturnValue,
+    /// A message sent across the host-plugin boundary was larger than the
+    /// limit set with `WasmPluginBuilder::with_max_message_size`
+    MessageTooLarge {
+        /// The size of the message in bytes
+        size: usize,
+        /// The maximum message size in bytes
+        limit: usize,
+    },
+    /// The plugin needs more of a resource than the limit set on the builder,
+    /// for example with `WasmPluginBuilder::with_table_limit`
+    ResourceLimitExceeded {
+        /// The kind of resource, such as "globals"
+        resource: &'static str,
+        /// How much of the resource the plugin needs
+        requested: usize,
+        /// The limit which was exceeded
+        limit: usize,
+    },
+    /// The plugin doesn't export one of the functions, or the memory, needed
+    /// to pass messages to and from it, or exports a function with the wrong
+    /// signature. This usually means it wasn't built with wasm_plugin_guest.
+    MissingRuntimeExport(String),
+    /// The plugin was built with a version of wasm_plugin_guest which speaks
+    /// a different protocol than this crate, see
+    /// `WasmPlugin::verify_abi_version`
+    AbiMismatch {
+        /// The `ABI_VERSION` of the plugin's wasm_plugin_guest
+        guest: u32,
+        /// This crate's `ABI_VERSION`
+        host: u32,
+    },
+    /// A function was imported into the plugin under a name which was
+    /// already used by another import, or an import from
+    /// `WasmPluginBuilder::with_import_object` clashes with another import of
+    /// the same name. The name is given as `namespace.name`.
+    DuplicateImport(String),
+    /// `WasmPluginBuilder::validate` found problems with the plugin, each
+    /// described by one of the strings.
+    ValidationFailed(Vec<String>),
+    /// The thread running a `PluginService`'s plugin, or the workers of a
+    /// `WasmPluginWorkerPool`, have stopped, either because the plugin failed
+    /// to load or because it panicked
+    ServiceStopped,
+    /// A call made with `WasmPlugin::call_function_with_timeout` didn't
+    /// finish in time and the plugin was stopped
+    Timeout,
+    /// The `CancelToken` given to `WasmPlugin::call_function_with_cancel_token`
+    /// was cancelled before the call finished
+    Cancelled,
+    /// The plugin was compiled with a `Store` provided by the caller, so it
+    /// can't be stopped in the middle of a call as
+    /// `WasmPlugin::call_function_with_timeout` and
+    /// `WasmPlugin::call_function_with_cancel_token` need
+    Uninterruptible,
+    /// The plugin doesn't export the function that was called
+    FunctionNotFound(FunctionNotFound),
+    /// A plugin function was called with an argument when it takes none, or
+    /// without one when it takes one. This usually means the function's
+    /// signature changed in the plugin.
+    ArityMismatch {
+        /// The function which was called
+        function: String,
+        /// The number of arguments the function takes
+        expected: usize,
+        /// The number of arguments it was called with
+        got: usize,
+    },
+    /// A global or function exported by the plugin has a different type than
+    /// the one it was used as, with `WasmPlugin::get_global`,
+    /// `WasmPlugin::set_global` or `WasmPlugin::scalar_function`
+    TypeMismatch {
+        /// The export's name
+        name: String,
+        /// The type it was used as
+        expected: &'static str,
+        /// The export's WASM type
+        found: String,
+    },
+    /// A buffer from `WasmPlugin::stage_buffer` was used with a plugin
+    /// instance it doesn't belong to, or with a range outside of it.
+    /// Contains a description of the problem.
+    InvalidStagedBuffer(String),
+    /// The table passed to `WasmPlugin::call_table_function` has no function
+    /// at the requested index
+    InvalidTableIndex {
+        /// The table's export name
+        table: String,
+        /// The index which was called
+        index: u32,
+    },
+    /// No plugin is registered under the requested name in a
+    /// `PluginRegistry`
+    PluginNotFound(String),
+    /// An import registered with
+    /// `WasmPluginBuilder::import_function_with_call_context` was called
+    /// during a call which wasn't given a context of the type it expects,
+    /// which is named here. See `WasmPlugin::call_function_with_context`.
+    MissingCallContext(&'static str),
     /// A problem decoding the utf8 sent by the plugin
     #[cfg(feature = "serialize_nanoserde_json")]
     FromUtf8Error(std::string::FromUtf8Error),
+    /// A problem setting up the WASI environment from a `WasiConfig`
+    #[cfg(feature = "wasi")]
+    WasiStateCreationError(wasmer_wasi::WasiStateCreationError),
+    /// A problem providing WASI to the plugin
+    #[cfg(feature = "wasi")]
+    WasiError(wasmer_wasi::WasiError),
 }
 
-impl std::error::Error for WasmPluginError {}
+impl std::error::Error for WasmPluginError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WasmPluginError::WasmerCompileError(e) => Some(e),
+            WasmPluginError::WasmerInstantiationError(e) => Some(&**e),
+            WasmPluginError::WasmerRuntimeError(e) => Some(e),
+            WasmPluginError::WasmerExportError(e) => Some(e),
+            WasmPluginError::WasmerMemoryError(e) => Some(e),
+            WasmPluginError::IoError(e) => Some(e),
+            WasmPluginError::FunctionNotFound(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl core::fmt::Debug for WasmPluginError {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
@@ -36,18 +180,168 @@ impl core::fmt::Display for WasmPluginError {
         match self {
             WasmPluginError::WasmerCompileError(e) => e.fmt(f),
             WasmPluginError::WasmerInstantiationError(e) => e.fmt(f),
+            // Wasmer's Display includes the full backtrace
+            #[cfg(feature = "trap_backtrace")]
             WasmPluginError::WasmerRuntimeError(e) => e.fmt(f),
+            #[cfg(not(feature = "trap_backtrace"))]
+            WasmPluginError::WasmerRuntimeError(e) => write!(f, "RuntimeError: {}", e.message()),
             WasmPluginError::WasmerExportError(e) => e.fmt(f),
+            WasmPluginError::WasmerMemoryError(e) => e.fmt(f),
             WasmPluginError::IoError(e) => e.fmt(f),
+            WasmPluginError::InvalidWasm(e) => write!(f, "The plugin is not a valid WASM module: {}", e),
 
-            WasmPluginError::SerializationError => write!(f, "There was a problem serializing the argument to the function call"),
-            WasmPluginError::DeserializationError=> write!(f, "There was a problem deserializing the value returned by the plugin function. This almost certainly means that the type at the call site does not match the type in the plugin's function signature."),
+            WasmPluginError::SerializationError(e) => write!(f, "There was a problem serializing the argument to the function call: {}", e),
+            WasmPluginError::DeserializationError { type_name, bytes_received, first_bytes, reason } => {
+                write!(f, "There was a problem deserializing {} bytes from the plugin as `{}`: {}. This almost certainly means that the type at the call site does not match the type in the plugin's function signature. The data started with:", bytes_received, type_name, reason)?;
+                for byte in first_bytes {
+                    write!(f, " {:02x}", byte)?;
+                }
+                Ok(())
+            }
+            WasmPluginError::GuestMessageError(e) => write!(f, "The plugin failed to handle a message: {}. This almost certainly means that the types at the call site do not match the plugin's function signature.", e),
+            WasmPluginError::MessageTooLarge { size, limit } => write!(f, "A message of {} bytes exceeds the limit of {} bytes", size, limit),
+            WasmPluginError::ResourceLimitExceeded { resource, requested, limit } => write!(f, "The plugin needs {} {} which exceeds the limit of {}", requested, resource, limit),
+            WasmPluginError::MissingRuntimeExport(name) => write!(f, "The plugin does not export '{}' which is required to communicate with it. Was it built with wasm_plugin_guest?", name),
+            WasmPluginError::AbiMismatch { guest, host } => write!(f, "The plugin was built for version {} of the wasm_plugin ABI but the host uses version {}. Rebuild the plugin with a matching version of wasm_plugin_guest.", guest, host),
+            WasmPluginError::DuplicateImport(name) => write!(f, "The import '{}' was provided more than once", name),
+            WasmPluginError::ValidationFailed(problems) => write!(f, "The plugin failed validation: {}", problems.join(", ")),
+            WasmPluginError::ServiceStopped => write!(f, "The thread running the plugin has stopped"),
+            WasmPluginError::Timeout => write!(f, "The plugin did not finish a call in time and was stopped"),
+            WasmPluginError::Uninterruptible => write!(f, "The plugin can't be stopped part way through a call because it was compiled with a custom Store"),
+            WasmPluginError::Cancelled => write!(f, "The call to the plugin was cancelled"),
+            WasmPluginError::FunctionNotFound(e) => e.fmt(f),
+            WasmPluginError::ArityMismatch { function, expected, got } => write!(f, "The plugin function '{}' takes {} argument(s) but was called with {}", function, expected, got),
+            WasmPluginError::TypeMismatch { name, expected, found } => write!(f, "The plugin's export '{}' has type {} which can't be used as {}", name, found, expected),
+            WasmPluginError::InvalidStagedBuffer(e) => write!(f, "The staged buffer can't be used: {}", e),
+            WasmPluginError::InvalidTableIndex { table, index } => write!(f, "The plugin's table '{}' has no function at index {}", table, index),
+            WasmPluginError::PluginNotFound(name) => write!(f, "No plugin named '{}' is loaded", name),
+            WasmPluginError::MissingCallContext(type_name) => write!(f, "An imported function needs a context of type `{}` but the plugin was called without one. Use `call_function_with_context` to provide it.", type_name),
+            WasmPluginError::EmptyReturnValue => write!(f, "The plugin function returned nothing but the call site expected a value. This usually means the plugin's function returns `()` while the call site expects some other type."),
             #[cfg(feature = "serialize_nanoserde_json")]
             WasmPluginError::FromUtf8Error(e) => e.fmt(f),
+            #[cfg(feature = "wasi")]
+            WasmPluginError::WasiStateCreationError(e) => e.fmt(f),
+            #[cfg(feature = "wasi")]
+            WasmPluginError::WasiError(e) => e.fmt(f),
         }
     }
 }
 
+pub use wasmer_vm::TrapCode;
+
+impl WasmPluginError {
+    /// The reason the plugin trapped, such as an out of bounds memory access
+    /// or reaching `unreachable`, if that is what this error is.
+    pub fn trap_code(&self) -> Option<TrapCode> {
+        match self {
+            WasmPluginError::WasmerRuntimeError(e) => e.clone().to_trap(),
+            _ => None,
+        }
+    }
+
+    /// The plugin's functions which were on the stack when it trapped,
+    /// innermost first. Empty if this error isn't a runtime error. With the
+    /// `trap_backtrace` feature the trace is also included when the error is
+    /// displayed.
+    pub fn trace(&self) -> &[wasmer::FrameInfo] {
+        match self {
+            WasmPluginError::WasmerRuntimeError(e) => e.trace(),
+            _ => &[],
+        }
+    }
+
+    // Describe a failure to deserialize `data` as a `T`
+    pub(crate) fn deserialization<T>(data: &[u8], reason: impl ToString) -> Self {
+        WasmPluginError::DeserializationError {
+            type_name: std::any::type_name::<T>(),
+            bytes_received: data.len(),
+            first_bytes: data[..data.len().min(64)].to_vec(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// A function was called which the plugin doesn't export.
+///
+/// ```rust
+/// # use wasm_plugin_host::errors::FunctionNotFound;
+/// let error = FunctionNotFound {
+///     function: "favorite_number".to_string(),
+///     export_name: "wasm_plugin_exported__favorite_number".to_string(),
+///     plugin: None,
+///     available: vec!["favorite_numbers".to_string(), "hello".to_string()],
+/// };
+/// assert_eq!(error.suggestion(), Some("favorite_numbers"));
+/// assert!(error.to_string().ends_with("did you mean favorite_numbers?"));
+/// ```
+#[derive(Clone, Debug)]
+pub struct FunctionNotFound {
+    /// The name the function was called by
+    pub function: String,
+    /// The name of the export which was looked for in the plugin
+    pub export_name: String,
+    /// The plugin's name, if it was given one with `WasmPluginBuilder::named`
+    pub plugin: Option<String>,
+    /// The functions the plugin does export
+    pub available: Vec<String>,
+}
+
+impl FunctionNotFound {
+    /// The available function whose name is closest to the one called, if
+    /// any is close enough to plausibly be a typo.
+    pub fn suggestion(&self) -> Option<&str> {
+        self.available
+            .iter()
+            .map(|name| (edit_distance(&self.function, name), name))
+            .filter(|(distance, _)| *distance <= (self.function.len() / 3).max(1))
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, name)| name.as_str())
+    }
+}
+
+impl std::error::Error for FunctionNotFound {}
+
+impl core::fmt::Display for FunctionNotFound {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "The plugin ")?;
+        if let Some(plugin) = &self.plugin {
+            write!(f, "'{}' ", plugin)?;
+        }
+        write!(
+            f,
+            "has no function '{}' (looked for the export '{}')",
+            self.function, self.export_name
+        )?;
+        if let Some(suggestion) = self.suggestion() {
+            write!(f, ", did you mean {}?", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+// The number of single character insertions, deletions or substitutions
+// needed to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != *cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+impl From<FunctionNotFound> for WasmPluginError {
+    fn from(e: FunctionNotFound) -> WasmPluginError {
+        WasmPluginError::FunctionNotFound(e)
+    }
+}
+
 impl From<std::io::Error> for WasmPluginError {
     fn from(e: std::io::Error) -> WasmPluginError {
         WasmPluginError::IoError(e)
@@ -62,13 +356,15 @@ impl From<wasmer::CompileError> for WasmPluginError {
 
 impl From<wasmer::InstantiationError> for WasmPluginError {
     fn from(e: wasmer::InstantiationError) -> WasmPluginError {
-        WasmPluginError::WasmerInstantiationError(e)
+        WasmPluginError::WasmerInstantiationError(Box::new(e))
     }
 }
 
 impl From<wasmer::RuntimeError> for WasmPluginError {
     fn from(e: wasmer::RuntimeError) -> WasmPluginError {
-        WasmPluginError::WasmerRuntimeError(e)
+        // Errors raised inside imported functions are passed through as is
+        e.downcast::<WasmPluginError>()
+            .unwrap_or_else(WasmPluginError::WasmerRuntimeError)
     }
 }
 
@@ -78,6 +374,12 @@ impl From<wasmer::ExportError> for WasmPluginError {
     }
 }
 
+impl From<wasmer::MemoryError> for WasmPluginError {
+    fn from(e: wasmer::MemoryError) -> WasmPluginError {
+        WasmPluginError::WasmerMemoryError(e)
+    }
+}
+
 #[cfg(feature = "serialize_nanoserde_json")]
 impl From<std::string::FromUtf8Error> for WasmPluginError {
     fn from(e: std::string::FromUtf8Error) -> WasmPluginError {
@@ -85,4 +387,18 @@ impl From<std::string::FromUtf8Error> for WasmPluginError {
     }
 }
 
+#[cfg(feature = "wasi")]
+impl From<wasmer_wasi::WasiStateCreationError> for WasmPluginError {
+    fn from(e: wasmer_wasi::WasiStateCreationError) -> WasmPluginError {
+        WasmPluginError::WasiStateCreationError(e)
+    }
+}
+
+#[cfg(feature = "wasi")]
+impl From<wasmer_wasi::WasiError> for WasmPluginError {
+    fn from(e: wasmer_wasi::WasiError) -> WasmPluginError {
+        WasmPluginError::WasiError(e)
+    }
+}
+
 pub type Result<T> = std::result::Result<T, WasmPluginError>;
@@ -0,0 +1,46 @@
+//! Message buffers written into a plugin's memory during a call, which are
+//! freed once that call finishes.
+
+use std::cell::RefCell;
+use std::sync::Mutex;
+
+use crate::FatPointer;
+
+thread_local! {
+    // The buffers of each call in progress on this thread, innermost last.
+    // Calls into plugins are synchronous so the calls on a thread are
+    // strictly nested and the innermost one is always the call whose imports
+    // are running, even when it's a call into a different plugin. Keeping the
+    // lists here means imports never have to lock anything to hand off their
+    // buffers.
+    static FRAMES: RefCell<Vec<Vec<FatPointer>>> = const { RefCell::new(Vec::new()) };
+}
+
+// Start collecting buffers for a new call on this thread
+pub(crate) fn enter() {
+    FRAMES.with(|frames| frames.borrow_mut().push(vec![]));
+}
+
+// Stop collecting buffers for the innermost call and return them. A buffer
+// can be collected twice, for example when a plugin returns a message an
+// import sent it as its own result, but it's only returned once.
+pub(crate) fn exit() -> Vec<FatPointer> {
+    let mut buffers = FRAMES.with(|frames| frames.borrow_mut().pop().unwrap_or_default());
+    buffers.sort_unstable_by_key(|buffer| buffer.0);
+    buffers.dedup_by_key(|buffer| buffer.0);
+    buffers
+}
+
+// Add buffers to the innermost call, to be freed when it finishes. Buffers
+// written outside of any call, like by imports called by a function which
+// was called through `ScalarFunction::native`, are added to the plugin's
+// `unfreed` buffers instead.
+pub(crate) fn collect(
+    buffers: impl IntoIterator<Item = FatPointer>,
+    unfreed: &Mutex<Vec<FatPointer>>,
+) {
+    FRAMES.with(|frames| match frames.borrow_mut().last_mut() {
+        Some(frame) => frame.extend(buffers),
+        None => unfreed.lock().unwrap().extend(buffers),
+    });
+}
@@ -0,0 +1,136 @@
+//! Stopping plugins which are still running, for timeouts and cancellation.
+//!
+//! Wasmer can't interrupt running WASM from the outside so plugins are
+//! compiled with a middleware which adds a global flag to the module and
+//! checks it at the top of every loop. Setting the flag from another thread
+//! makes the plugin trap the next time it goes around a loop, which stops
+//! even plugins which never call back into the host.
+
+use std::sync::{mpsc, Mutex};
+use std::time::Duration;
+
+use wasmer::wasmparser::{Operator, Type as WpType, TypeOrFuncType};
+use wasmer::{
+    ExportIndex, FunctionMiddleware, Global, GlobalInit, GlobalType, LocalFunctionIndex,
+    MiddlewareError, MiddlewareReaderState, Module, ModuleMiddleware, Mutability, Store, Type, Val,
+};
+use wasmer_vm::ModuleInfo;
+
+use crate::{errors, CancelToken};
+
+// The name the flag is exported under
+pub(crate) const INTERRUPT_FLAG: &str = "wasm_plugin_interrupted";
+
+// The middleware remembers the index of the flag between transforming a
+// module's info and compiling its functions, so a store must only compile one
+// module at a time. Compiling already spreads the functions of a module
+// across every core so this costs little.
+static COMPILING: Mutex<()> = Mutex::new(());
+
+// Compile `source`, which must be done through here for stores using
+// `InterruptMiddleware`
+pub(crate) fn compile(store: &Store, source: &[u8]) -> errors::Result<Module> {
+    let _compiling = COMPILING
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    Ok(Module::new(store, source)?)
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct InterruptMiddleware {
+    // The global index of the flag in the module being compiled
+    flag: Mutex<Option<u32>>,
+}
+
+impl ModuleMiddleware for InterruptMiddleware {
+    fn generate_function_middleware(&self, _: LocalFunctionIndex) -> Box<dyn FunctionMiddleware> {
+        Box::new(FunctionInterrupt {
+            flag: self
+                .flag
+                .lock()
+                .unwrap()
+                .expect("the module info is transformed before its functions are compiled"),
+        })
+    }
+
+    fn transform_module_info(&self, module_info: &mut ModuleInfo) {
+        let flag = module_info
+            .globals
+            .push(GlobalType::new(Type::I32, Mutability::Var));
+        module_info
+            .global_initializers
+            .push(GlobalInit::I32Const(0));
+        module_info
+            .exports
+            .insert(INTERRUPT_FLAG.to_string(), ExportIndex::Global(flag));
+        *self.flag.lock().unwrap() = Some(flag.as_u32());
+    }
+}
+
+#[derive(Debug)]
+struct FunctionInterrupt {
+    flag: u32,
+}
+
+impl FunctionMiddleware for FunctionInterrupt {
+    fn feed<'a>(
+        &mut self,
+        operator: Operator<'a>,
+        state: &mut MiddlewareReaderState<'a>,
+    ) -> Result<(), MiddlewareError> {
+        let is_loop = matches!(operator, Operator::Loop { .. });
+        state.push_operator(operator);
+        if is_loop {
+            // if (flag) { unreachable }
+            state.extend(&[
+                Operator::GlobalGet {
+                    global_index: self.flag,
+                },
+                Operator::If {
+                    ty: TypeOrFuncType::Type(WpType::EmptyBlockType),
+                },
+                Operator::Unreachable,
+                Operator::End,
+            ]);
+        }
+        Ok(())
+    }
+}
+
+// Make `call` on this thread while a watchdog thread checks `should_stop`
+// every `poll_interval` and interrupts the plugin through `flag` once it
+// returns true. `token` is cancelled at the same time so imports which are
+// called afterwards fail rather than carrying on. Returns the call's result
+// and whether the plugin was interrupted, in which case the result is the
+// trap it caused. The flag is cleared again before returning so the plugin
+// can still be used.
+pub(crate) fn call_interruptible<R>(
+    flag: &Global,
+    token: &CancelToken,
+    poll_interval: Duration,
+    should_stop: impl Fn() -> bool + Send + Sync,
+    call: impl FnOnce() -> R,
+) -> (R, bool) {
+    let (done, finished) = mpsc::channel::<()>();
+    let result = std::thread::scope(|scope| {
+        scope.spawn(move || loop {
+            match finished.recv_timeout(poll_interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if should_stop() {
+                        token.cancel();
+                        let _ = flag.set(Val::I32(1));
+                        return;
+                    }
+                }
+                _ => return,
+            }
+        });
+        let result = crate::cancel::with_cancel_token(token.clone(), call);
+        drop(done);
+        result
+    });
+    // The watchdog has been joined so nothing sets the flag after this
+    let interrupted = matches!(flag.get(), Val::I32(1));
+    let _ = flag.set(Val::I32(0));
+    (result, interrupted)
+}
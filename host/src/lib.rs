@@ -1,4 +1,4 @@
-#![doc(html_root_url = "https://docs.rs/wasm_plugin_host/0.1.7")]
+#![doc(html_root_url = "https://docs.rs/wasm_plugin_host/0.2.0")]
 #![deny(missing_docs)]
 
 //! A low-ish level tool for easily hosting WASM based plugins.
@@ -11,7 +11,8 @@
 //!
 //! Loading a plugin is as simple as reading the .wasm file off disk.
 //!
-//! ```rust
+//! ```rust,no_run
+//! # use wasm_plugin_host::WasmPluginBuilder;
 //! # use std::error::Error;
 //! #
 //! # fn main() -> Result<(), Box<dyn Error>> {
@@ -25,8 +26,10 @@
 //!  the function takes no arguments and returns a single serde deserializable
 //! value:
 //!
-//! ```rust
-//! # #[derive(Deserialize)]
+//! ```rust,no_run
+//! # use wasm_plugin_host::WasmPluginBuilder;
+//! # use serde::Deserialize;
+//! # #[derive(Deserialize, Clone)]
 //! # struct ResultType;
 //! # use std::error::Error;
 //! #
@@ -38,8 +41,10 @@
 //! # }
 //! ```
 //! Or it takes a single serializable argument and returns a single result:
-//! ```rust
-//! # #[derive(Deserialize)]
+//! ```rust,no_run
+//! # use wasm_plugin_host::WasmPluginBuilder;
+//! # use serde::{Deserialize, Serialize};
+//! # #[derive(Deserialize, Clone)]
 //! # struct ResultType;
 //! # #[derive(Serialize, Default)]
 //! # struct Message;
@@ -61,27 +66,160 @@
 //! `serialize_bincode`: Uses serde and bincode. It is selected by default.
 //! `serialize_json`: Uses serde and serde_json.
 //! `serialize_nanoserde_json': Uses nanoserde.
+//! `serialize_cbor`: Uses serde and ciborium to produce CBOR (RFC 8949).
+//! `serialize_protobuf`: Uses prost to produce protocol buffers.
 //!
 //! Bincode is likely the best choice if all plugins the system uses will be
 //! written in Rust. Json is useful if a mix of languages will be used.
+//! CBOR is a good fit for plugins embedded in systems which already speak it,
+//! such as CoAP or COSE based ones.
+//! Protocol buffers suit plugin ecosystems which share `.proto` schemas
+//! across languages. With `serialize_protobuf` every argument and return
+//! type must be a `prost::Message`, such as a type generated by
+//! `prost-build`, `String`, `Vec<u8>`, a scalar or `()`. Tuples aren't
+//! messages so a plugin function takes at most one argument, unless it is
+//! exported with `#[export_function(argument_list)]` and called with
+//! `WasmPlugin::call_function_with_argument_list`.
+//!
+//! Plugins are compiled with Cranelift by default. If the `singlepass`
+//! feature is selected then the much faster, but less optimizing, Singlepass
+//! compiler can be chosen with `WasmPluginBuilder::from_source_with_compiler`.
+//!
+//! If the `wasi` feature is selected then plugins compiled for `wasm32-wasi`
+//! can be hosted by providing a `WasiConfig` to `WasmPluginBuilder::with_wasi`.
+//! WASI plugins get their randomness from WASI itself so `inject_getrandom`
+//! only matters for plugins which use wasm_plugin_guest's getrandom shim.
+//!
+//! If the `trap_backtrace` feature is selected then errors caused by a
+//! plugin trapping include a backtrace of the plugin's functions when they
+//! are displayed. The trap code and trace are always available from
+//! `WasmPluginError::trap_code` and `WasmPluginError::trace`.
+//!
+//! If the `tracing` feature is selected then compiling, instantiating and
+//! calling plugins is instrumented with [tracing](https://crates.io/crates/tracing)
+//! spans and events. Every call to a plugin function runs in a
+//! `wasm_plugin::call` span recording the function's name and the bytes sent
+//! and received. Calls the plugin makes to imported functions can be traced
+//! too, in `wasm_plugin::import` spans nested inside it, with
+//! `WasmPluginBuilder::with_import_tracing`. Without the feature there is no
+//! instrumentation at all.
+//!
+//! If the `either` feature is selected then `Either` is re-exported and
+//! plugin functions may return `Either<A, B>` to send back one of two types.
+//! It works with any of the serde based serialization features.
+//!
+//! Plugins built with wasm_plugin_guest's `host_logging` feature can send log
+//! records to the host, see `WasmPluginBuilder::with_logger`.
+//!
+//! If the `module_cache` feature is selected then compiled modules can be
+//! kept in a `ModuleCache` and reused when the same plugin is loaded again,
+//! see `WasmPluginBuilder::from_source_cached`.
+//!
+//! If the `compress_zstd` feature is selected then messages can be compressed
+//! with zstd, see `WasmPluginBuilder::with_compression`. Plugins need
+//! wasm_plugin_guest's `compress_zstd` feature to take part.
+//!
+//! If the `compress_messages` feature is selected then messages of 4 KiB or
+//! more are compressed as lz4 frames by default, see
+//! `WasmPluginBuilder::with_compression_threshold`. This helps plugins which
+//! exchange large data like images or arrays. Plugins need wasm_plugin_guest's
+//! `compress_messages` feature to take part.
+//!
+//! If the `serialize_rkyv` feature is selected then plugin functions which
+//! return large values can send them as [rkyv](https://crates.io/crates/rkyv)
+//! archives that are read in place rather than deserialized, see
+//! `WasmPlugin::call_function_zero_copy`. It works alongside whichever
+//! serialization feature is selected, which is still used for everything
+//! else. Plugins need wasm_plugin_guest's `serialize_rkyv` feature.
+//!
+//! If the `worker_pool` feature is selected then `WasmPluginWorkerPool` runs
+//! several instances of a plugin on their own threads which take calls from
+//! a shared queue.
+//!
+//! Rather than naming plugin functions with strings, the functions can be
+//! described by a trait shared with the plugin and called through a client
+//! generated by `wasm_plugin_client`:
+//!
+//! ```rust,no_run
+//! # use wasm_plugin_host::{wasm_plugin_client, WasmPluginBuilder};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! #[wasm_plugin_client]
+//! pub trait Geometry {
+//!     fn area(&self, size: (f32, f32)) -> f32;
+//!     fn scale(&mut self, factor: f32, size: (f32, f32)) -> (f32, f32);
+//!     fn reset(&mut self);
+//! }
+//!
+//! let mut client = GeometryClient::new(WasmPluginBuilder::from_file("geometry.wasm")?.finish()?);
+//! let area = client.area((2.0, 3.0))?;
+//! let size = client.scale(2.0, (2.0, 3.0))?;
+//! client.reset()?;
+//! # Ok(())
+//! # }
+//! ```
 //!
 //! ## Limitations
 //!
-//! There is no reflection so you must know up front which functions
-//! a plugin exports and their signatures.
+//! The functions a plugin exports and imports can be listed with
+//! `WasmPlugin::exported_functions` and `WasmPlugin::imported_function_names`
+//! but their signatures can't, so you must know up front what types each
+//! function takes and returns.
 
 use std::{
+    borrow::Borrow,
+    collections::{HashMap, HashSet},
     path::Path,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, RwLock},
 };
 
-use wasmer::{Exports, Function, Instance, LazyInit, Memory, MemoryView, Module, Store, WasmerEnv};
-pub use wasmer::{Extern, HostFunction};
+use buffer_pool::BufferPool;
+pub use cancel::CancelToken;
+pub use compression::{Compression, DEFAULT_COMPRESSION_THRESHOLD};
+#[cfg(feature = "either")]
+pub use either::Either;
+pub use logging::LogLevel;
+pub use metrics::PluginMetrics;
+#[cfg(feature = "module_cache")]
+pub use module_cache::ModuleCache;
+#[cfg(feature = "worker_pool")]
+pub use pool::WasmPluginWorkerPool;
+pub use registry::PluginRegistry;
+pub use scalar::{ScalarArgs, ScalarFunction};
+pub use service::PluginService;
+#[cfg(feature = "wasi")]
+pub use wasi::WasiConfig;
+pub use wasm_plugin_host_derive::wasm_plugin_client;
+use wasmer::{
+    Exports, ExternType, Function, HostEnvInitError, LazyInit, Module, Type, WasmTypeList,
+    WasmerEnv,
+};
+pub use wasmer::{Extern, Features, HostFunction, ImportObject, Instance, Memory, Store, Table};
+#[cfg(feature = "serialize_rkyv")]
+pub use zero_copy::ZeroCopy;
 
+mod buffer_pool;
+mod call_context;
+mod cancel;
+mod compression;
 #[allow(missing_docs)]
 pub mod errors;
+mod garbage;
+mod interrupt;
+mod logging;
+mod metrics;
+#[cfg(feature = "module_cache")]
+mod module_cache;
+#[cfg(feature = "worker_pool")]
+pub mod pool;
+pub mod registry;
+mod scalar;
 #[allow(missing_docs)]
 pub mod serialization;
+pub mod service;
+#[cfg(feature = "wasi")]
+pub mod wasi;
+#[cfg(feature = "serialize_rkyv")]
+mod zero_copy;
 use bitfield::bitfield;
 use serialization::{Deserializable, Serializable};
 
@@ -94,37 +232,194 @@ bitfield! {
     len, set_len: 63, 32;
 }
 
-#[derive(WasmerEnv, Clone)]
+#[derive(Clone)]
 struct Env<C>
 where
     C: Send + Sync + Clone + 'static,
 {
-    #[wasmer(export(name = "allocate_message_buffer"))]
     allocator: LazyInit<Function>,
-    #[wasmer(export)]
     memory: LazyInit<Memory>,
-    garbage: Arc<Mutex<Vec<FatPointer>>>,
+    exports: LazyInit<Exports>,
+    settings: Arc<RwLock<Settings>>,
+    unfreed: Arc<Mutex<Vec<FatPointer>>>,
     ctx: C,
+    // The name recorded in the `wasm_plugin::import` span, if imports are
+    // being traced. Kept here rather than captured by the shim because
+    // wasmer can't import closures which capture anything.
+    #[cfg(feature = "tracing")]
+    import_name: Option<Arc<str>>,
+}
+
+impl<C: Send + Sync + Clone + 'static> WasmerEnv for Env<C> {
+    fn init_with_instance(&mut self, instance: &Instance) -> Result<(), HostEnvInitError> {
+        let settings = self.settings.read().unwrap();
+        let mut exports = instance.exports.clone();
+        expose_memory(&mut exports, &settings);
+        self.allocator
+            .initialize(exports.get_function("allocate_message_buffer")?.clone());
+        self.memory
+            .initialize(exports.get_memory(&settings.memory_export_name)?.clone());
+        drop(settings);
+        self.exports.initialize(exports);
+        Ok(())
+    }
 }
 
 impl<C: Send + Sync + Clone + 'static> Env<C> {
-    fn new(garbage: Arc<Mutex<Vec<FatPointer>>>, ctx: C) -> Self {
+    fn new(settings: Arc<RwLock<Settings>>, unfreed: Arc<Mutex<Vec<FatPointer>>>, ctx: C) -> Self {
         Self {
             allocator: Default::default(),
             memory: Default::default(),
-            garbage,
+            exports: Default::default(),
+            settings,
+            unfreed,
             ctx,
+            #[cfg(feature = "tracing")]
+            import_name: None,
         }
     }
 
-    fn message_buffer(&self) -> MessageBuffer {
+    fn message_buffer(&self) -> MessageBuffer<'_> {
         unsafe {
-            MessageBuffer {
-                allocator: self.allocator.get_unchecked(),
-                memory: self.memory.get_unchecked(),
-                garbage: vec![],
+            MessageBuffer::new(
+                self.memory.get_unchecked(),
+                self.allocator.get_unchecked(),
+                &self.settings.read().unwrap(),
+            )
+        }
+    }
+
+    fn plugin_handle(&self) -> PluginHandle<'_> {
+        PluginHandle {
+            exports: unsafe { self.exports.get_unchecked() },
+            settings: &self.settings,
+            unfreed: &self.unfreed,
+        }
+    }
+
+    // The body shared by every imported function's shim. Buffers written
+    // while producing the return value are handed off to the call in
+    // progress so they can be freed once it finishes.
+    fn call_import(
+        &self,
+        call: impl FnOnce(&mut MessageBuffer) -> errors::Result<Option<FatPointer>>,
+    ) -> u64 {
+        // Plugins can't be interrupted but a cancelled call can at least be
+        // stopped when it calls back into the host
+        if cancel::is_current_call_cancelled() {
+            raise(errors::WasmPluginError::Cancelled);
+        }
+        #[cfg(feature = "tracing")]
+        let _span = self
+            .import_name
+            .as_ref()
+            .map(|name| tracing::info_span!("wasm_plugin::import", fn_name = %name).entered());
+        let mut buffer = self.message_buffer();
+        let r = call(&mut buffer)
+            .unwrap_or_else(|e| raise(e))
+            .map(|p| p.0)
+            .unwrap_or(0);
+        garbage::collect(buffer.garbage.drain(..), &self.unfreed);
+        r
+    }
+}
+
+/// The default limit on the size of a single message sent across the
+/// host-plugin boundary.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// The default limit on the number of bytes read when deserializing a
+/// single value received from a plugin.
+pub const DEFAULT_DESERIALIZATION_LIMIT: u64 = DEFAULT_MAX_MESSAGE_SIZE as u64;
+
+// How often `WasmPlugin::call_function_with_cancel_token` checks whether its
+// token has been cancelled
+const CANCEL_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// The version of the protocol used to talk to plugins. It must match the
+/// `ABI_VERSION` of the wasm_plugin_guest the plugin was built with, see
+/// `WasmPlugin::verify_abi_version`.
+pub const ABI_VERSION: u32 = 1;
+
+// Settings which are chosen on the builder but are also needed by the envs
+// of imported functions, which may have been created before the setting was
+// changed.
+#[derive(Clone, Debug)]
+struct Settings {
+    max_message_size: usize,
+    deserialization_limit: u64,
+    compression: Compression,
+    compression_threshold: usize,
+    memory_export_name: String,
+    shared_memory: Option<Memory>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            deserialization_limit: DEFAULT_DESERIALIZATION_LIMIT,
+            compression: Compression::default_for_features(),
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            memory_export_name: "memory".to_string(),
+            shared_memory: None,
+        }
+    }
+}
+
+// Imported functions can't return errors to the plugin so they are raised as
+// a trap instead. `WasmPluginError`'s conversion from `RuntimeError` recovers
+// the original error when the trap reaches `call_function`.
+fn raise(e: errors::WasmPluginError) -> ! {
+    wasmer::RuntimeError::raise(Box::new(e))
+}
+
+/// The compiler used to translate a plugin's WASM into native code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compiler {
+    /// Cranelift produces well optimized code but takes a while to do it. It
+    /// is what `from_source` and `from_file` use.
+    Cranelift,
+    /// Singlepass compiles very quickly but produces slower code. It is a
+    /// good fit for short lived or untrusted plugins. Run
+    /// `cargo bench --features singlepass --bench compilers` to compare the
+    /// two on your machine.
+    #[cfg(feature = "singlepass")]
+    Singlepass,
+}
+
+impl Compiler {
+    // Plugins compiled with these stores can be interrupted, see the
+    // `interrupt` module
+    fn store(self) -> Store {
+        self.store_with(None)
+    }
+
+    fn store_with_features(self, features: Features) -> Store {
+        self.store_with(Some(features))
+    }
+
+    // Without `features` each compiler enables the proposals it supports
+    fn store_with(self, features: Option<Features>) -> Store {
+        use wasmer::CompilerConfig;
+        let interrupt = Arc::new(interrupt::InterruptMiddleware::default());
+        let mut jit = match self {
+            Compiler::Cranelift => {
+                let mut config = wasmer::Cranelift::default();
+                config.push_middleware(interrupt);
+                wasmer::JIT::new(config)
+            }
+            #[cfg(feature = "singlepass")]
+            Compiler::Singlepass => {
+                let mut config = wasmer::Singlepass::default();
+                config.push_middleware(interrupt);
+                wasmer::JIT::new(config)
             }
+        };
+        if let Some(features) = features {
+            jit = jit.features(features);
         }
+        Store::new(&jit.engine())
     }
 }
 
@@ -133,8 +428,27 @@ pub struct WasmPluginBuilder {
     module: Module,
     store: Store,
     env: Exports,
-    // TODO: Can we do this without the lock?
-    garbage: Arc<Mutex<Vec<FatPointer>>>,
+    settings: Arc<RwLock<Settings>>,
+    unfreed: Arc<Mutex<Vec<FatPointer>>>,
+    error_handler: Option<ErrorHandler>,
+    metrics: Option<Metrics>,
+    #[cfg(feature = "wasi")]
+    wasi: Option<WasiConfig>,
+    #[cfg(feature = "tracing")]
+    import_tracing: bool,
+    name: Option<String>,
+    import_objects: Vec<ImportObject>,
+    init_config: Option<errors::Result<Vec<u8>>>,
+    buffer_pool: Option<usize>,
+    table_limit: Option<u32>,
+    globals_limit: Option<usize>,
+    abi_check: bool,
+    // The first function imported under a name which was already taken,
+    // reported by `finish`
+    duplicate_import: Option<String>,
+    // Whether the log import is still the default, which `with_logger` may
+    // replace
+    default_logger: bool,
 }
 impl WasmPluginBuilder {
     /// Load a plugin off disk and prepare it for use.
@@ -145,10 +459,116 @@ impl WasmPluginBuilder {
 
     /// Load a plugin from WASM source and prepare it for use.
     pub fn from_source(source: &[u8]) -> errors::Result<Self> {
-        let store = Store::default();
-        let module = Module::new(&store, source)?;
+        Self::from_source_with_store(Compiler::Cranelift.store(), source)
+    }
+
+    /// Load a plugin from WASM source and prepare it for use, checking that
+    /// the source is a valid WASM module first. Invalid source, like a file
+    /// which isn't WASM at all, produces a `WasmPluginError::InvalidWasm`
+    /// describing the problem rather than a compiler error.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::{errors::WasmPluginError, WasmPluginBuilder};
+    /// let result = WasmPluginBuilder::from_source_verified(b"[package]\nname = \"oops\"");
+    /// assert!(matches!(result, Err(WasmPluginError::InvalidWasm(_))));
+    /// ```
+    pub fn from_source_verified(source: &[u8]) -> errors::Result<Self> {
+        verify_source(source)?;
+        Self::from_source(source)
+    }
+
+    /// Load a plugin off disk and prepare it for use, compiling it with the
+    /// given compiler.
+    pub fn from_file_with_compiler(
+        path: impl AsRef<Path>,
+        compiler: Compiler,
+    ) -> errors::Result<Self> {
+        let source = std::fs::read(path)?;
+        Self::from_source_with_compiler(&source, compiler)
+    }
+
+    /// Load a plugin from WASM source and prepare it for use, compiling it
+    /// with the given compiler.
+    pub fn from_source_with_compiler(source: &[u8], compiler: Compiler) -> errors::Result<Self> {
+        Self::from_source_with_store(compiler.store(), source)
+    }
+
+    /// Load a plugin off disk and prepare it for use, compiling it with the
+    /// given compiler and WASM proposals enabled.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::{Compiler, Features, WasmPluginBuilder};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut features = Features::new();
+    /// features.simd(true).reference_types(true);
+    /// let plugin = WasmPluginBuilder::from_file_with_features(
+    ///     "path/to/plugin.wasm",
+    ///     Compiler::Cranelift,
+    ///     features,
+    /// )?
+    /// .finish()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn from_file_with_features(
+        path: impl AsRef<Path>,
+        compiler: Compiler,
+        features: Features,
+    ) -> errors::Result<Self> {
+        let source = std::fs::read(path)?;
+        Self::from_source_with_features(&source, compiler, features)
+    }
+
+    /// Load a plugin from WASM source and prepare it for use, compiling it
+    /// with the given compiler and WASM proposals enabled. The source is
+    /// validated against `features`, so a plugin which uses a proposal that
+    /// isn't enabled, like SIMD or reference types, fails with a compile
+    /// error here rather than depending on the defaults of the target.
+    pub fn from_source_with_features(
+        source: &[u8],
+        compiler: Compiler,
+        features: Features,
+    ) -> errors::Result<Self> {
+        Self::from_source_with_store(compiler.store_with_features(features), source)
+    }
+
+    /// Load a plugin off disk and prepare it for use within a `Store` provided
+    /// by the caller.
+    pub fn from_file_with_store(store: Store, path: impl AsRef<Path>) -> errors::Result<Self> {
+        let source = std::fs::read(path)?;
+        Self::from_source_with_store(store, &source)
+    }
+
+    /// Load a plugin from WASM source and prepare it for use within a `Store`
+    /// provided by the caller. This gives full control over the engine,
+    /// compiler, tunables and middleware used for the plugin, and allows them
+    /// to be shared between several plugins by cloning the `Store`.
+    ///
+    /// Plugins compiled with a caller's `Store` can't be interrupted, so
+    /// `WasmPlugin::call_function_with_timeout` fails with
+    /// `WasmPluginError::Uninterruptible` for them.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(store, source), fields(source_size = source.len()))
+    )]
+    pub fn from_source_with_store(store: Store, source: &[u8]) -> errors::Result<Self> {
+        let module = interrupt::compile(&store, source)?;
+        Ok(Self::from_module(store, module))
+    }
+
+    /// Prepare a plugin for use from WASM source, reusing the compiled
+    /// module from `cache` if the same source has been loaded through it
+    /// before. The plugin uses the cache's `Store`.
+    #[cfg(feature = "module_cache")]
+    pub fn from_source_cached(cache: &ModuleCache, source: &[u8]) -> errors::Result<Self> {
+        let module = cache.get_or_compile(source)?;
+        Ok(Self::from_module(cache.store().clone(), module))
+    }
+
+    fn from_module(store: Store, module: Module) -> Self {
         let mut env = wasmer::Exports::new();
-        let garbage: Arc<Mutex<Vec<FatPointer>>> = Default::default();
+        let settings: Arc<RwLock<Settings>> = Default::default();
+        let unfreed: Arc<Mutex<Vec<FatPointer>>> = Default::default();
         env.insert(
             "abort",
             Function::new_native(&store, |_: u32, _: u32, _: i32, _: i32| {}),
@@ -159,33 +579,419 @@ impl WasmPluginBuilder {
                 "__getrandom",
                 Function::new_native_with_env(
                     &store,
-                    Env::new(garbage.clone(), ()),
+                    Env::new(settings.clone(), unfreed.clone(), ()),
                     getrandom_shim,
                 ),
             );
         }
 
-        Ok(Self {
+        let builder = Self {
             module,
             store,
             env,
-            garbage,
-        })
+            settings,
+            unfreed,
+            error_handler: None,
+            metrics: None,
+            #[cfg(feature = "wasi")]
+            wasi: None,
+            #[cfg(feature = "tracing")]
+            import_tracing: false,
+            name: None,
+            import_objects: vec![],
+            init_config: None,
+            buffer_pool: None,
+            table_limit: None,
+            globals_limit: None,
+            abi_check: false,
+            duplicate_import: None,
+            default_logger: true,
+        };
+        builder.import_function(logging::LOG_IMPORT_NAME, logging::default_logger)
+    }
+
+    /// Provide the WASI imports (`wasi_snapshot_preview1` or `wasi_unstable`)
+    /// to a plugin compiled for `wasm32-wasi`. The plugin's other imports are
+    /// provided as usual, alongside WASI.
+    ///
+    /// WASI plugins are treated like any other plugin: their `_start` function
+    /// is not run and they are used through `call_function`.
+    #[cfg(feature = "wasi")]
+    pub fn with_wasi(mut self, config: WasiConfig) -> Self {
+        self.wasi = Some(config);
+        self
+    }
+
+    /// Provide every import in an existing `ImportObject`, in any namespace,
+    /// to the plugin. This is useful for imports built by other libraries.
+    ///
+    /// These imports take precedence over the defaults the builder provides,
+    /// such as WASI or the `abort` no-op. An import which clashes with a
+    /// function registered with `import_function` or friends, or with one
+    /// from another import object, makes `finish` fail with
+    /// `WasmPluginError::DuplicateImport`.
+    pub fn with_import_object(mut self, imports: ImportObject) -> Self {
+        self.import_objects.push(imports);
+        self
+    }
+
+    /// Limit the size of messages sent across the host-plugin boundary, in
+    /// either direction, to `max_message_size` bytes. This protects the host
+    /// from misbehaving plugins which try to make it allocate huge buffers.
+    /// Calls which would exceed the limit fail with
+    /// `WasmPluginError::MessageTooLarge`.
+    ///
+    /// The default is `DEFAULT_MAX_MESSAGE_SIZE`.
+    pub fn with_max_message_size(self, max_message_size: usize) -> Self {
+        self.settings.write().unwrap().max_message_size = max_message_size;
+        self
+    }
+
+    /// Refuse to instantiate plugins whose tables start with more than
+    /// `elements` elements in total. Along with `with_globals_limit` this
+    /// protects the host from untrusted plugins which declare huge amounts of
+    /// state. `finish` fails with `WasmPluginError::ResourceLimitExceeded` if
+    /// the plugin exceeds the limit.
+    pub fn with_table_limit(mut self, elements: u32) -> Self {
+        self.table_limit = Some(elements);
+        self
+    }
+
+    /// Refuse to instantiate plugins which define or import more than
+    /// `globals` globals. See `with_table_limit`.
+    pub fn with_globals_limit(mut self, globals: usize) -> Self {
+        self.globals_limit = Some(globals);
+        self
+    }
+
+    /// Check that the plugin was built with a wasm_plugin_guest which speaks
+    /// the same protocol as this crate before it is initialized. `finish`
+    /// fails with `WasmPluginError::AbiMismatch` if it doesn't. See
+    /// `WasmPlugin::verify_abi_version`.
+    pub fn with_abi_check(mut self) -> Self {
+        self.abi_check = true;
+        self
+    }
+
+    /// Limit deserializing a single argument or return value sent by the
+    /// plugin to reading `bytes` bytes. With bincode this stops a malicious
+    /// plugin from claiming a collection is so large that decoding it
+    /// exhausts the host's memory. Values which exceed the limit fail with
+    /// `WasmPluginError::DeserializationError`.
+    ///
+    /// The default is `DEFAULT_DESERIALIZATION_LIMIT`.
+    pub fn with_deserialization_limit(self, bytes: u64) -> Self {
+        self.settings.write().unwrap().deserialization_limit = bytes;
+        self
+    }
+
+    /// Use the memory the plugin exports as `name` to pass messages, rather
+    /// than the one exported as `memory`. Some toolchains use a different
+    /// name for a module's linear memory.
+    ///
+    /// If the plugin doesn't export a memory under this name, the first
+    /// memory it does export is used instead, so this is only needed for
+    /// plugins which export several.
+    pub fn with_memory_export_name(self, name: &str) -> Self {
+        self.settings.write().unwrap().memory_export_name = name.to_string();
+        self
+    }
+
+    /// Provide `memory` to the plugin as its `env.memory` import, for plugins
+    /// built to import their linear memory rather than define it. The memory
+    /// must belong to the plugin's `Store`, see `from_file_with_store`.
+    /// Plugins which import a memory the host doesn't provide are given a
+    /// new one of the size they ask for, so this is only needed to access
+    /// the memory from the host. The host keeps its own handle to the memory
+    /// and can read or write it directly before calling into the plugin,
+    /// which suits zero-copy texture sharing or ring buffers shared with the
+    /// host.
+    ///
+    /// Messages are still passed through the plugin's
+    /// `allocate_message_buffer` and are written into this memory, so the
+    /// plugin's allocator owns all of it. The host must only write to regions
+    /// the plugin has set aside for it, for example a buffer the plugin
+    /// allocated and returned the address of, otherwise it will corrupt the
+    /// plugin's heap and the messages stored in it. `WasmPlugin::reset` and
+    /// `WasmPlugin::reload` reuse the memory rather than starting with an
+    /// empty one.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # use wasmer::{Memory, MemoryType, Store};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let store = Store::default();
+    /// let memory = Memory::new(&store, MemoryType::new(17, None, false))?;
+    /// let mut plugin = WasmPluginBuilder::from_file_with_store(store, "path/to/plugin.wasm")?
+    ///     .with_shared_memory(memory.clone())
+    ///     .finish()?;
+    /// let address: u32 = plugin.call_function("frame_buffer")?;
+    /// unsafe {
+    ///     memory.data_unchecked_mut()[address as usize] = 0xff;
+    /// }
+    /// plugin.call_function::<()>("render")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_shared_memory(mut self, memory: Memory) -> Self {
+        self.env.insert("memory", memory.clone());
+        self.settings.write().unwrap().shared_memory = Some(memory);
+        self
+    }
+
+    /// Compress messages sent to the plugin. The plugin must be built with
+    /// the matching wasm_plugin_guest feature to read them.
+    pub fn with_compression(self, compression: Compression) -> Self {
+        self.settings.write().unwrap().compression = compression;
+        self
+    }
+
+    /// Only compress messages which are at least `bytes` long. Compressing
+    /// small messages costs more than it saves. Defaults to
+    /// `DEFAULT_COMPRESSION_THRESHOLD`.
+    pub fn with_compression_threshold(self, bytes: usize) -> Self {
+        self.settings.write().unwrap().compression_threshold = bytes;
+        self
+    }
+
+    /// Keep up to `max_buffers` of the buffers used to send arguments to the
+    /// plugin between calls and reuse them, rather than allocating a new
+    /// buffer in the plugin's memory for every call. This saves work in the
+    /// plugin's allocator for hosts which make many calls with arguments.
+    ///
+    /// Buffers are allocated in powers of two so that each one can be reused
+    /// for arguments of similar sizes. When more than `max_buffers` are
+    /// free the smallest is released.
+    pub fn with_buffer_pool(mut self, max_buffers: usize) -> Self {
+        self.buffer_pool = Some(max_buffers);
+        self
+    }
+
+    /// Give the plugin a name which is included when it is displayed. This
+    /// makes logs easier to follow when several plugins are loaded.
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Send `config` to the plugin's `wasm_plugin_guest::init_function` once
+    /// it has been instantiated. This is the place for settings which apply
+    /// to the whole plugin, rather than passing them to every call. Plugins
+    /// without an init function ignore it.
+    ///
+    /// The config is sent again whenever the plugin is reinstantiated by
+    /// `WasmPlugin::reset`.
+    pub fn with_init_config<T: Serializable>(mut self, config: &T) -> Self {
+        // Any error is reported by `finish`
+        self.init_config = Some(config.serialize());
+        self
+    }
+
+    /// Receive the log records the plugin sends with wasm_plugin_guest's
+    /// logging macros, from its `host_logging` feature. The logger is given
+    /// each record's level, its target, which is the module it was logged
+    /// from, and its message.
+    ///
+    /// Without a logger the records are passed to `tracing` if the `tracing`
+    /// feature is selected and dropped otherwise.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?
+    ///     .with_logger(|level, target, message| eprintln!("[{} {}] {}", level, target, message))
+    ///     .finish()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_logger(self, logger: impl Fn(LogLevel, &str, &str) + Send + 'static) -> Self {
+        let mut builder = self.import_function(
+            logging::LOG_IMPORT_NAME,
+            move |record: logging::LogRecord| {
+                let (level, target, message) = logging::into_parts(record);
+                logger(LogLevel::from_wire(level), &target, &message)
+            },
+        );
+        builder.default_logger = false;
+        builder
+    }
+
+    /// Call `handler` when the plugin calls its `abort` import, rather than
+    /// ignoring it. AssemblyScript plugins call `abort` when an assertion
+    /// fails or they hit an unrecoverable error, passing the address of the
+    /// message, the address of the file name, the line and the column.
+    pub fn with_abort_handler(
+        mut self,
+        handler: impl Fn(u32, u32, i32, i32) + Send + Sync + 'static,
+    ) -> Self {
+        let handler: AbortHandler = Arc::new(handler);
+        let env = Env::new(self.settings.clone(), self.unfreed.clone(), handler);
+        self.env.insert(
+            "abort",
+            Function::new_native_with_env(
+                &self.store,
+                env,
+                |env: &Env<AbortHandler>, message: u32, file: u32, line: i32, column: i32| {
+                    (env.ctx)(message, file, line, column)
+                },
+            ),
+        );
+        self
+    }
+
+    /// Report calls to the plugin's `abort` import with `tracing::error!`,
+    /// including the message and file name read from the plugin's memory as
+    /// AssemblyScript strings. See `with_abort_handler`.
+    #[cfg(feature = "tracing")]
+    pub fn with_debug_abort(mut self) -> Self {
+        let env = Env::new(self.settings.clone(), self.unfreed.clone(), ());
+        self.env.insert(
+            "abort",
+            Function::new_native_with_env(&self.store, env, debug_abort),
+        );
+        self
+    }
+
+    /// Register a callback which will be invoked with every error produced
+    /// while calling one of the plugin's functions, before the error is
+    /// returned to the caller. This is a convenient place for cross-cutting
+    /// concerns like logging or metrics.
+    ///
+    /// The handler is called while the plugin is in the middle of a call so
+    /// it must not call back into the plugin, and it must not panic.
+    /// Registering a second handler replaces the first.
+    pub fn register_error_handler(
+        mut self,
+        handler: impl Fn(&errors::WasmPluginError) + Send + Sync + 'static,
+    ) -> Self {
+        self.error_handler = Some(ErrorHandler(Arc::new(handler)));
+        self
+    }
+
+    /// Report every call made through the plugin's `call_function` family
+    /// of methods to `metrics`, with how long it took and how much data was
+    /// passed each way. This makes it easy to feed a monitoring system like
+    /// Prometheus without wrapping every call site. Registering a second
+    /// `PluginMetrics` replaces the first.
+    ///
+    /// Each call in a `call_function_batch` is reported separately. Calls a
+    /// plugin makes back into itself through a `PluginHandle` aren't
+    /// reported.
+    pub fn with_metrics(mut self, metrics: impl PluginMetrics + 'static) -> Self {
+        self.metrics = Some(Metrics(Arc::new(metrics)));
+        self
+    }
+
+    /// Wrap every function imported after this is called in a
+    /// `tracing::info_span!` named `wasm_plugin::import` which records the
+    /// function's name. Each one is a child of the `wasm_plugin::call` span of
+    /// the call it happens during, which shows how much of a call's time is
+    /// spent in host callbacks.
+    #[cfg(feature = "tracing")]
+    pub fn with_import_tracing(mut self) -> Self {
+        self.import_tracing = true;
+        self
+    }
+
+    fn import(self, name: impl ToString, value: impl Into<Extern>) -> Self {
+        let name = name.to_string();
+        let replaces_default = name == logging::LOG_IMPORT_NAME && self.default_logger;
+        self.import_into_env(
+            format!("wasm_plugin_imported__{}", name),
+            value,
+            replaces_default,
+        )
     }
 
-    fn import(mut self, name: impl ToString, value: impl Into<Extern>) -> Self {
-        let name = format!("wasm_plugin_imported__{}", name.to_string());
+    // Import `value` into the `env` namespace under exactly `name`, noting
+    // the first name which is imported twice
+    fn import_into_env(
+        mut self,
+        name: String,
+        value: impl Into<Extern>,
+        replaces_default: bool,
+    ) -> Self {
+        if self.env.contains(name.as_str()) && !replaces_default && self.duplicate_import.is_none()
+        {
+            // Reported by `finish`
+            self.duplicate_import = Some(format!("env.{}", name));
+        }
         self.env.insert(name, value);
         self
     }
 
-    // FIXME: There is a lot of problematic duplication in this code. I need
-    // to sit down and come up with a better abstraction.
+    // Wrap `call` in a native function with the signature the guest expects
+    // for an import with or without an argument and return value, and
+    // import it into the plugin.
+    fn import_with_env<C, G>(
+        self,
+        name: impl ToString,
+        env: Env<C>,
+        has_arg: bool,
+        has_return: bool,
+        call: G,
+    ) -> Self
+    where
+        C: Send + Sync + Clone + 'static,
+        G: Fn(
+                &Env<C>,
+                &mut MessageBuffer,
+                Option<(usize, usize)>,
+            ) -> errors::Result<Option<FatPointer>>
+            + Send
+            + 'static,
+    {
+        #[cfg(feature = "tracing")]
+        let env = Env {
+            import_name: self.import_tracing.then(|| name.to_string().into()),
+            ..env
+        };
+        let f = match (has_arg, has_return) {
+            (true, true) => Function::new_native_with_env(
+                &self.store,
+                env,
+                move |env: &Env<C>, ptr: u32, len: u32| -> u64 {
+                    env.call_import(|buffer| call(env, buffer, Some((ptr as usize, len as usize))))
+                },
+            ),
+            (true, false) => Function::new_native_with_env(
+                &self.store,
+                env,
+                move |env: &Env<C>, ptr: u32, len: u32| {
+                    env.call_import(|buffer| call(env, buffer, Some((ptr as usize, len as usize))));
+                },
+            ),
+            (false, true) => {
+                Function::new_native_with_env(&self.store, env, move |env: &Env<C>| -> u64 {
+                    env.call_import(|buffer| call(env, buffer, None))
+                })
+            }
+            (false, false) => {
+                Function::new_native_with_env(&self.store, env, move |env: &Env<C>| {
+                    env.call_import(|buffer| call(env, buffer, None));
+                })
+            }
+        };
+        self.import(name, f)
+    }
 
     /// Import a function defined in the host into the guest. The function's
     /// arguments and return type must all be serializable.
     /// An immutable reference to `ctx` will be passed to the function as it's
-    /// first argument each time it's called.
+    /// first argument each time it's called, followed by up to eight
+    /// arguments sent by the plugin.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?
+    ///     .import_function_with_context("scale", 10u32, |factor: &u32, a: u32, b: u32| {
+    ///         (a * factor, b * factor)
+    ///     })
+    ///     .finish()?;
+    /// # Ok(())
+    /// # }
+    /// ```
     ///
     /// NOTE: This method exists due to a limitation in the underlying Waswer
     /// engine which currently doesn't support imported closures with
@@ -203,128 +1009,686 @@ impl WasmPluginBuilder {
         ctx: C,
         value: F,
     ) -> Self {
-        let env = Env::new(self.garbage.clone(), ctx);
-
-        if F::has_arg() {
-            let f = if F::has_return() {
-                let wrapped = move |env: &Env<C>, ptr: u32, len: u32| -> u64 {
-                    let mut buffer = env.message_buffer();
-                    let r = value
-                        .call_with_input(&mut buffer, ptr as usize, len as usize, &env.ctx)
-                        .unwrap()
-                        .map(|p| p.0)
-                        .unwrap_or(0);
-                    env.garbage.lock().unwrap().extend(buffer.garbage.drain(..));
-                    r
-                };
-                Function::new_native_with_env(&self.store, env, wrapped)
-            } else {
-                let wrapped = move |env: &Env<C>, ptr: u32, len: u32| {
-                    let mut buffer = env.message_buffer();
-                    value
-                        .call_with_input(&mut buffer, ptr as usize, len as usize, &env.ctx)
-                        .unwrap();
-                    env.garbage.lock().unwrap().extend(buffer.garbage.drain(..));
-                };
-                Function::new_native_with_env(&self.store, env, wrapped)
-            };
-            self.import(name, f)
-        } else {
-            let f = if F::has_return() {
-                let wrapped = move |env: &Env<C>| -> u64 {
-                    let mut buffer = env.message_buffer();
-                    let r = value
-                        .call_without_input(&mut buffer, &env.ctx)
-                        .unwrap()
-                        .map(|p| p.0)
-                        .unwrap_or(0);
-                    env.garbage.lock().unwrap().extend(buffer.garbage.drain(..));
-                    r
-                };
-                Function::new_native_with_env(&self.store, env, wrapped)
-            } else {
-                let wrapped = move |env: &Env<C>| {
-                    let mut buffer = env.message_buffer();
-                    value.call_without_input(&mut buffer, &env.ctx).unwrap();
-                    env.garbage.lock().unwrap().extend(buffer.garbage.drain(..));
-                };
-                Function::new_native_with_env(&self.store, env, wrapped)
-            };
-            self.import(name, f)
-        }
+        let env = Env::new(self.settings.clone(), self.unfreed.clone(), ctx);
+        self.import_with_env(
+            name,
+            env,
+            F::has_arg(),
+            F::has_return(),
+            move |env, buffer, input| match input {
+                Some((ptr, len)) => value.call_with_input(buffer, ptr, len, &env.ctx),
+                None => value.call_without_input(buffer, &env.ctx),
+            },
+        )
+    }
+
+    /// Import a function defined in the host into the guest which shares a
+    /// mutable context with other imports. The context is locked for the
+    /// duration of each call and a mutable reference to it is passed to the
+    /// function as it's first argument.
+    ///
+    /// Every import given a clone of the same `Arc` sees the same context so
+    /// this is the way for several related imports to share something like a
+    /// cache. The host can keep its own clone to inspect the context between
+    /// calls, but must not hold the lock while calling into the plugin or
+    /// the imports will deadlock. If an import panics while it holds the lock
+    /// the imports keep working with the context as it was left, rather than
+    /// failing every later call because the lock is poisoned.
+    ///
+    /// ```rust,no_run
+    /// # use std::{collections::HashMap, sync::{Arc, Mutex}};
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let cache: Arc<Mutex<HashMap<String, String>>> = Default::default();
+    /// let plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?
+    ///     .import_function_with_shared_context(
+    ///         "cache_insert",
+    ///         cache.clone(),
+    ///         |cache: &mut HashMap<String, String>, (key, value): (String, String)| {
+    ///             cache.insert(key, value);
+    ///         },
+    ///     )
+    ///     .import_function_with_shared_context(
+    ///         "cache_get",
+    ///         cache.clone(),
+    ///         |cache: &mut HashMap<String, String>, key: String| cache.get(&key).cloned(),
+    ///     )
+    ///     .finish()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn import_function_with_shared_context<Args, F, C>(
+        self,
+        name: impl ToString,
+        ctx: Arc<Mutex<C>>,
+        value: F,
+    ) -> Self
+    where
+        F: ImportableFnWithContext<Arc<Mutex<C>>, Shared<Args>> + Send + 'static,
+        C: Send + 'static,
+    {
+        self.import_function_with_context(name, ctx, value)
+    }
+
+    /// Import a function defined in the host into the guest which owns some
+    /// mutable state. A mutable reference to `state` is passed to the
+    /// function as it's first argument each time it's called.
+    ///
+    /// Unlike `import_function_with_shared_context` the state can't be
+    /// shared with other imports or the host. It's still kept behind a
+    /// `Mutex` because clones of a `WasmPlugin` share its imports and may be
+    /// called from different threads, but the lock is never contended by
+    /// anything else so taking it is cheap.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?
+    ///     .import_function_stateful("next_id", 0u64, |next: &mut u64| {
+    ///         *next += 1;
+    ///         *next
+    ///     })
+    ///     .finish()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn import_function_stateful<Args, F, T>(
+        self,
+        name: impl ToString,
+        state: T,
+        value: F,
+    ) -> Self
+    where
+        F: ImportableFnWithContext<StatefulContext<T>, Stateful<Args>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let ctx = StatefulContext(Arc::new(Mutex::new(state)));
+        self.import_function_with_context(name, ctx, value)
     }
 
     /// Import a function defined in the host into the guest. The function's
-    /// arguments and return type must all be serializable.
+    /// arguments and return type must all be serializable. Functions may take
+    /// up to eight arguments, which are sent as a tuple to match
+    /// `wasm_plugin_guest::import_functions!`. Each name may only be imported
+    /// once; `finish` fails with `WasmPluginError::DuplicateImport` if a name
+    /// is reused by this or any of the other `import_function` methods.
+    ///
+    /// A function which may fail can return `Result<T, E>` where both `T` and
+    /// `E` are serializable. The plugin declares the import as returning the
+    /// same `Result` and gets the error back as a value it can handle. This
+    /// works with the serde based formats; with the others, or when failing
+    /// should simply abort the plugin's call, use
+    /// `import_function_returning_result`.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?
+    ///     .import_function("zero", || 0u32)
+    ///     .import_function("one", |a: u32| a)
+    ///     .import_function("two", |a: u32, b: String| format!("{}{}", a, b))
+    ///     .import_function("three", |a: i32, b: i32, c: i32| a + b + c)
+    ///     .import_function("four", |a: u8, b: u16, c: u32, d: u64| {
+    ///         a as u64 + b as u64 + c as u64 + d
+    ///     })
+    ///     .import_function("add", |a: i32, b: i32| a + b)
+    ///     .import_function("interpolate", |x: f64, y: f64, t: f64| x + (y - x) * t)
+    ///     .import_function("pair", |(a, b): (u32, u32)| a * b)
+    ///     .import_function("parse", |s: String| s.parse::<u32>().map_err(|e| e.to_string()))
+    ///     .finish()?;
+    /// # Ok(())
+    /// # }
+    /// ```
     pub fn import_function<Args, F: ImportableFn<Args> + Send + 'static>(
         self,
         name: impl ToString,
         value: F,
     ) -> Self {
-        let env = Env::new(self.garbage.clone(), ());
-
-        if F::has_arg() {
-            let f = if F::has_return() {
-                let wrapped = move |env: &Env<()>, ptr: u32, len: u32| -> u64 {
-                    let mut buffer = env.message_buffer();
-                    let r = value
-                        .call_with_input(&mut buffer, ptr as usize, len as usize)
-                        .unwrap()
-                        .map(|p| p.0)
-                        .unwrap_or(0);
-                    env.garbage.lock().unwrap().extend(buffer.garbage.drain(..));
-                    r
-                };
-                Function::new_native_with_env(&self.store, env, wrapped)
-            } else {
-                let wrapped = move |env: &Env<()>, ptr: u32, len: u32| {
-                    let mut buffer = env.message_buffer();
-                    value
-                        .call_with_input(&mut buffer, ptr as usize, len as usize)
-                        .unwrap();
-                    env.garbage.lock().unwrap().extend(buffer.garbage.drain(..));
-                };
-                Function::new_native_with_env(&self.store, env, wrapped)
-            };
-            self.import(name, f)
+        let env = Env::new(self.settings.clone(), self.unfreed.clone(), ());
+        self.import_with_env(
+            name,
+            env,
+            F::has_arg(),
+            F::has_return(),
+            move |_env, buffer, input| match input {
+                Some((ptr, len)) => value.call_with_input(buffer, ptr, len),
+                None => value.call_without_input(buffer),
+            },
+        )
+    }
+
+    /// Import a function defined in the host into the guest which may fail,
+    /// like `Fn(Args) -> Result<ReturnType, E>`. The plugin must import it
+    /// with `wasm_plugin_guest::import_function_with_result!`, declaring
+    /// `ReturnType` as its return type. The error only has to implement
+    /// `Display`; if the function fails the plugin panics with a message
+    /// which includes it.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?
+    ///     .import_function_returning_result("load_texture", |path: String| std::fs::read(path))
+    ///     .import_function_returning_result("now", || {
+    ///         std::time::SystemTime::now()
+    ///             .duration_since(std::time::UNIX_EPOCH)
+    ///             .map(|since| since.as_secs())
+    ///     })
+    ///     .finish()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn import_function_returning_result<Args, F>(self, name: impl ToString, value: F) -> Self
+    where
+        Fallible<F, Args>: ImportableFn<Args>,
+        F: Send + 'static,
+        Args: 'static,
+    {
+        self.import_function(name, Fallible(value, std::marker::PhantomData))
+    }
+
+    /// Import a function defined in the host into the guest which borrows its
+    /// argument, like `Fn(&str)`. The function gets a reference to the owned
+    /// value deserialized from the message, which saves a copy compared to
+    /// taking the owned value and borrowing from it.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?
+    ///     .import_function_by_ref("please_capitalize_this", |s: &str| s.to_uppercase())
+    ///     .finish()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn import_function_by_ref<Arg, F>(self, name: impl ToString, value: F) -> Self
+    where
+        Arg: ?Sized,
+        F: ImportableFn<ByRef<Arg>> + Send + 'static,
+    {
+        self.import_function(name, value)
+    }
+
+    /// Import a function defined in the host into the guest directly, without
+    /// any serialization. Its arguments and return values must be WASM
+    /// scalars like `u32` or `f64`, which makes calling it about as cheap as
+    /// calling a function inside the plugin. This suits callbacks which are
+    /// called very often, like once per pixel.
+    ///
+    /// The function is imported into the `env` namespace under exactly
+    /// `name`, without the `wasm_plugin_imported__` prefix, so the plugin
+    /// declares it in an ordinary `extern "C"` block rather than with
+    /// `wasm_plugin_guest::import_functions`. It must be a `fn` or a closure
+    /// which captures nothing. As with the other imports, `finish` fails with
+    /// `WasmPluginError::DuplicateImport` if `name` is imported twice.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?
+    ///     .import_native_function("blend", |a: u32, b: u32| (a >> 1) + (b >> 1))
+    ///     .finish()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn import_native_function<Args, Rets>(
+        self,
+        name: impl ToString,
+        value: impl HostFunction<Args, Rets, wasmer::internals::WithoutEnv, ()>,
+    ) -> Self
+    where
+        Args: WasmTypeList,
+        Rets: WasmTypeList,
+    {
+        let function = Function::new_native(&self.store, value);
+        self.import_into_env(name.to_string(), function, false)
+    }
+
+    /// Import a function defined in the host into the guest which can call
+    /// back into the plugin while it runs. A `PluginHandle` for the plugin is
+    /// passed to the function as it's first argument each time it's called.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::{PluginHandle, WasmPluginBuilder};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?
+    ///     .import_function_with_plugin("ask_the_plugin", |plugin: &PluginHandle, question: String| {
+    ///         plugin
+    ///             .call_function_with_argument::<String, _>("answer", &question)
+    ///             .unwrap_or_default()
+    ///     })
+    ///     .finish()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Every nested call runs on the host's native stack on top of the call
+    /// that is already in progress and nothing limits how deep the recursion
+    /// can go, so a plugin and host which keep calling each other will
+    /// eventually overflow the stack. Keep the nesting shallow and bounded.
+    ///
+    /// The handle is only valid for the duration of the import. The outer
+    /// call is still holding its own borrow of the `WasmPlugin` so the handle
+    /// only offers calls which take `&self`, and any state the import shares
+    /// with the rest of the host must tolerate being reentered.
+    pub fn import_function_with_plugin<Args, F>(self, name: impl ToString, value: F) -> Self
+    where
+        F: for<'a> ImportableFnWithContext<PluginHandle<'a>, Args> + Send + 'static,
+    {
+        let env = Env::new(self.settings.clone(), self.unfreed.clone(), ());
+        self.import_with_env(
+            name,
+            env,
+            F::has_arg(),
+            F::has_return(),
+            move |env, buffer, input| {
+                let plugin = env.plugin_handle();
+                match input {
+                    Some((ptr, len)) => value.call_with_input(buffer, ptr, len, &plugin),
+                    None => value.call_without_input(buffer, &plugin),
+                }
+            },
+        )
+    }
+
+    /// Import a function defined in the host into the guest which reads the
+    /// context given to the call in progress. The function is passed a
+    /// reference to the `Ctx` given to `WasmPlugin::call_function_with_context`
+    /// as its first argument, which lets request scoped data like a session
+    /// or a deadline reach the host's callbacks without passing through the
+    /// plugin. If the plugin calls the import during a call which wasn't
+    /// given a `Ctx` the call fails with `WasmPluginError::MissingCallContext`.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// struct Session {
+    ///     user: String,
+    /// }
+    ///
+    /// let mut plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?
+    ///     .import_function_with_call_context("current_user", |session: &Session| {
+    ///         session.user.clone()
+    ///     })
+    ///     .finish()?;
+    /// let session = Session {
+    ///     user: "alice".to_string(),
+    /// };
+    /// let greeting: String = plugin.call_function_with_context("greet", &session)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn import_function_with_call_context<Ctx, Args, F>(
+        self,
+        name: impl ToString,
+        value: F,
+    ) -> Self
+    where
+        F: ImportableFnWithContext<Ctx, Args> + Send + 'static,
+        Ctx: 'static,
+    {
+        let env = Env::new(self.settings.clone(), self.unfreed.clone(), ());
+        self.import_with_env(
+            name,
+            env,
+            F::has_arg(),
+            F::has_return(),
+            move |_env, buffer, input| {
+                call_context::current_call_context(|ctx: Option<&Ctx>| {
+                    let ctx = ctx.ok_or_else(|| {
+                        errors::WasmPluginError::MissingCallContext(std::any::type_name::<Ctx>())
+                    })?;
+                    match input {
+                        Some((ptr, len)) => value.call_with_input(buffer, ptr, len, ctx),
+                        None => value.call_without_input(buffer, ctx),
+                    }
+                })
+            },
+        )
+    }
+
+    /// Check that the plugin exports everything needed to communicate with it
+    /// and each of the named `functions`, without instantiating it. All of
+    /// the problems found are reported together in
+    /// `WasmPluginError::ValidationFailed`.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// WasmPluginBuilder::from_file("path/to/plugin.wasm")?.validate(&["update", "render"])?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn validate(&self, functions: &[&str]) -> errors::Result<()> {
+        let mut problems: Vec<String> =
+            missing_runtime_exports(&self.module, &self.settings.read().unwrap())
+                .into_iter()
+                .map(|name| format!("missing runtime export '{}'", name))
+                .collect();
+        for function in functions {
+            let export_name = format!("wasm_plugin_exported__{}", function);
+            if !has_export(&self.module, &export_name, |ty| {
+                matches!(ty, ExternType::Function(_))
+            }) {
+                problems.push(format!("missing function '{}'", function));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
         } else {
-            let f = if F::has_return() {
-                let wrapped = move |env: &Env<()>| -> u64 {
-                    let mut buffer = env.message_buffer();
-                    let r = value
-                        .call_without_input(&mut buffer)
-                        .unwrap()
-                        .map(|p| p.0)
-                        .unwrap_or(0);
-                    env.garbage.lock().unwrap().extend(buffer.garbage.drain(..));
-                    r
-                };
-                Function::new_native_with_env(&self.store, env, wrapped)
-            } else {
-                let wrapped = move |env: &Env<()>| {
-                    let mut buffer = env.message_buffer();
-                    value.call_without_input(&mut buffer).unwrap();
-                    env.garbage.lock().unwrap().extend(buffer.garbage.drain(..));
-                };
-                Function::new_native_with_env(&self.store, env, wrapped)
-            };
-            self.import(name, f)
+            Err(errors::WasmPluginError::ValidationFailed(problems))
         }
     }
 
     /// Finalize the builder and create the WasmPlugin ready for use.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip(self)))]
     pub fn finish(self) -> errors::Result<WasmPlugin> {
-        let mut import_object = wasmer::ImportObject::new();
-        import_object.register("env", self.env);
-        Ok(WasmPlugin {
-            instance: Instance::new(&self.module, &import_object)?,
-            garbage: self.garbage,
-        })
-    }
-}
+        if let Some(name) = self.duplicate_import {
+            return Err(errors::WasmPluginError::DuplicateImport(name));
+        }
+        let init_config = self.init_config.transpose()?;
+        check_resource_limits(&self.module, self.table_limit, self.globals_limit)?;
+        check_runtime_exports(&self.module, &self.settings.read().unwrap())?;
+        let mut namespaces: HashMap<String, Exports> = HashMap::new();
+        #[cfg(feature = "wasi")]
+        if let Some(config) = &self.wasi {
+            for ((namespace, name), export) in config.import_object(&self.module)? {
+                namespaces
+                    .entry(namespace)
+                    .or_default()
+                    .insert(name, Extern::from_vm_export(&self.store, export));
+            }
+        }
+        namespaces.insert("env".to_string(), self.env);
 
-/// A marker trait for Fn types who's arguments and return type can be
-/// serialized and are thus safe to import into a plugin;
+        // Imports from the user's import objects replace the defaults, like
+        // WASI or the abort no-op, but may not replace functions registered
+        // with the builder or each other.
+        let mut provided = HashSet::new();
+        for imports in self.import_objects {
+            for ((namespace, name), export) in imports {
+                let registered = namespace == "env"
+                    && name.starts_with("wasm_plugin_imported__")
+                    && namespaces["env"].contains(name.as_str());
+                if registered || !provided.insert((namespace.clone(), name.clone())) {
+                    return Err(errors::WasmPluginError::DuplicateImport(format!(
+                        "{}.{}",
+                        namespace, name
+                    )));
+                }
+                namespaces
+                    .entry(namespace)
+                    .or_default()
+                    .insert(name, Extern::from_vm_export(&self.store, export));
+            }
+        }
+
+        // Plugins which import their memory get a fresh one if the host
+        // didn't provide it
+        for import in self.module.imports().memories() {
+            let exports = namespaces.entry(import.module().to_string()).or_default();
+            let memory = match exports.get_memory(import.name()) {
+                Ok(memory) => memory.clone(),
+                Err(_) => {
+                    let memory = Memory::new(&self.store, *import.ty())?;
+                    exports.insert(import.name(), memory.clone());
+                    memory
+                }
+            };
+            self.settings
+                .write()
+                .unwrap()
+                .shared_memory
+                .get_or_insert(memory);
+        }
+
+        let mut import_object = ImportObject::new();
+        for (namespace, exports) in namespaces {
+            import_object.register(namespace, exports);
+        }
+        let mut instance = Instance::new(&self.module, &import_object)?;
+        expose_memory(&mut instance.exports, &self.settings.read().unwrap());
+        let scalar_exports = scalar::scalar_exports(&instance.exports);
+        let interrupt_flag = interrupt_flag(&instance);
+        let plugin = WasmPlugin {
+            instance,
+            store: self.store,
+            import_object,
+            settings: self.settings,
+            unfreed: self.unfreed,
+            error_handler: self.error_handler,
+            metrics: self.metrics,
+            name: self.name,
+            init_config,
+            buffer_pool: self
+                .buffer_pool
+                .map(|max_buffers| Arc::new(Mutex::new(BufferPool::new(max_buffers)))),
+            scalar_exports,
+            interrupt_flag,
+        };
+        if self.abi_check {
+            plugin.verify_abi_version()?;
+        }
+        plugin.init()?;
+        Ok(plugin)
+    }
+}
+
+// The flag added by the `interrupt` middleware, if the plugin was compiled
+// with it
+fn interrupt_flag(instance: &Instance) -> Option<wasmer::Global> {
+    instance
+        .exports
+        .get_global(interrupt::INTERRUPT_FLAG)
+        .ok()
+        .cloned()
+}
+
+// Exported functions take a pointer and length if they have an argument and
+// nothing otherwise. Calling one the wrong way is caught here so it can be
+// reported more clearly than Wasmer's signature mismatch.
+fn check_arity(export_name: &str, f: &Function, has_arg: bool) -> errors::Result<()> {
+    let expected = if f.ty().params().is_empty() { 0 } else { 1 };
+    let got = has_arg as usize;
+    if expected != got {
+        return Err(errors::WasmPluginError::ArityMismatch {
+            function: export_name
+                .strip_prefix("wasm_plugin_exported__")
+                .unwrap_or(export_name)
+                .to_string(),
+            expected,
+            got,
+        });
+    }
+    Ok(())
+}
+
+// Check that `source` is a WASM module which uses only the features Wasmer
+// supports by default
+fn verify_source(source: &[u8]) -> errors::Result<()> {
+    if !source.starts_with(b"\0asm") {
+        return Err(errors::WasmPluginError::InvalidWasm(
+            "it does not start with the WASM magic number".to_string(),
+        ));
+    }
+    let features = wasmer::Features::default();
+    let mut validator = wasmparser::Validator::new();
+    validator.wasm_features(wasmparser::WasmFeatures {
+        reference_types: features.reference_types,
+        module_linking: features.module_linking,
+        simd: features.simd,
+        multi_value: features.multi_value,
+        threads: features.threads,
+        tail_call: features.tail_call,
+        bulk_memory: features.bulk_memory,
+        multi_memory: features.multi_memory,
+        memory64: features.memory64,
+        ..Default::default()
+    });
+    validator
+        .validate_all(source)
+        .map_err(|e| errors::WasmPluginError::InvalidWasm(e.to_string()))
+}
+
+fn exported_functions(exports: &Exports) -> Vec<String> {
+    exports
+        .iter()
+        .filter(|(_, export)| matches!(export, Extern::Function(_)))
+        .filter_map(|(name, _)| name.strip_prefix("wasm_plugin_exported__"))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+// Every plugin needs the exports wasm_plugin_guest provides to support passing
+// messages, whether or not it was actually written with wasm_plugin_guest.
+fn check_runtime_exports(module: &Module, settings: &Settings) -> errors::Result<()> {
+    match missing_runtime_exports(module, settings).into_iter().next() {
+        Some(name) => Err(errors::WasmPluginError::MissingRuntimeExport(name)),
+        None => Ok(()),
+    }
+}
+
+fn missing_runtime_exports(module: &Module, settings: &Settings) -> Vec<String> {
+    let mut missing = vec![];
+    // Any memory the plugin exports or imports will do, see `expose_memory`
+    if settings.shared_memory.is_none()
+        && module.exports().memories().next().is_none()
+        && module.imports().memories().next().is_none()
+    {
+        missing.push(settings.memory_export_name.clone());
+    }
+    // A function with the wrong signature is no more use than a missing one
+    let functions: [(&str, &[Type], &[Type]); 2] = [
+        ("allocate_message_buffer", &[Type::I32], &[Type::I32]),
+        ("free_message_buffer", &[Type::I32, Type::I32], &[]),
+    ];
+    for (name, params, results) in &functions {
+        let found = module.exports().any(|export| match export.ty() {
+            ExternType::Function(f) => {
+                export.name() == *name && f.params() == *params && f.results() == *results
+            }
+            _ => false,
+        });
+        if !found {
+            missing.push(name.to_string());
+        }
+    }
+    missing
+}
+
+// Format `bytes`, which start at `address`, like `hexdump -C`
+fn hex_dump(address: usize, bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        dump.push_str(&format!("{:08x} ", address + i * 16));
+        for (j, byte) in line.iter().enumerate() {
+            if j == 8 {
+                dump.push(' ');
+            }
+            dump.push_str(&format!(" {:02x}", byte));
+        }
+        let padding = (16 - line.len()) * 3 + if line.len() <= 8 { 1 } else { 0 };
+        dump.push_str(&" ".repeat(padding));
+        dump.push_str("  |");
+        dump.extend(line.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        dump.push_str("|\n");
+    }
+    dump
+}
+
+// Plugins which import a shared memory don't have to export it again, and
+// some toolchains give the memory they export a different name, so the
+// memory is added to the plugin's exports where the rest of the crate expects
+// to find it. A shared memory takes precedence over the first memory the
+// plugin exports under another name.
+fn expose_memory(exports: &mut Exports, settings: &Settings) {
+    if exports.contains(settings.memory_export_name.as_str()) {
+        return;
+    }
+    let memory = settings.shared_memory.clone().or_else(|| {
+        exports
+            .iter()
+            .memories()
+            .next()
+            .map(|(_, memory)| memory.clone())
+    });
+    if let Some(memory) = memory {
+        exports.insert(settings.memory_export_name.clone(), memory);
+    }
+}
+
+fn has_export(module: &Module, name: &str, is_kind: fn(&ExternType) -> bool) -> bool {
+    module
+        .exports()
+        .any(|export| export.name() == name && is_kind(export.ty()))
+}
+
+// `import_with_env` gives a function which takes an argument the signature
+// `(ptr, len)` and one which doesn't an empty signature, so the plugin can
+// only call it the way it expects to be called
+fn unreachable_without_argument() -> ! {
+    unreachable!("imports which take an argument are always called with one")
+}
+
+fn unreachable_with_argument() -> ! {
+    unreachable!("imports which take no argument are never called with one")
+}
+
+/// Import several host functions into a plugin at once. The functions are
+/// written with the same signatures used in the plugin's
+/// `wasm_plugin_guest::import_functions!` block, plus a body, so the names
+/// can't drift out of sync with the string passed to `import_function`.
+///
+/// ```rust,no_run
+/// # use wasm_plugin_host::{host_functions, WasmPluginBuilder};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let builder = WasmPluginBuilder::from_file("path/to/plugin.wasm")?;
+/// let plugin = host_functions!(builder,
+///     fn the_hosts_favorite_numbers() -> Vec<i32> {
+///         vec![0, 1, 42]
+///     }
+///     fn please_capitalize_this(s: String) -> String {
+///         s.to_uppercase()
+///     }
+/// )
+/// .finish()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Functions with more than one argument receive them as a tuple on the wire
+/// just like a single tuple argument passed to `import_function`.
+#[macro_export]
+macro_rules! host_functions {
+    (@import $builder:ident, $name:ident, (), ($($ret:ty)?), $body:block) => {
+        $builder.import_function(stringify!($name), move || $(-> $ret)? { $body })
+    };
+    (@import $builder:ident, $name:ident, ($arg:ident : $ty:ty), ($($ret:ty)?), $body:block) => {
+        $builder.import_function(stringify!($name), move |$arg: $ty| $(-> $ret)? { $body })
+    };
+    (@import $builder:ident, $name:ident, ($($arg:ident : $ty:ty),+), ($($ret:ty)?), $body:block) => {
+        $builder.import_function(
+            stringify!($name),
+            move |($($arg),+): ($($ty),+)| $(-> $ret)? { $body },
+        )
+    };
+    ($builder:expr, $(fn $name:ident($($args:tt)*) $(-> $ret:ty)? $body:block)*) => {{
+        let builder = $builder;
+        $(
+            let builder = $crate::host_functions!(@import builder, $name, ($($args)*), ($($ret)?), $body);
+        )*
+        builder
+    }};
+}
+
+/// A marker trait for Fn types who's arguments and return type can be
+/// serialized and are thus safe to import into a plugin;
 pub trait ImportableFnWithContext<C, Arglist> {
     #[doc(hidden)]
     fn has_arg() -> bool;
@@ -365,12 +1729,11 @@ where
         len: usize,
         ctx: &C,
     ) -> errors::Result<Option<FatPointer>> {
-        let message = message_buffer.read_message(ptr, len);
-        let result = self(ctx, Args::deserialize(&message)?);
+        let result = self(ctx, message_buffer.read_value(ptr, len)?);
         if std::mem::size_of::<ReturnType>() > 0 {
             // No need to write anything for ZSTs
             let message = result.serialize()?;
-            Ok(Some(message_buffer.write_message(&message)))
+            Ok(Some(message_buffer.write_message(&message)?))
         } else {
             Ok(None)
         }
@@ -381,7 +1744,7 @@ where
         _message_buffer: &mut MessageBuffer,
         _ctx: &C,
     ) -> errors::Result<Option<FatPointer>> {
-        unimplemented!("Requires argument")
+        unreachable_without_argument()
     }
 }
 
@@ -403,7 +1766,7 @@ where
         _len: usize,
         _ctx: &C,
     ) -> errors::Result<Option<FatPointer>> {
-        unimplemented!("Must not supply argument")
+        unreachable_with_argument()
     }
 
     fn call_without_input(
@@ -415,37 +1778,84 @@ where
         if std::mem::size_of::<ReturnType>() > 0 {
             // No need to write anything for ZSTs
             let message = result.serialize()?;
-            Ok(Some(message_buffer.write_message(&message)))
+            Ok(Some(message_buffer.write_message(&message)?))
         } else {
             Ok(None)
         }
     }
 }
 
-/// A marker trait for Fn types who's arguments and return type can be
-/// serialized and are thus safe to import into a plugin;
-pub trait ImportableFn<ArgList> {
-    #[doc(hidden)]
-    fn has_arg() -> bool;
-    #[doc(hidden)]
-    fn has_return() -> bool;
-    #[doc(hidden)]
-    fn call_with_input(
-        &self,
-        message_buffer: &mut MessageBuffer,
-        ptr: usize,
-        len: usize,
-    ) -> errors::Result<Option<FatPointer>>;
-    #[doc(hidden)]
-    fn call_without_input(
-        &self,
-        message_buffer: &mut MessageBuffer,
-    ) -> errors::Result<Option<FatPointer>>;
+// Functions with several arguments, like `Fn(&C, u32, String)`, receive them
+// as a single tuple on the wire, which is how wasm_plugin_guest sends them.
+macro_rules! impl_importable_fn_with_context {
+    ($($arg:ident),+) => {
+        impl<C, $($arg,)+ ReturnType, F> ImportableFnWithContext<C, Multiple<($($arg,)+)>> for F
+        where
+            F: Fn(&C, $($arg),+) -> ReturnType,
+            ($($arg,)+): Deserializable,
+            ReturnType: Serializable,
+        {
+            fn has_arg() -> bool {
+                true
+            }
+            fn has_return() -> bool {
+                std::mem::size_of::<ReturnType>() > 0
+            }
+            #[allow(non_snake_case)]
+            fn call_with_input(
+                &self,
+                message_buffer: &mut MessageBuffer,
+                ptr: usize,
+                len: usize,
+                ctx: &C,
+            ) -> errors::Result<Option<FatPointer>> {
+                let ($($arg,)+) = message_buffer.read_value(ptr, len)?;
+                let result = self(ctx, $($arg),+);
+                if std::mem::size_of::<ReturnType>() > 0 {
+                    // No need to write anything for ZSTs
+                    let message = result.serialize()?;
+                    Ok(Some(message_buffer.write_message(&message)?))
+                } else {
+                    Ok(None)
+                }
+            }
+
+            fn call_without_input(
+                &self,
+                _message_buffer: &mut MessageBuffer,
+                _ctx: &C,
+            ) -> errors::Result<Option<FatPointer>> {
+                unreachable_without_argument()
+            }
+        }
+    };
 }
 
-impl<F, Args, ReturnType> ImportableFn<Args> for F
+impl_importable_fn_with_context!(T1, T2);
+impl_importable_fn_with_context!(T1, T2, T3);
+impl_importable_fn_with_context!(T1, T2, T3, T4);
+impl_importable_fn_with_context!(T1, T2, T3, T4, T5);
+impl_importable_fn_with_context!(T1, T2, T3, T4, T5, T6);
+impl_importable_fn_with_context!(T1, T2, T3, T4, T5, T6, T7);
+impl_importable_fn_with_context!(T1, T2, T3, T4, T5, T6, T7, T8);
+
+#[doc(hidden)]
+pub struct Shared<Args>(std::marker::PhantomData<Args>);
+
+// An import which panicked while it held the context poisons the lock. The
+// panic has already failed the call it happened in, so later calls carry on
+// with the context as the import left it rather than panicking too.
+fn lock_shared_context<C>(ctx: &Mutex<C>) -> std::sync::MutexGuard<'_, C> {
+    ctx.lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+// Functions imported with `import_function_with_shared_context` get a
+// mutable reference to the context, which is locked for the duration of the
+// call.
+impl<C, Args, ReturnType, F> ImportableFnWithContext<Arc<Mutex<C>>, Shared<Args>> for F
 where
-    F: Fn(Args) -> ReturnType,
+    F: Fn(&mut C, Args) -> ReturnType,
     Args: Deserializable,
     ReturnType: Serializable,
 {
@@ -460,14 +1870,15 @@ where
         message_buffer: &mut MessageBuffer,
         ptr: usize,
         len: usize,
+        ctx: &Arc<Mutex<C>>,
     ) -> errors::Result<Option<FatPointer>> {
-        let message = message_buffer.read_message(ptr, len);
-        let result = self(Args::deserialize(&message)?);
+        let args = message_buffer.read_value(ptr, len)?;
+        let result = self(&mut lock_shared_context(ctx), args);
         if std::mem::size_of::<ReturnType>() > 0 {
+            // No need to write anything for ZSTs
             let message = result.serialize()?;
-            Ok(Some(message_buffer.write_message(&message)))
+            Ok(Some(message_buffer.write_message(&message)?))
         } else {
-            // No need to write anything for ZSTs
             Ok(None)
         }
     }
@@ -475,17 +1886,15 @@ where
     fn call_without_input(
         &self,
         _message_buffer: &mut MessageBuffer,
+        _ctx: &Arc<Mutex<C>>,
     ) -> errors::Result<Option<FatPointer>> {
-        unimplemented!("Requires argument")
+        unreachable_without_argument()
     }
 }
 
-#[doc(hidden)]
-pub enum NoArgs {}
-
-impl<F, ReturnType> ImportableFn<NoArgs> for F
+impl<C, ReturnType, F> ImportableFnWithContext<Arc<Mutex<C>>, Shared<NoArgs>> for F
 where
-    F: Fn() -> ReturnType,
+    F: Fn(&mut C) -> ReturnType,
     ReturnType: Serializable,
 {
     fn has_arg() -> bool {
@@ -499,175 +1908,2176 @@ where
         _message_buffer: &mut MessageBuffer,
         _ptr: usize,
         _len: usize,
+        _ctx: &Arc<Mutex<C>>,
     ) -> errors::Result<Option<FatPointer>> {
-        unimplemented!("Must not supply argument")
+        unreachable_with_argument()
     }
 
     fn call_without_input(
         &self,
         message_buffer: &mut MessageBuffer,
+        ctx: &Arc<Mutex<C>>,
     ) -> errors::Result<Option<FatPointer>> {
-        let result = self();
+        let result = self(&mut lock_shared_context(ctx));
         if std::mem::size_of::<ReturnType>() > 0 {
             // No need to write anything for ZSTs
             let message = result.serialize()?;
-            Ok(Some(message_buffer.write_message(&message)))
+            Ok(Some(message_buffer.write_message(&message)?))
         } else {
             Ok(None)
         }
     }
 }
 
-/// A loaded plugin
-#[derive(Clone, Debug)]
-pub struct WasmPlugin {
-    instance: Instance,
-    garbage: Arc<Mutex<Vec<FatPointer>>>,
-}
-
 #[doc(hidden)]
-pub struct MessageBuffer<'a> {
-    memory: &'a Memory,
-    allocator: &'a Function,
-    garbage: Vec<FatPointer>,
-}
-
-impl<'a> MessageBuffer<'a> {
-    fn write_message(&mut self, message: &[u8]) -> FatPointer {
-        let len = message.len() as u32;
-
-        let ptr = self
-            .allocator
-            .native::<u32, u32>()
-            .unwrap()
-            .call(len as u32)
-            .unwrap();
+pub struct Stateful<Args>(std::marker::PhantomData<Args>);
 
-        unsafe {
-            let data = self.memory.data_unchecked_mut();
-            data[ptr as usize..ptr as usize + len as usize].copy_from_slice(&message);
-        }
+/// The state owned by a function imported with
+/// `WasmPluginBuilder::import_function_stateful`.
+#[doc(hidden)]
+pub struct StatefulContext<T>(Arc<Mutex<T>>);
 
-        let mut fat_ptr = FatPointer(0);
-        fat_ptr.set_ptr(ptr);
-        fat_ptr.set_len(len);
-        self.garbage.push(FatPointer(fat_ptr.0));
-        fat_ptr
+impl<T> Clone for StatefulContext<T> {
+    fn clone(&self) -> Self {
+        StatefulContext(self.0.clone())
     }
+}
 
-    fn read_message(&self, ptr: usize, len: usize) -> Vec<u8> {
-        let mut buff: Vec<u8> = vec![0; len];
-        unsafe {
-            let data = self.memory.data_unchecked();
-            buff.copy_from_slice(&data[ptr..ptr + len]);
+impl<T, Args, ReturnType, F> ImportableFnWithContext<StatefulContext<T>, Stateful<Args>> for F
+where
+    F: Fn(&mut T, Args) -> ReturnType,
+    Args: Deserializable,
+    ReturnType: Serializable,
+{
+    fn has_arg() -> bool {
+        true
+    }
+    fn has_return() -> bool {
+        std::mem::size_of::<ReturnType>() > 0
+    }
+    fn call_with_input(
+        &self,
+        message_buffer: &mut MessageBuffer,
+        ptr: usize,
+        len: usize,
+        ctx: &StatefulContext<T>,
+    ) -> errors::Result<Option<FatPointer>> {
+        let args = message_buffer.read_value(ptr, len)?;
+        let result = self(&mut lock_shared_context(&ctx.0), args);
+        if std::mem::size_of::<ReturnType>() > 0 {
+            // No need to write anything for ZSTs
+            let message = result.serialize()?;
+            Ok(Some(message_buffer.write_message(&message)?))
+        } else {
+            Ok(None)
         }
-        buff
     }
 
-    fn read_message_from_fat_pointer(&self, fat_ptr: u64) -> Vec<u8> {
-        unsafe {
-            let data = self.memory.data_unchecked();
-            let fat_ptr = FatPointer(fat_ptr);
-            let mut buff: Vec<u8> = vec![0; fat_ptr.len() as usize];
-            buff.copy_from_slice(
-                &data[fat_ptr.ptr() as usize..fat_ptr.ptr() as usize + fat_ptr.len() as usize],
-            );
-            buff
-        }
+    fn call_without_input(
+        &self,
+        _message_buffer: &mut MessageBuffer,
+        _ctx: &StatefulContext<T>,
+    ) -> errors::Result<Option<FatPointer>> {
+        unreachable_without_argument()
     }
 }
 
-impl WasmPlugin {
-    fn message_buffer(&self) -> errors::Result<MessageBuffer> {
-        Ok(MessageBuffer {
-            memory: self.instance.exports.get_memory("memory")?,
-            allocator: self
-                .instance
-                .exports
-                .get::<Function>("allocate_message_buffer")?,
-            garbage: vec![],
-        })
+impl<T, ReturnType, F> ImportableFnWithContext<StatefulContext<T>, Stateful<NoArgs>> for F
+where
+    F: Fn(&mut T) -> ReturnType,
+    ReturnType: Serializable,
+{
+    fn has_arg() -> bool {
+        false
     }
-
-    /// Call a function exported by the plugin with a single argument
-    /// which will be serialized and sent to the plugin.
-    ///
-    /// Deserialization of the return value depends on the type being known
-    /// at the call site.
-    pub fn call_function_with_argument<ReturnType, Args>(
+    fn has_return() -> bool {
+        std::mem::size_of::<ReturnType>() > 0
+    }
+    fn call_with_input(
         &self,
-        fn_name: &str,
-        args: &Args,
-    ) -> errors::Result<ReturnType>
-    where
-        Args: Serializable,
-        ReturnType: Deserializable,
-    {
-        let message = args.serialize()?;
-        let mut buffer = self.message_buffer()?;
-        let ptr = buffer.write_message(&message);
-
-        let buff = self.call_function_raw(fn_name, Some(ptr))?;
-        drop(buffer);
-        ReturnType::deserialize(&buff)
+        _message_buffer: &mut MessageBuffer,
+        _ptr: usize,
+        _len: usize,
+        _ctx: &StatefulContext<T>,
+    ) -> errors::Result<Option<FatPointer>> {
+        unreachable_with_argument()
     }
 
-    fn call_function_raw(
+    fn call_without_input(
         &self,
-        fn_name: &str,
-        input_buffer: Option<FatPointer>,
-    ) -> errors::Result<Vec<u8>> {
-        let f = self
-            .instance
-            .exports
-            .get_function(&format!("wasm_plugin_exported__{}", fn_name))
-            .unwrap_or_else(|_| panic!("Unable to find function {}", fn_name));
-
-        let ptr = if let Some(fat_ptr) = input_buffer {
-            f.native::<(u32, u32), u64>()?
-                .call(fat_ptr.ptr() as u32, fat_ptr.len() as u32)?
+        message_buffer: &mut MessageBuffer,
+        ctx: &StatefulContext<T>,
+    ) -> errors::Result<Option<FatPointer>> {
+        let result = self(&mut lock_shared_context(&ctx.0));
+        if std::mem::size_of::<ReturnType>() > 0 {
+            // No need to write anything for ZSTs
+            let message = result.serialize()?;
+            Ok(Some(message_buffer.write_message(&message)?))
         } else {
-            f.native::<(), u64>()?.call()?
-        };
-        let result = self.message_buffer()?.read_message_from_fat_pointer(ptr);
+            Ok(None)
+        }
+    }
+}
 
-        let mut garbage: Vec<_> = self.garbage.lock().unwrap().drain(..).collect();
+/// A marker trait for Fn types who's arguments and return type can be
+/// serialized and are thus safe to import into a plugin;
+pub trait ImportableFn<ArgList> {
+    #[doc(hidden)]
+    fn has_arg() -> bool;
+    #[doc(hidden)]
+    fn has_return() -> bool;
+    #[doc(hidden)]
+    fn call_with_input(
+        &self,
+        message_buffer: &mut MessageBuffer,
+        ptr: usize,
+        len: usize,
+    ) -> errors::Result<Option<FatPointer>>;
+    #[doc(hidden)]
+    fn call_without_input(
+        &self,
+        message_buffer: &mut MessageBuffer,
+    ) -> errors::Result<Option<FatPointer>>;
+}
 
-        if FatPointer(ptr).len() > 0 {
-            garbage.push(FatPointer(ptr));
-        }
-        if !garbage.is_empty() {
-            let f = self
-                .instance
-                .exports
-                .get_function("free_message_buffer")
-                .unwrap_or_else(|_| panic!("Unable to find function 'free_message_buffer'"))
-                .native::<(u32, u32), ()>()?;
-            for fat_ptr in garbage {
-                f.call(fat_ptr.ptr() as u32, fat_ptr.len() as u32)?
-            }
+impl<F, Args, ReturnType> ImportableFn<Args> for F
+where
+    F: Fn(Args) -> ReturnType,
+    Args: Deserializable,
+    ReturnType: Serializable,
+{
+    fn has_arg() -> bool {
+        true
+    }
+    fn has_return() -> bool {
+        std::mem::size_of::<ReturnType>() > 0
+    }
+    fn call_with_input(
+        &self,
+        message_buffer: &mut MessageBuffer,
+        ptr: usize,
+        len: usize,
+    ) -> errors::Result<Option<FatPointer>> {
+        let result = self(message_buffer.read_value(ptr, len)?);
+        if std::mem::size_of::<ReturnType>() > 0 {
+            let message = result.serialize()?;
+            Ok(Some(message_buffer.write_message(&message)?))
+        } else {
+            // No need to write anything for ZSTs
+            Ok(None)
         }
-
-        Ok(result)
     }
 
-    /// Call a function exported by the plugin.
+    fn call_without_input(
+        &self,
+        _message_buffer: &mut MessageBuffer,
+    ) -> errors::Result<Option<FatPointer>> {
+        unreachable_without_argument()
+    }
+}
+
+#[doc(hidden)]
+pub enum NoArgs {}
+
+// Fallible functions are wrapped rather than distinguished by a marker so
+// that `import_function` can still take closures which return a `Result` as
+// an ordinary value.
+#[doc(hidden)]
+pub struct Fallible<F, Args>(F, std::marker::PhantomData<fn(Args)>);
+
+// Fallible functions send a byte saying whether they succeeded followed by
+// the value they returned or a description of the error. This must match
+// wasm_plugin_guest's `read_result_message`.
+fn tag_result<ReturnType, E>(result: Result<ReturnType, E>) -> errors::Result<Vec<u8>>
+where
+    ReturnType: Serializable,
+    E: std::fmt::Display,
+{
+    Ok(match result {
+        Ok(value) => {
+            let mut message = vec![0];
+            // Like other returns, nothing is written for ZSTs
+            if std::mem::size_of::<ReturnType>() > 0 {
+                message.extend(value.serialize()?);
+            }
+            message
+        }
+        Err(e) => {
+            let mut message = vec![1];
+            message.extend(e.to_string().into_bytes());
+            message
+        }
+    })
+}
+
+impl<F, Args, ReturnType, E> ImportableFn<Args> for Fallible<F, Args>
+where
+    F: Fn(Args) -> Result<ReturnType, E>,
+    Args: Deserializable,
+    ReturnType: Serializable,
+    E: std::fmt::Display,
+{
+    fn has_arg() -> bool {
+        true
+    }
+    fn has_return() -> bool {
+        true
+    }
+    fn call_with_input(
+        &self,
+        message_buffer: &mut MessageBuffer,
+        ptr: usize,
+        len: usize,
+    ) -> errors::Result<Option<FatPointer>> {
+        let message = tag_result((self.0)(message_buffer.read_value(ptr, len)?))?;
+        Ok(Some(message_buffer.write_message(&message)?))
+    }
+
+    fn call_without_input(
+        &self,
+        _message_buffer: &mut MessageBuffer,
+    ) -> errors::Result<Option<FatPointer>> {
+        unreachable_without_argument()
+    }
+}
+
+impl<F, ReturnType, E> ImportableFn<NoArgs> for Fallible<F, NoArgs>
+where
+    F: Fn() -> Result<ReturnType, E>,
+    ReturnType: Serializable,
+    E: std::fmt::Display,
+{
+    fn has_arg() -> bool {
+        false
+    }
+    fn has_return() -> bool {
+        true
+    }
+    fn call_with_input(
+        &self,
+        _message_buffer: &mut MessageBuffer,
+        _ptr: usize,
+        _len: usize,
+    ) -> errors::Result<Option<FatPointer>> {
+        unreachable_with_argument()
+    }
+
+    fn call_without_input(
+        &self,
+        message_buffer: &mut MessageBuffer,
+    ) -> errors::Result<Option<FatPointer>> {
+        let message = tag_result((self.0)())?;
+        Ok(Some(message_buffer.write_message(&message)?))
+    }
+}
+
+#[doc(hidden)]
+pub struct ByRef<T: ?Sized>(std::marker::PhantomData<T>);
+
+// Functions which borrow their argument, like `Fn(&str)`, get a reference to
+// an owned value deserialized from the message.
+impl<F, Arg, ReturnType> ImportableFn<ByRef<Arg>> for F
+where
+    F: Fn(&Arg) -> ReturnType,
+    Arg: ToOwned + ?Sized,
+    Arg::Owned: Deserializable,
+    ReturnType: Serializable,
+{
+    fn has_arg() -> bool {
+        true
+    }
+    fn has_return() -> bool {
+        std::mem::size_of::<ReturnType>() > 0
+    }
+    fn call_with_input(
+        &self,
+        message_buffer: &mut MessageBuffer,
+        ptr: usize,
+        len: usize,
+    ) -> errors::Result<Option<FatPointer>> {
+        let arg: Arg::Owned = message_buffer.read_value(ptr, len)?;
+        let result = self(arg.borrow());
+        if std::mem::size_of::<ReturnType>() > 0 {
+            let message = result.serialize()?;
+            Ok(Some(message_buffer.write_message(&message)?))
+        } else {
+            // No need to write anything for ZSTs
+            Ok(None)
+        }
+    }
+
+    fn call_without_input(
+        &self,
+        _message_buffer: &mut MessageBuffer,
+    ) -> errors::Result<Option<FatPointer>> {
+        unreachable_without_argument()
+    }
+}
+
+impl<F, ReturnType> ImportableFn<NoArgs> for F
+where
+    F: Fn() -> ReturnType,
+    ReturnType: Serializable,
+{
+    fn has_arg() -> bool {
+        false
+    }
+    fn has_return() -> bool {
+        std::mem::size_of::<ReturnType>() > 0
+    }
+    fn call_with_input(
+        &self,
+        _message_buffer: &mut MessageBuffer,
+        _ptr: usize,
+        _len: usize,
+    ) -> errors::Result<Option<FatPointer>> {
+        unreachable_with_argument()
+    }
+
+    fn call_without_input(
+        &self,
+        message_buffer: &mut MessageBuffer,
+    ) -> errors::Result<Option<FatPointer>> {
+        let result = self();
+        if std::mem::size_of::<ReturnType>() > 0 {
+            // No need to write anything for ZSTs
+            let message = result.serialize()?;
+            Ok(Some(message_buffer.write_message(&message)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[doc(hidden)]
+pub struct Multiple<Args>(std::marker::PhantomData<Args>);
+
+// Functions with several arguments, like `Fn(u32, String)`, receive them as a
+// single tuple on the wire, which is how wasm_plugin_guest sends them.
+macro_rules! impl_importable_fn {
+    ($($arg:ident),+) => {
+        impl<F, $($arg,)+ ReturnType> ImportableFn<Multiple<($($arg,)+)>> for F
+        where
+            F: Fn($($arg),+) -> ReturnType,
+            ($($arg,)+): Deserializable,
+            ReturnType: Serializable,
+        {
+            fn has_arg() -> bool {
+                true
+            }
+            fn has_return() -> bool {
+                std::mem::size_of::<ReturnType>() > 0
+            }
+            #[allow(non_snake_case)]
+            fn call_with_input(
+                &self,
+                message_buffer: &mut MessageBuffer,
+                ptr: usize,
+                len: usize,
+            ) -> errors::Result<Option<FatPointer>> {
+                let ($($arg,)+) = message_buffer.read_value(ptr, len)?;
+                let result = self($($arg),+);
+                if std::mem::size_of::<ReturnType>() > 0 {
+                    let message = result.serialize()?;
+                    Ok(Some(message_buffer.write_message(&message)?))
+                } else {
+                    // No need to write anything for ZSTs
+                    Ok(None)
+                }
+            }
+
+            fn call_without_input(
+                &self,
+                _message_buffer: &mut MessageBuffer,
+            ) -> errors::Result<Option<FatPointer>> {
+                unreachable_without_argument()
+            }
+        }
+    };
+}
+
+impl_importable_fn!(T1, T2);
+impl_importable_fn!(T1, T2, T3);
+impl_importable_fn!(T1, T2, T3, T4);
+impl_importable_fn!(T1, T2, T3, T4, T5);
+impl_importable_fn!(T1, T2, T3, T4, T5, T6);
+impl_importable_fn!(T1, T2, T3, T4, T5, T6, T7);
+impl_importable_fn!(T1, T2, T3, T4, T5, T6, T7, T8);
+
+/// A loaded plugin
+#[derive(Clone, Debug)]
+pub struct WasmPlugin {
+    instance: Instance,
+    store: Store,
+    import_object: ImportObject,
+    settings: Arc<RwLock<Settings>>,
+    // Buffers which couldn't be freed at the end of the call which wrote
+    // them, see `WasmPlugin::collect_garbage`
+    unfreed: Arc<Mutex<Vec<FatPointer>>>,
+    error_handler: Option<ErrorHandler>,
+    metrics: Option<Metrics>,
+    name: Option<String>,
+    init_config: Option<Vec<u8>>,
+    buffer_pool: Option<Arc<Mutex<BufferPool>>>,
+    // Functions exported with `#[export_function(scalar)]`, keyed by their
+    // regular export's name
+    scalar_exports: HashMap<String, scalar::ScalarExport>,
+    // The flag which interrupts the plugin, if it was compiled with one
+    interrupt_flag: Option<wasmer::Global>,
+}
+
+impl std::fmt::Display for WasmPlugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WasmPlugin ")?;
+        if let Some(name) = &self.name {
+            write!(f, "{:?} ", name)?;
+        }
+        write!(f, "{{ functions: {}", self.exported_functions().len())?;
+        if let Ok(memory) = self.memory() {
+            write!(
+                f,
+                ", memory: {} pages ({} KiB)",
+                memory.size().0,
+                memory.data_size() / 1024
+            )?;
+        }
+        write!(f, " }}")
+    }
+}
+
+#[derive(Clone)]
+struct ErrorHandler(Arc<dyn Fn(&errors::WasmPluginError) + Send + Sync>);
+
+impl std::fmt::Debug for ErrorHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ErrorHandler")
+    }
+}
+
+#[derive(Clone)]
+struct Metrics(Arc<dyn PluginMetrics>);
+
+impl std::fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Metrics")
+    }
+}
+
+#[doc(hidden)]
+pub struct MessageBuffer<'a> {
+    memory: &'a Memory,
+    allocator: &'a Function,
+    garbage: Vec<FatPointer>,
+    max_message_size: usize,
+    deserialization_limit: u64,
+    compression: Compression,
+    compression_threshold: usize,
+}
+
+impl<'a> MessageBuffer<'a> {
+    fn new(memory: &'a Memory, allocator: &'a Function, settings: &Settings) -> Self {
+        Self {
+            memory,
+            allocator,
+            garbage: vec![],
+            max_message_size: settings.max_message_size,
+            deserialization_limit: settings.deserialization_limit,
+            compression: settings.compression,
+            compression_threshold: settings.compression_threshold,
+        }
+    }
+
+    fn check_size(&self, size: usize) -> errors::Result<()> {
+        if size > self.max_message_size {
+            Err(errors::WasmPluginError::MessageTooLarge {
+                size,
+                limit: self.max_message_size,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn write_message(&mut self, message: &[u8]) -> errors::Result<FatPointer> {
+        self.check_size(message.len())?;
+        let message = compression::compress(self.compression, self.compression_threshold, message)?;
+        let len = message.len() as u32;
+
+        let ptr = self.allocator.native::<u32, u32>()?.call(len)?;
+        self.copy_into(ptr, &message);
+
+        let mut fat_ptr = FatPointer(0);
+        fat_ptr.set_ptr(ptr);
+        fat_ptr.set_len(len);
+        self.garbage.push(FatPointer(fat_ptr.0));
+        Ok(fat_ptr)
+    }
+
+    // Write a message into a buffer from `pool`, allocating a new one if none
+    // are big enough. Returns the message and the buffer, which should be
+    // given back to the pool once the plugin is done with the message.
+    fn write_pooled_message(
+        &mut self,
+        message: &[u8],
+        pool: &Mutex<BufferPool>,
+    ) -> errors::Result<(FatPointer, FatPointer)> {
+        self.check_size(message.len())?;
+        let message = compression::compress(self.compression, self.compression_threshold, message)?;
+        let len = message.len() as u32;
+
+        let pooled = pool.lock().unwrap().take(len);
+        let buffer = match pooled {
+            Some(buffer) => buffer,
+            None => {
+                let capacity = BufferPool::capacity_for(len);
+                let ptr = self.allocator.native::<u32, u32>()?.call(capacity)?;
+                let mut buffer = FatPointer(0);
+                buffer.set_ptr(ptr);
+                buffer.set_len(capacity);
+                buffer
+            }
+        };
+        self.copy_into(buffer.ptr(), &message);
+
+        let mut fat_ptr = FatPointer(0);
+        fat_ptr.set_ptr(buffer.ptr());
+        fat_ptr.set_len(len);
+        Ok((fat_ptr, buffer))
+    }
+
+    // Copy `data` into a new buffer as is, without compressing it. The
+    // buffer is not garbage, its owner must free it.
+    fn stage(&mut self, data: &[u8]) -> errors::Result<FatPointer> {
+        self.check_size(data.len())?;
+        let len = data.len() as u32;
+        let ptr = self.allocator.native::<u32, u32>()?.call(len)?;
+        self.copy_into(ptr, data);
+
+        let mut fat_ptr = FatPointer(0);
+        fat_ptr.set_ptr(ptr);
+        fat_ptr.set_len(len);
+        Ok(fat_ptr)
+    }
+
+    fn copy_into(&self, ptr: u32, message: &[u8]) {
+        unsafe {
+            let data = self.memory.data_unchecked_mut();
+            data[ptr as usize..ptr as usize + message.len()].copy_from_slice(message);
+        }
+    }
+
+    fn read_message(&self, ptr: usize, len: usize) -> errors::Result<Vec<u8>> {
+        self.check_size(len)?;
+        let mut buff: Vec<u8> = vec![0; len];
+        unsafe {
+            let data = self.memory.data_unchecked();
+            buff.copy_from_slice(&data[ptr..ptr + len]);
+        }
+        compression::decompress(buff, self.max_message_size)
+    }
+
+    fn read_value<T: Deserializable>(&self, ptr: usize, len: usize) -> errors::Result<T> {
+        let message = self.read_message(ptr, len)?;
+        T::deserialize_with_limit(&message, self.deserialization_limit)
+    }
+
+    fn read_message_from_fat_pointer(&self, fat_ptr: u64) -> errors::Result<Vec<u8>> {
+        let fat_ptr = FatPointer(fat_ptr);
+        self.read_message(fat_ptr.ptr() as usize, fat_ptr.len() as usize)
+    }
+}
+
+/// A handle to a plugin which is in the middle of a call, passed to functions
+/// imported with `WasmPluginBuilder::import_function_with_plugin`. It can be
+/// used to call back into the plugin's exported functions.
+pub struct PluginHandle<'a> {
+    exports: &'a Exports,
+    settings: &'a RwLock<Settings>,
+    unfreed: &'a Mutex<Vec<FatPointer>>,
+}
+
+impl<'a> PluginHandle<'a> {
+    fn message_buffer(&self) -> errors::Result<MessageBuffer<'a>> {
+        Ok(MessageBuffer::new(
+            self.memory()?,
+            self.exports.get::<Function>("allocate_message_buffer")?,
+            &self.settings.read().unwrap(),
+        ))
+    }
+
+    fn deserialization_limit(&self) -> u64 {
+        self.settings.read().unwrap().deserialization_limit
+    }
+
+    fn memory(&self) -> errors::Result<&'a Memory> {
+        let settings = self.settings.read().unwrap();
+        Ok(self.exports.get_memory(&settings.memory_export_name)?)
+    }
+
+    /// Call a function exported by the plugin with a single argument
+    /// which will be serialized and sent to the plugin.
+    pub fn call_function_with_argument<ReturnType, Args>(
+        &self,
+        fn_name: &str,
+        args: &Args,
+    ) -> errors::Result<ReturnType>
+    where
+        Args: Serializable,
+        ReturnType: Deserializable,
+    {
+        let message = args.serialize()?;
+        let argument = self.write_argument(&message)?;
+        let buff = self.call_export_raw(
+            &format!("wasm_plugin_exported__{}", fn_name),
+            Some(argument.pointer()),
+        )?;
+        serialization::deserialize_return(fn_name, &buff, self.deserialization_limit())
+    }
+
+    /// Call a function exported by the plugin.
+    pub fn call_function<ReturnType>(&self, fn_name: &str) -> errors::Result<ReturnType>
+    where
+        ReturnType: Deserializable,
+    {
+        let buff = self.call_export_raw(&format!("wasm_plugin_exported__{}", fn_name), None)?;
+        serialization::deserialize_return(fn_name, &buff, self.deserialization_limit())
+    }
+
+    fn call_export_raw(
+        &self,
+        export_name: &str,
+        input_buffer: Option<FatPointer>,
+    ) -> errors::Result<Vec<u8>> {
+        let f = self.export_function(export_name)?;
+        self.call_raw(export_name, f, input_buffer)
+    }
+
+    // Call `f`, which must have the signature of a function exported with
+    // wasm_plugin_guest, and read its response. `name` identifies it in
+    // errors.
+    fn call_raw(
+        &self,
+        name: &str,
+        f: &Function,
+        input_buffer: Option<FatPointer>,
+    ) -> errors::Result<Vec<u8>> {
+        check_arity(name, f, input_buffer.is_some())?;
+
+        // Imports called during this call hand their buffers to it rather
+        // than to any outer call in progress
+        let frame = GarbageFrame::enter(self);
+
+        let ptr = (|| -> errors::Result<u64> {
+            Ok(if let Some(fat_ptr) = input_buffer {
+                f.native::<(u32, u32), u64>()?
+                    .call(fat_ptr.ptr(), fat_ptr.len())?
+            } else {
+                f.native::<(), u64>()?.call()?
+            })
+        })();
+        let result = ptr.and_then(|ptr| {
+            if ptr == 0 {
+                return Err(self.last_guest_error());
+            }
+            // The message is copied out of the plugin's memory before its
+            // buffer is freed below
+            let result = self.message_buffer()?.read_message_from_fat_pointer(ptr);
+            if FatPointer(ptr).len() > 0 {
+                garbage::collect(Some(FatPointer(ptr)), self.unfreed);
+            }
+            result
+        });
+
+        frame.exit()?;
+
+        // The buffers are freed even if the call failed or the result was
+        // rejected so a plugin sending oversized messages doesn't also leak
+        // memory
+        result
+    }
+
+    // Make a call which doesn't go through a message buffer, collecting the
+    // buffers of any imports it calls like other calls do
+    fn call_native<T>(&self, call: impl FnOnce() -> errors::Result<T>) -> errors::Result<T> {
+        let frame = GarbageFrame::enter(self);
+        let result = call();
+        frame.exit()?;
+        result
+    }
+
+    // Call a function exported with `#[export_function(scalar)]` and
+    // serialize the number it returns, as its regular export `name` would have
+    fn call_scalar_export(
+        &self,
+        name: &str,
+        scalar: &scalar::ScalarExport,
+        input_buffer: Option<FatPointer>,
+    ) -> errors::Result<Vec<u8>> {
+        let f = self.export_function(&scalar.export_name)?;
+        check_arity(name, f, input_buffer.is_some())?;
+
+        let frame = GarbageFrame::enter(self);
+        let result = scalar.kind.call(f, input_buffer).map_err(|e| {
+            // The plugin traps if it can't read the argument, after recording
+            // why like any other export would
+            match self.last_guest_error() {
+                errors::WasmPluginError::GuestMessageError(message) if !message.is_empty() => {
+                    errors::WasmPluginError::GuestMessageError(message)
+                }
+                _ => e,
+            }
+        });
+        frame.exit()?;
+        result
+    }
+
+    // Call an export which takes an argument once for each of `args`. The
+    // function is resolved and the buffers are freed only once for the whole
+    // batch, and the buffers holding the arguments are freed too.
+    // Call `export_name` once for each of `args`, passing the start time,
+    // bytes sent and bytes received or error of each call to `record`. A
+    // batch which can't be started at all is recorded as one failed call.
+    fn call_export_batch<Args: Serializable>(
+        &self,
+        export_name: &str,
+        args: &[Args],
+        mut record: impl FnMut(std::time::Instant, usize, Result<usize, &errors::WasmPluginError>),
+    ) -> errors::Result<Vec<Vec<u8>>> {
+        let start = std::time::Instant::now();
+        let setup = (|| {
+            let f = self.export_function(export_name)?;
+            check_arity(export_name, f, true)?;
+            Ok((f.native::<(u32, u32), u64>()?, self.message_buffer()?))
+        })();
+        let (f, mut buffer) = match setup {
+            Ok(setup) => setup,
+            Err(e) => {
+                record(start, 0, Err(&e));
+                return Err(e);
+            }
+        };
+        let frame = GarbageFrame::enter(self);
+
+        let results = (|| {
+            let mut results = Vec::with_capacity(args.len());
+            for arg in args {
+                let start = std::time::Instant::now();
+                let mut payload_in = 0;
+                let result = (|| -> errors::Result<Vec<u8>> {
+                    let input = buffer.write_message(&arg.serialize()?)?;
+                    payload_in = input.len() as usize;
+                    let ptr = f.call(input.ptr(), input.len())?;
+                    if ptr == 0 {
+                        return Err(self.last_guest_error());
+                    }
+                    let message = buffer.read_message_from_fat_pointer(ptr)?;
+                    if FatPointer(ptr).len() > 0 {
+                        buffer.garbage.push(FatPointer(ptr));
+                    }
+                    Ok(message)
+                })();
+                record(start, payload_in, result.as_ref().map(Vec::len));
+                results.push(result?);
+            }
+            Ok(results)
+        })();
+
+        garbage::collect(buffer.garbage.drain(..), self.unfreed);
+        frame.exit()?;
+        results
+    }
+
+    fn export_function(&self, export_name: &str) -> errors::Result<&'a Function> {
+        self.exports.get_function(export_name).map_err(|_| {
+            errors::FunctionNotFound {
+                function: export_name
+                    .strip_prefix("wasm_plugin_exported__")
+                    .unwrap_or(export_name)
+                    .to_string(),
+                export_name: export_name.to_string(),
+                plugin: None,
+                available: exported_functions(self.exports),
+            }
+            .into()
+        })
+    }
+
+    // Write an argument for a call into the plugin's memory. It's freed when
+    // the guard is dropped.
+    fn write_argument(&self, message: &[u8]) -> errors::Result<MessageBufferGuard<'a>> {
+        let mut buffer = self.message_buffer()?;
+        let ptr = buffer.write_message(message)?;
+        Ok(MessageBufferGuard {
+            handle: PluginHandle {
+                exports: self.exports,
+                settings: self.settings,
+                unfreed: self.unfreed,
+            },
+            buffer: ptr,
+        })
+    }
+
+    // Free `buffers`, stopping at the first failure. The buffers after the
+    // one which couldn't be freed are kept for `WasmPlugin::collect_garbage`.
+    fn free_buffers(&self, buffers: Vec<FatPointer>) -> errors::Result<()> {
+        if buffers.is_empty() {
+            return Ok(());
+        }
+        let mut buffers = buffers.into_iter();
+        let result = (|| {
+            let f = self
+                .exports
+                .get_native_function::<(u32, u32), ()>("free_message_buffer")
+                .map_err(|_| {
+                    errors::WasmPluginError::MissingRuntimeExport("free_message_buffer".to_string())
+                })?;
+            for fat_ptr in &mut buffers {
+                f.call(fat_ptr.ptr(), fat_ptr.len())?
+            }
+            Ok(())
+        })();
+        if result.is_err() {
+            self.unfreed.lock().unwrap().extend(buffers);
+        }
+        result
+    }
+}
+
+// Collects the buffers written into the plugin's memory during a call and
+// frees them when the call exits. If the call is cut short by a panic they are
+// freed when the frame is dropped instead.
+struct GarbageFrame<'h, 'a> {
+    handle: &'h PluginHandle<'a>,
+    exited: bool,
+}
+
+impl<'h, 'a> GarbageFrame<'h, 'a> {
+    fn enter(handle: &'h PluginHandle<'a>) -> Self {
+        garbage::enter();
+        Self {
+            handle,
+            exited: false,
+        }
+    }
+
+    fn exit(mut self) -> errors::Result<()> {
+        self.exited = true;
+        self.handle.free_buffers(garbage::exit())
+    }
+}
+
+impl<'h, 'a> Drop for GarbageFrame<'h, 'a> {
+    fn drop(&mut self) {
+        if !self.exited {
+            // The panic which cut the call short is more interesting than any
+            // error freeing the buffers
+            let _ = self.handle.free_buffers(garbage::exit());
+        }
+    }
+}
+
+// A buffer the host wrote into the plugin's memory which is freed when the
+// guard is dropped, however the call using it ends.
+struct MessageBufferGuard<'a> {
+    handle: PluginHandle<'a>,
+    buffer: FatPointer,
+}
+
+impl<'a> MessageBufferGuard<'a> {
+    fn pointer(&self) -> FatPointer {
+        FatPointer(self.buffer.0)
+    }
+}
+
+impl<'a> Drop for MessageBufferGuard<'a> {
+    fn drop(&mut self) {
+        // If freeing fails the plugin is already broken and the error it
+        // caused has been reported
+        let _ = self.handle.free_buffers(vec![FatPointer(self.buffer.0)]);
+    }
+}
+
+impl<'a> PluginHandle<'a> {
+    // An exported function returns a null fat pointer when wasm_plugin_guest
+    // couldn't read its argument or write its return value. The details are
+    // kept by the plugin until they are asked for.
+    fn last_guest_error(&self) -> errors::WasmPluginError {
+        let error = (|| -> errors::Result<String> {
+            let ptr = self
+                .exports
+                .get_native_function::<(), u64>("wasm_plugin_last_error")?
+                .call()?;
+            let fat_ptr = FatPointer(ptr);
+            let mut buffer = vec![0; fat_ptr.len() as usize];
+            let data = unsafe { self.memory()?.data_unchecked() };
+            buffer.copy_from_slice(
+                &data[fat_ptr.ptr() as usize..(fat_ptr.ptr() + fat_ptr.len()) as usize],
+            );
+            if fat_ptr.len() > 0 {
+                garbage::collect(Some(fat_ptr), self.unfreed);
+            }
+            Ok(String::from_utf8_lossy(&buffer).into_owned())
+        })();
+        match error {
+            Ok(error) => errors::WasmPluginError::GuestMessageError(error),
+            Err(e) => e,
+        }
+    }
+}
+
+impl WasmPlugin {
+    /// Check that the plugin was built with a wasm_plugin_guest which speaks
+    /// the same protocol as this crate, by comparing the `ABI_VERSION` the
+    /// plugin exports with this crate's. Mismatched versions can otherwise
+    /// fail in confusing ways, like messages which can't be deserialized.
+    ///
+    /// Fails with `WasmPluginError::AbiMismatch` if the versions differ and
+    /// with `WasmPluginError::MissingRuntimeExport` if the plugin was built
+    /// with a version of wasm_plugin_guest which predates the check.
+    pub fn verify_abi_version(&self) -> errors::Result<()> {
+        self.report((|| {
+            let version = self
+                .instance
+                .exports
+                .get_native_function::<(), u32>("wasm_plugin_abi_version")
+                .map_err(|_| {
+                    errors::WasmPluginError::MissingRuntimeExport(
+                        "wasm_plugin_abi_version".to_string(),
+                    )
+                })?;
+            let guest = version.call()?;
+            if guest != ABI_VERSION {
+                return Err(errors::WasmPluginError::AbiMismatch {
+                    guest,
+                    host: ABI_VERSION,
+                });
+            }
+            Ok(())
+        })())
+    }
+
+    // Send the init config to the plugin's init function, if it has both
+    fn init(&self) -> errors::Result<()> {
+        match &self.init_config {
+            Some(config) if self.has_function("init") => {
+                self.call_function_with_message("init", Some(config))?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn deserialization_limit(&self) -> u64 {
+        self.settings.read().unwrap().deserialization_limit
+    }
+
+    fn has_function(&self, fn_name: &str) -> bool {
+        self.instance
+            .exports
+            .get_function(&format!("wasm_plugin_exported__{}", fn_name))
+            .is_ok()
+    }
+
+    fn handle(&self) -> PluginHandle<'_> {
+        PluginHandle {
+            exports: &self.instance.exports,
+            settings: &self.settings,
+            unfreed: &self.unfreed,
+        }
+    }
+
+    fn message_buffer(&self) -> errors::Result<MessageBuffer<'_>> {
+        self.handle().message_buffer()
+    }
+
+    // Write an argument into the plugin's memory and make a call with it,
+    // using the buffer pool if there is one
+    fn with_argument<T>(
+        &self,
+        message: &[u8],
+        call: impl FnOnce(FatPointer) -> errors::Result<T>,
+    ) -> errors::Result<T> {
+        match &self.buffer_pool {
+            Some(pool) => {
+                let (ptr, pooled) = self.message_buffer()?.write_pooled_message(message, pool)?;
+                let result = call(ptr);
+                let evicted = pool.lock().unwrap().give_back(pooled);
+                self.handle().free_buffers(evicted.into_iter().collect())?;
+                result
+            }
+            None => {
+                let argument = self.handle().write_argument(message)?;
+                call(argument.pointer())
+            }
+        }
+    }
+
+    /// Call a function exported by the plugin with a single argument
+    /// which will be serialized and sent to the plugin.
+    ///
+    /// Deserialization of the return value depends on the type being known
+    /// at the call site.
+    pub fn call_function_with_argument<ReturnType, Args>(
+        &self,
+        fn_name: &str,
+        args: &Args,
+    ) -> errors::Result<ReturnType>
+    where
+        Args: Serializable,
+        ReturnType: Deserializable,
+    {
+        let message = args.serialize()?;
+        let buff =
+            self.with_argument(&message, |ptr| self.call_function_raw(fn_name, Some(ptr)))?;
+        serialization::deserialize_return(fn_name, &buff, self.deserialization_limit())
+    }
+
+    /// Call a function exported by the plugin with a single argument which is
+    /// taken by value. This is otherwise identical to
+    /// `call_function_with_argument` and is convenient when the argument is
+    /// constructed at the call site.
+    pub fn call_function_with_owned_argument<ReturnType, Args>(
+        &self,
+        fn_name: &str,
+        args: Args,
+    ) -> errors::Result<ReturnType>
+    where
+        Args: Serializable,
+        ReturnType: Deserializable,
+    {
+        self.call_function_with_argument(fn_name, &args)
+    }
+
+    /// Call a function exported by the plugin with an argument which is
+    /// already serialized in the plugin's wire format, for example because
+    /// it was received from elsewhere and is being forwarded. `raw` is sent
+    /// to the plugin as is, without going through `Serializable`.
+    pub fn call_function_with_raw_serialized<ReturnType>(
+        &mut self,
+        fn_name: &str,
+        raw: Vec<u8>,
+    ) -> errors::Result<ReturnType>
+    where
+        ReturnType: Deserializable,
+    {
+        let buff = self.call_function_with_message(fn_name, Some(&raw))?;
+        serialization::deserialize_return(fn_name, &buff, self.deserialization_limit())
+    }
+
+    /// Call a function exported by the plugin which returns a
+    /// `wasm_plugin_guest::ZeroCopy<T>` and access the archived `T` without
+    /// deserializing it. This avoids the cost of parsing large values when
+    /// only parts of them are needed. The plugin must be built with
+    /// wasm_plugin_guest's `serialize_rkyv` feature.
+    ///
+    /// The archive is validated before it's returned, see `ZeroCopy`.
+    #[cfg(feature = "serialize_rkyv")]
+    pub fn call_function_zero_copy<T>(&mut self, fn_name: &str) -> errors::Result<ZeroCopy<T>>
+    where
+        T: rkyv::Archive,
+        T::Archived: for<'a> rkyv::bytecheck::CheckBytes<
+            rkyv::api::high::HighValidator<'a, rkyv::rancor::Error>,
+        >,
+    {
+        let buff = self.call_function_with_message(fn_name, None)?;
+        self.report(ZeroCopy::new(&buff))
+    }
+
+    /// Call a function exported by the plugin with a single argument, which
+    /// is serialized as usual, and access the archived value it returns
+    /// without deserializing it. See `call_function_zero_copy`.
+    #[cfg(feature = "serialize_rkyv")]
+    pub fn call_function_with_argument_zero_copy<T, Args>(
+        &mut self,
+        fn_name: &str,
+        args: &Args,
+    ) -> errors::Result<ZeroCopy<T>>
+    where
+        T: rkyv::Archive,
+        T::Archived: for<'a> rkyv::bytecheck::CheckBytes<
+            rkyv::api::high::HighValidator<'a, rkyv::rancor::Error>,
+        >,
+        Args: Serializable,
+    {
+        let message = args.serialize()?;
+        let buff = self.call_function_with_message(fn_name, Some(&message))?;
+        self.report(ZeroCopy::new(&buff))
+    }
+
+    /// Call a function exported by the plugin with arguments which are each
+    /// already serialized separately, for example because they are being
+    /// forwarded from elsewhere. The function must be exported with
+    /// `#[export_function(argument_list)]`, which expects each argument
+    /// framed by its length as a little endian `u32` rather than the usual
+    /// single tuple.
+    pub fn call_function_with_argument_list<ReturnType>(
+        &mut self,
+        fn_name: &str,
+        args: Vec<Vec<u8>>,
+    ) -> errors::Result<ReturnType>
+    where
+        ReturnType: Deserializable,
+    {
+        let message = serialization::frame_arguments(&args)?;
+        let buff = self.call_function_with_message(fn_name, Some(&message))?;
+        serialization::deserialize_return(fn_name, &buff, self.deserialization_limit())
+    }
+
+    /// Copy `data` into the plugin's memory once so that it can be passed to
+    /// many calls, in whole or in part, with
+    /// `call_function_with_staged_buffer` rather than being sent again for
+    /// every call. The buffer is freed when the returned handle is dropped.
+    ///
+    /// The data is not serialized or compressed, so each range of it that is
+    /// passed to a call must already be a message in the plugin's wire
+    /// format. For example a batch of inputs can be serialized one after
+    /// another and each passed to a call by its range.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::{serialization::Serializable, WasmPluginBuilder};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?.finish()?;
+    /// let mut data = vec![];
+    /// let mut ranges = vec![];
+    /// for word in &["one", "two", "three"] {
+    ///     let start = data.len();
+    ///     data.extend(word.to_string().serialize()?);
+    ///     ranges.push(start..data.len());
+    /// }
+    /// let staged = plugin.stage_buffer(&data)?;
+    /// for range in ranges {
+    ///     let length: usize = plugin.call_function_with_staged_buffer("length", &staged, range)?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stage_buffer(&mut self, data: &[u8]) -> errors::Result<BufferHandle> {
+        let buffer = self.message_buffer()?.stage(data)?;
+        Ok(BufferHandle {
+            exports: self.instance.exports.clone(),
+            memory: self.memory()?.clone(),
+            buffer,
+        })
+    }
+
+    /// Call a function exported by the plugin with the bytes in `range` of a
+    /// buffer staged with `stage_buffer` as its argument. Nothing is copied
+    /// into the plugin's memory for the call.
+    ///
+    /// The handle must have come from this plugin and the plugin must not
+    /// have been reset or reloaded since, otherwise
+    /// `WasmPluginError::InvalidStagedBuffer` is returned.
+    pub fn call_function_with_staged_buffer<ReturnType>(
+        &mut self,
+        fn_name: &str,
+        buffer: &BufferHandle,
+        range: std::ops::Range<usize>,
+    ) -> errors::Result<ReturnType>
+    where
+        ReturnType: Deserializable,
+    {
+        if !buffer.memory.same(self.memory()?) {
+            return self.report(Err(errors::WasmPluginError::InvalidStagedBuffer(
+                "the buffer belongs to a different plugin instance".to_string(),
+            )));
+        }
+        if range.start > range.end || range.end > buffer.len() {
+            return self.report(Err(errors::WasmPluginError::InvalidStagedBuffer(format!(
+                "the range {:?} is outside the buffer of {} bytes",
+                range,
+                buffer.len()
+            ))));
+        }
+        let mut argument = FatPointer(0);
+        argument.set_ptr(buffer.buffer.ptr() + range.start as u32);
+        argument.set_len(range.len() as u32);
+        let buff = self.call_function_raw(fn_name, Some(argument))?;
+        serialization::deserialize_return(fn_name, &buff, self.deserialization_limit())
+    }
+
+    /// Call a function exported by the plugin and return the still
+    /// serialized bytes it responded with, rather than deserializing them.
+    /// `raw` is an optional argument which is already serialized, as for
+    /// `call_function_with_raw_serialized`.
+    ///
+    /// Functions exported with `#[export_function(raw_bytes)]` skip
+    /// serialization in the plugin as well, so the bytes they return reach
+    /// the host exactly as they were, without any framing added by the
+    /// serialization format.
+    pub fn call_function_returning_raw_bytes(
+        &mut self,
+        fn_name: &str,
+        raw: Option<&[u8]>,
+    ) -> errors::Result<Vec<u8>> {
+        self.call_function_with_message(fn_name, raw)
+    }
+
+    fn call_function_raw(
+        &self,
+        fn_name: &str,
+        input_buffer: Option<FatPointer>,
+    ) -> errors::Result<Vec<u8>> {
+        self.call_export_raw(&format!("wasm_plugin_exported__{}", fn_name), input_buffer)
+    }
+
+    // Call a function with an already serialized argument, returning the
+    // still serialized response.
+    pub(crate) fn call_function_with_message(
+        &self,
+        fn_name: &str,
+        message: Option<&[u8]>,
+    ) -> errors::Result<Vec<u8>> {
+        match message {
+            Some(message) => {
+                self.with_argument(message, |ptr| self.call_function_raw(fn_name, Some(ptr)))
+            }
+            None => self.call_function_raw(fn_name, None),
+        }
+    }
+
+    fn call_export_raw(
+        &self,
+        export_name: &str,
+        input_buffer: Option<FatPointer>,
+    ) -> errors::Result<Vec<u8>> {
+        let start = std::time::Instant::now();
+        let payload_in = input_buffer.as_ref().map_or(0, |ptr| ptr.len() as usize);
+        let result = self.call_export_unreported(export_name, input_buffer);
+        self.record_metrics(
+            export_name,
+            start,
+            payload_in,
+            result.as_ref().map(Vec::len),
+        );
+        self.report(result)
+    }
+
+    fn record_metrics(
+        &self,
+        export_name: &str,
+        start: std::time::Instant,
+        payload_in: usize,
+        result: Result<usize, &errors::WasmPluginError>,
+    ) {
+        if let Some(metrics) = &self.metrics {
+            let fn_name = export_name
+                .strip_prefix("wasm_plugin_exported__")
+                .unwrap_or(export_name);
+            match result {
+                Ok(payload_out) => {
+                    metrics
+                        .0
+                        .on_call(fn_name, start.elapsed(), payload_in, payload_out)
+                }
+                Err(e) => metrics.0.on_error(fn_name, e),
+            }
+        }
+    }
+
+    // Make a call without passing errors to the error handler, for callers
+    // which expect some errors and report the rest themselves
+    fn call_export_unreported(
+        &self,
+        export_name: &str,
+        input_buffer: Option<FatPointer>,
+    ) -> errors::Result<Vec<u8>> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "wasm_plugin::call",
+            plugin = self.name.as_deref().unwrap_or_default(),
+            fn_name = export_name
+                .strip_prefix("wasm_plugin_exported__")
+                .unwrap_or(export_name),
+            bytes_sent = input_buffer.as_ref().map_or(0, |ptr| ptr.len()),
+            bytes_received = tracing::field::Empty,
+        );
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        #[cfg(feature = "tracing")]
+        let start = std::time::Instant::now();
+        #[cfg(feature = "tracing")]
+        tracing::debug!("call started");
+        // Functions which return a single number skip the message buffer
+        // when they can
+        let result = match self.scalar_exports.get(export_name) {
+            Some(scalar) => self
+                .handle()
+                .call_scalar_export(export_name, scalar, input_buffer),
+            None => self.handle().call_export_raw(export_name, input_buffer),
+        };
+        #[cfg(feature = "tracing")]
+        match &result {
+            Ok(buff) => {
+                span.record("bytes_received", &buff.len());
+                tracing::debug!(duration = ?start.elapsed(), "call finished")
+            }
+            Err(e) => tracing::debug!(error = %e, duration = ?start.elapsed(), "call failed"),
+        }
+        result
+    }
+
+    // Pass any error to the registered error handler on its way out, naming
+    // the plugin in errors which can mention it
+    fn report<T>(&self, mut result: errors::Result<T>) -> errors::Result<T> {
+        if let Err(errors::WasmPluginError::FunctionNotFound(e)) = &mut result {
+            e.plugin = self.name.clone();
+        }
+        if let (Err(e), Some(handler)) = (&result, &self.error_handler) {
+            (handler.0)(e);
+        }
+        result
+    }
+
+    /// Call a function exported by the plugin once for each of `args`,
+    /// collecting the results. This is much cheaper than calling
+    /// `call_function_with_argument` in a loop because the function is
+    /// looked up once and all of the buffers used are freed together at the
+    /// end of the batch.
+    ///
+    /// The batch stops at the first call that fails.
+    pub fn call_function_batch<ReturnType, Args>(
+        &self,
+        fn_name: &str,
+        args: &[Args],
+    ) -> errors::Result<Vec<ReturnType>>
+    where
+        Args: Serializable,
+        ReturnType: Deserializable,
+    {
+        let export_name = format!("wasm_plugin_exported__{}", fn_name);
+        let buffs = self.report(self.handle().call_export_batch(
+            &export_name,
+            args,
+            |start, payload_in, result| {
+                self.record_metrics(&export_name, start, payload_in, result)
+            },
+        ))?;
+        let limit = self.deserialization_limit();
+        buffs
+            .iter()
+            .map(|buff| serialization::deserialize_return(fn_name, buff, limit))
+            .collect()
+    }
+
+    /// Call a function exported by the plugin.
     ///
     /// Deserialization of the return value depends on the type being known
-    /// at the call site.
+    /// at the call site. Functions which return `()` or another zero sized
+    /// type send back an empty message which is accepted only when the call
+    /// site also expects a zero sized type.
+    ///
+    /// Functions exported with `#[export_function(scalar)]` return their
+    /// number directly instead of writing a message into the plugin's
+    /// memory, which is used here and by the other `call_function` methods
+    /// when the plugin provides it. The number is deserialized as
+    /// `ReturnType` just like a message would be.
     pub fn call_function<ReturnType>(&mut self, fn_name: &str) -> errors::Result<ReturnType>
     where
         ReturnType: Deserializable,
     {
         let buff = self.call_function_raw(fn_name, None)?;
-        ReturnType::deserialize(&buff)
+        serialization::deserialize_return(fn_name, &buff, self.deserialization_limit())
+    }
+
+    /// Call a function exported by the plugin, making `ctx` available to the
+    /// imports registered with
+    /// `WasmPluginBuilder::import_function_with_call_context` for the
+    /// duration of the call. The context isn't serialized or sent to the
+    /// plugin.
+    pub fn call_function_with_context<ReturnType, Ctx>(
+        &mut self,
+        fn_name: &str,
+        ctx: &Ctx,
+    ) -> errors::Result<ReturnType>
+    where
+        ReturnType: Deserializable,
+        Ctx: 'static,
+    {
+        call_context::with_call_context(ctx, || self.call_function(fn_name))
+    }
+
+    /// Call a function exported by the plugin if it exists, returning
+    /// `Ok(None)` if the plugin doesn't export it. This suits functions which
+    /// are optional capabilities of a plugin. Other errors are returned as
+    /// they would be by `call_function`, but a missing function isn't passed
+    /// to the error handler.
+    pub fn try_call_function<ReturnType>(
+        &mut self,
+        fn_name: &str,
+    ) -> errors::Result<Option<ReturnType>>
+    where
+        ReturnType: Deserializable,
+    {
+        let export_name = format!("wasm_plugin_exported__{}", fn_name);
+        let start = std::time::Instant::now();
+        let buff = match self.call_export_unreported(&export_name, None) {
+            Err(errors::WasmPluginError::FunctionNotFound(e)) if e.export_name == export_name => {
+                return Ok(None)
+            }
+            result => {
+                self.record_metrics(&export_name, start, 0, result.as_ref().map(Vec::len));
+                self.report(result)?
+            }
+        };
+        serialization::deserialize_return(fn_name, &buff, self.deserialization_limit()).map(Some)
+    }
+
+    /// Call a function exported by the plugin, stopping it if it hasn't
+    /// returned within `timeout`. This protects the host from plugins which
+    /// never return, for example because they are stuck in a loop.
+    ///
+    /// The plugin is stopped the next time it goes around a loop, or when it
+    /// next calls an imported function, and `WasmPluginError::Timeout` is
+    /// returned. Like a plugin which panicked, one which was stopped part way
+    /// through a call may have left its own state half updated, so it may be
+    /// worth `reset`ting it, but it can still be used.
+    ///
+    /// Only plugins compiled by this crate can be stopped. For ones compiled
+    /// with a caller's `Store` this fails with
+    /// `WasmPluginError::Uninterruptible` without calling the plugin.
+    ///
+    /// ```rust,no_run
+    /// # use std::time::Duration;
+    /// # use wasm_plugin_host::{errors::WasmPluginError, WasmPluginBuilder};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?.finish()?;
+    /// match plugin.call_function_with_timeout::<String>("render", Duration::from_secs(1)) {
+    ///     Err(WasmPluginError::Timeout) => println!("the plugin took too long"),
+    ///     result => println!("{}", result?),
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn call_function_with_timeout<ReturnType>(
+        &mut self,
+        fn_name: &str,
+        timeout: std::time::Duration,
+    ) -> errors::Result<ReturnType>
+    where
+        ReturnType: Deserializable,
+    {
+        let flag = match &self.interrupt_flag {
+            Some(flag) => flag,
+            None => return self.report(Err(errors::WasmPluginError::Uninterruptible)),
+        };
+        let export_name = format!("wasm_plugin_exported__{}", fn_name);
+        let start = std::time::Instant::now();
+        let (result, interrupted) = interrupt::call_interruptible(
+            flag,
+            &CancelToken::new(),
+            timeout,
+            || true,
+            || self.call_export_unreported(&export_name, None),
+        );
+        let result = match result {
+            Err(_) if interrupted => Err(errors::WasmPluginError::Timeout),
+            result => result,
+        };
+        self.record_metrics(&export_name, start, 0, result.as_ref().map(Vec::len));
+        let buff = self.report(result)?;
+        serialization::deserialize_return(fn_name, &buff, self.deserialization_limit())
+    }
+
+    /// Call a function exported by the plugin, giving up once `token` is
+    /// cancelled. This lets a server stop waiting for work whose result is
+    /// no longer wanted, for example because the client disconnected.
+    ///
+    /// As with `call_function_with_timeout` the plugin is stopped the next
+    /// time it goes around a loop or calls an imported function after
+    /// `token` is cancelled, and `WasmPluginError::Cancelled` is returned.
+    /// The plugin can still be used afterwards, though it may be worth
+    /// `reset`ting it. Plugins compiled with a caller's `Store` can't be
+    /// stopped and fail with `WasmPluginError::Uninterruptible`.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::{CancelToken, WasmPluginBuilder};
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?.finish()?;
+    /// let token = CancelToken::new();
+    /// let on_disconnect = token.clone();
+    /// std::thread::spawn(move || {
+    ///     std::thread::sleep(std::time::Duration::from_secs(1));
+    ///     on_disconnect.cancel();
+    /// });
+    /// let page: String = plugin.call_function_with_cancel_token("render_page", &token)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn call_function_with_cancel_token<ReturnType>(
+        &mut self,
+        fn_name: &str,
+        token: &CancelToken,
+    ) -> errors::Result<ReturnType>
+    where
+        ReturnType: Deserializable,
+    {
+        let flag = match &self.interrupt_flag {
+            Some(flag) => flag,
+            None => return self.report(Err(errors::WasmPluginError::Uninterruptible)),
+        };
+        if token.is_cancelled() {
+            return self.report(Err(errors::WasmPluginError::Cancelled));
+        }
+        let export_name = format!("wasm_plugin_exported__{}", fn_name);
+        let start = std::time::Instant::now();
+        let (result, interrupted) = interrupt::call_interruptible(
+            flag,
+            token,
+            CANCEL_POLL_INTERVAL,
+            || token.is_cancelled(),
+            || self.call_export_unreported(&export_name, None),
+        );
+        // Imports fail with `Cancelled` too, but a plugin which catches that
+        // may return something else
+        let result = match result {
+            Err(_) if interrupted || token.is_cancelled() => {
+                Err(errors::WasmPluginError::Cancelled)
+            }
+            result => result,
+        };
+        self.record_metrics(&export_name, start, 0, result.as_ref().map(Vec::len));
+        let buff = self.report(result)?;
+        serialization::deserialize_return(fn_name, &buff, self.deserialization_limit())
+    }
+
+    /// Call a function exported by the plugin with
+    /// `wasm_plugin_guest::export_streaming_function` and iterate over the
+    /// items it produces. Each item is fetched from the plugin as the
+    /// iterator is advanced, so the host can start work on the first items
+    /// before the plugin has produced the rest.
+    ///
+    /// Iteration stops after the first error.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?.finish()?;
+    /// for line in plugin.call_function_streaming::<String>("render_lines") {
+    ///     println!("{}", line?);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn call_function_streaming<'a, ReturnType>(
+        &'a mut self,
+        fn_name: &str,
+    ) -> impl Iterator<Item = errors::Result<ReturnType>> + 'a
+    where
+        ReturnType: Deserializable + 'a,
+    {
+        let plugin = &*self;
+        let fn_name = fn_name.to_string();
+        let has_next_name = format!("wasm_plugin_exported__{}_has_next", fn_name);
+        let next_name = format!("wasm_plugin_exported__{}_next", fn_name);
+        let mut started = false;
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let item = (|| {
+                if !started {
+                    started = true;
+                    plugin.call_function_raw(&fn_name, None)?;
+                }
+                let has_next = plugin.report((|| {
+                    let has_next = plugin.handle().export_function(&has_next_name)?;
+                    Ok(has_next.native::<(), i32>()?.call()?)
+                })())?;
+                if has_next == 0 {
+                    return Ok(None);
+                }
+                let buff = plugin.call_export_raw(&next_name, None)?;
+                serialization::deserialize_return(&fn_name, &buff, plugin.deserialization_limit())
+                    .map(Some)
+            })();
+            match item {
+                Ok(Some(item)) => Some(Ok(item)),
+                Ok(None) => {
+                    done = true;
+                    None
+                }
+                Err(e) => {
+                    done = true;
+                    Some(Err(e))
+                }
+            }
+        })
+    }
+
+    /// Call a function exported by the plugin without knowing its types at
+    /// compile time. The argument, if there is one, is sent as JSON and the
+    /// response is returned as an untyped JSON value. Functions which return
+    /// `()` produce `Value::Null`.
+    ///
+    /// This is meant for scripting and other dynamic uses. The plugin must be
+    /// built with wasm_plugin_guest's `serialize_json` feature.
+    #[cfg(feature = "serialize_json")]
+    pub fn call_function_json(
+        &mut self,
+        fn_name: &str,
+        args: Option<&serde_json::Value>,
+    ) -> errors::Result<serde_json::Value> {
+        let message = args.map(|args| args.serialize()).transpose()?;
+        let buff = self.call_function_with_message(fn_name, message.as_deref())?;
+        if buff.is_empty() {
+            Ok(serde_json::Value::Null)
+        } else {
+            serialization::deserialize_return(fn_name, &buff, self.deserialization_limit())
+        }
+    }
+
+    /// Call a function exported by the plugin using its exact export name,
+    /// without the `wasm_plugin_exported__` prefix being added.
+    ///
+    /// This is useful for modules which weren't built with
+    /// `wasm_plugin_guest::export_function`. The function must still follow
+    /// the same calling convention: it takes no arguments and returns a fat
+    /// pointer to a message the guest wrote with `write_message`.
+    pub fn call_function_unchecked<ReturnType>(
+        &mut self,
+        raw_export_name: &str,
+    ) -> errors::Result<ReturnType>
+    where
+        ReturnType: Deserializable,
+    {
+        let buff = self.call_export_raw(raw_export_name, None)?;
+        serialization::deserialize_return(raw_export_name, &buff, self.deserialization_limit())
+    }
+
+    /// Call a function exported by the plugin using its exact export name,
+    /// without the `wasm_plugin_exported__` prefix being added, with a single
+    /// argument which will be serialized and sent to the plugin.
+    ///
+    /// The function must still follow the same calling convention as
+    /// `call_function_with_argument`: it receives the pointer and length of a
+    /// message which it reads with `read_message` and returns a fat pointer to
+    /// a message it wrote with `write_message`.
+    pub fn call_function_with_argument_unchecked<ReturnType, Args>(
+        &self,
+        raw_export_name: &str,
+        args: &Args,
+    ) -> errors::Result<ReturnType>
+    where
+        Args: Serializable,
+        ReturnType: Deserializable,
+    {
+        let message = args.serialize()?;
+        let buff = self.with_argument(&message, |ptr| {
+            self.call_export_raw(raw_export_name, Some(ptr))
+        })?;
+        serialization::deserialize_return(raw_export_name, &buff, self.deserialization_limit())
+    }
+
+    /// The names of the functions exported by the plugin with
+    /// `wasm_plugin_guest::export_function`, which can be passed to
+    /// `call_function` and friends.
+    pub fn exported_functions(&self) -> Vec<String> {
+        exported_functions(&self.instance.exports)
+    }
+
+    /// The names of the host functions the plugin imports with
+    /// `wasm_plugin_guest::import_functions!`, as they were passed to
+    /// `import_function` and friends.
+    pub fn imported_function_names(&self) -> Vec<String> {
+        self.instance
+            .module()
+            .imports()
+            .filter(|import| import.module() == "env")
+            .filter(|import| matches!(import.ty(), ExternType::Function(_)))
+            .filter_map(|import| {
+                import
+                    .name()
+                    .strip_prefix("wasm_plugin_imported__")
+                    .map(|name| name.to_string())
+            })
+            .collect()
+    }
+
+    /// The name given to the plugin with `WasmPluginBuilder::named`, if any.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The version the plugin advertises by exporting a `version` function
+    /// which returns a `String`, for example with
+    /// `wasm_plugin_guest::export_version!`. Returns `None` if the plugin
+    /// doesn't export one.
+    pub fn plugin_version(&mut self) -> errors::Result<Option<String>> {
+        self.try_call_function("version")
+    }
+
+    /// Read the global the plugin exports as `name`, for example
+    /// `(export "API_VERSION" (global i32))`. `T` is one of `i32`, `u32`,
+    /// `i64`, `u64`, `f32` or `f64` and must match the global's type.
+    pub fn get_global<T>(&self, name: &str) -> errors::Result<T>
+    where
+        T: std::convert::TryFrom<wasmer::Val>,
+    {
+        let value = self.instance.exports.get_global(name)?.get();
+        let found = value.ty();
+        T::try_from(value).map_err(|_| errors::WasmPluginError::TypeMismatch {
+            name: name.to_string(),
+            expected: std::any::type_name::<T>(),
+            found: format!("{:?}", found),
+        })
+    }
+
+    /// The function table the plugin exports as `name`, for example
+    /// `(export "__indirect_function_table" (table 1 funcref))`.
+    pub fn get_table(&self, name: &str) -> errors::Result<Table> {
+        Ok(self.instance.exports.get_table(name)?.clone())
+    }
+
+    /// Call the function at `index` in the table the plugin exports as
+    /// `table_name`, sending it `args` if given. This lets the host dispatch
+    /// through a table of callbacks the plugin sets up, like a vtable,
+    /// rather than calling functions by name.
+    ///
+    /// The function must have been generated by one of
+    /// wasm_plugin_guest's export macros. A Rust plugin gets the index of
+    /// one by casting it to an integer, like
+    /// `wasm_plugin_exported__on_click as usize`, in which case the table
+    /// is `__indirect_function_table`.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?.finish()?;
+    /// let on_click: u32 = plugin.call_function("on_click_handler")?;
+    /// let handled: bool =
+    ///     plugin.call_table_function("__indirect_function_table", on_click, Some(&(10, 20)))?;
+    /// let count: u32 = plugin.call_table_function("__indirect_function_table", 3, None::<&()>)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn call_table_function<ReturnType, Args>(
+        &mut self,
+        table_name: &str,
+        index: u32,
+        args: Option<&Args>,
+    ) -> errors::Result<ReturnType>
+    where
+        ReturnType: Deserializable,
+        Args: Serializable,
+    {
+        let name = format!("{}[{}]", table_name, index);
+        let f = match self.get_table(table_name)?.get(index) {
+            Some(wasmer::Val::FuncRef(f)) => f,
+            _ => {
+                return self.report(Err(errors::WasmPluginError::InvalidTableIndex {
+                    table: table_name.to_string(),
+                    index,
+                }))
+            }
+        };
+        let buff = match args {
+            Some(args) => {
+                let message = args.serialize()?;
+                self.with_argument(&message, |ptr| self.handle().call_raw(&name, &f, Some(ptr)))
+            }
+            None => self.handle().call_raw(&name, &f, None),
+        };
+        let buff = self.report(buff)?;
+        serialization::deserialize_return(&name, &buff, self.deserialization_limit())
+    }
+
+    /// Call a function the plugin exports as `fn_name` with WASM values and
+    /// return the values it returns, without any serialization or message
+    /// buffers. This is much faster than `call_function` for functions which
+    /// only take and return numbers, like numerical kernels called in a
+    /// tight loop.
+    ///
+    /// The function is called by its own name rather than the
+    /// `wasm_plugin_exported__` one, so it must be exported with
+    /// `#[export_scalar_function]` or as a plain `extern "C"` function.
+    /// `Args` and `Rets` must match its signature, as with Wasmer's
+    /// `NativeFunc`, or `WasmPluginError::TypeMismatch` is returned. A single
+    /// argument is passed as itself rather than as a 1-tuple.
+    ///
+    /// Each call looks the function up again. Use `scalar_function` to look
+    /// it up once for functions called in a loop.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?.finish()?;
+    /// let length: f64 = plugin.call_function_scalar("hypotenuse", (3.0f64, 4.0f64))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn call_function_scalar<Args, Rets>(
+        &mut self,
+        fn_name: &str,
+        args: Args,
+    ) -> errors::Result<Rets>
+    where
+        Args: ScalarArgs,
+        Rets: WasmTypeList,
+    {
+        let result = self
+            .scalar_function_unreported(fn_name)
+            .and_then(|f| f.call(args));
+        self.report(result)
+    }
+
+    /// Look up a function the plugin exports as `fn_name` for calling with
+    /// WASM values, as `call_function_scalar` does, and keep it to be called
+    /// repeatedly without looking it up again. Fails with
+    /// `WasmPluginError::TypeMismatch` if `Args` and `Rets` don't match the
+    /// function's signature.
+    ///
+    /// The function keeps using the instance it was looked up in, so it must
+    /// be looked up again after the plugin is `reset` or `reload`ed.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?.finish()?;
+    /// let brightness = plugin.scalar_function::<(u32, u32), f32>("brightness")?;
+    /// for y in 0..480 {
+    ///     for x in 0..640 {
+    ///         let value = brightness.call((x, y))?;
+    ///     }
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn scalar_function<Args, Rets>(
+        &self,
+        fn_name: &str,
+    ) -> errors::Result<ScalarFunction<Args, Rets>>
+    where
+        Args: ScalarArgs,
+        Rets: WasmTypeList,
+    {
+        self.report(self.scalar_function_unreported(fn_name))
+    }
+
+    fn scalar_function_unreported<Args, Rets>(
+        &self,
+        fn_name: &str,
+    ) -> errors::Result<ScalarFunction<Args, Rets>>
+    where
+        Args: ScalarArgs,
+        Rets: WasmTypeList,
+    {
+        let function = self
+            .instance
+            .exports
+            .get_native_function::<Args, Rets>(fn_name)
+            .map_err(|e| match e {
+                wasmer::ExportError::IncompatibleType => {
+                    let found = match self.instance.exports.get_function(fn_name) {
+                        Ok(f) => f.ty().to_string(),
+                        Err(_) => "not a function".to_string(),
+                    };
+                    errors::WasmPluginError::TypeMismatch {
+                        name: fn_name.to_string(),
+                        expected: std::any::type_name::<fn(Args) -> Rets>(),
+                        found,
+                    }
+                }
+                e => e.into(),
+            })?;
+        Ok(ScalarFunction {
+            function,
+            exports: self.instance.exports.clone(),
+            settings: self.settings.clone(),
+            unfreed: self.unfreed.clone(),
+        })
+    }
+
+    /// Set the mutable global the plugin exports as `name`. `value` must
+    /// match the global's type, see `get_global`.
+    pub fn set_global<T>(&mut self, name: &str, value: T) -> errors::Result<()>
+    where
+        T: Into<wasmer::Val>,
+    {
+        let global = self.instance.exports.get_global(name)?;
+        let value = value.into();
+        if global.ty().ty != value.ty() {
+            return Err(errors::WasmPluginError::TypeMismatch {
+                name: name.to_string(),
+                expected: std::any::type_name::<T>(),
+                found: format!("{:?}", global.ty().ty),
+            });
+        }
+        Ok(global.set(value)?)
+    }
+
+    /// The underlying Wasmer `Instance`. This is an escape hatch for calling
+    /// exports which don't follow the plugin calling convention or inspecting
+    /// globals; nothing done through it is tracked by the `WasmPlugin`.
+    pub fn instance(&self) -> &Instance {
+        &self.instance
+    }
+
+    /// The `Store` the plugin was compiled and instantiated in.
+    pub fn store_mut(&mut self) -> &mut Store {
+        &mut self.store
+    }
+
+    /// The plugin's exported linear memory.
+    pub fn memory(&self) -> errors::Result<&Memory> {
+        self.handle().memory()
+    }
+
+    /// A view of the plugin's linear memory as bytes, for transferring data
+    /// directly rather than as messages. For example the plugin can return
+    /// the address and length of a buffer it owns and the host can then
+    /// read or fill it in place without any serialization.
+    ///
+    /// The guard borrows the plugin so it can't be reset or dropped while the
+    /// view is in use. Calls into the plugin may grow its memory, which makes
+    /// a view taken before them too short, so take a fresh view after calls.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?.finish()?;
+    /// let (ptr, len): (u32, u32) = plugin.call_function("texture_buffer")?;
+    /// let view = plugin.memory_view()?;
+    /// for cell in &view[ptr as usize..(ptr + len) as usize] {
+    ///     cell.set(0xff);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn memory_view(&self) -> errors::Result<MemoryViewGuard<'_>> {
+        Ok(MemoryViewGuard {
+            view: self.memory()?.view(),
+        })
+    }
+
+    /// The current size of the plugin's linear memory in bytes.
+    pub fn memory_size(&self) -> errors::Result<usize> {
+        Ok(self.memory()?.data_size() as usize)
+    }
+
+    /// The current size of the plugin's linear memory in WASM pages of 64 KiB.
+    pub fn memory_pages(&self) -> errors::Result<u32> {
+        Ok(self.memory()?.size().0)
+    }
+
+    /// Copy the whole of the plugin's linear memory, for post-mortem debugging
+    /// of problems like heap corruption or bad pointer math. It still works
+    /// after a call has failed with a `RuntimeError`, as long as the plugin
+    /// hasn't been reset. The copy is empty if the plugin has no memory.
+    pub fn dump_linear_memory(&self) -> Vec<u8> {
+        self.memory()
+            .map(|memory| memory.view::<u8>().iter().map(|cell| cell.get()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Write a copy of the plugin's linear memory to the file at `path`. See
+    /// `dump_linear_memory`.
+    ///
+    /// ```rust,no_run
+    /// # use wasm_plugin_host::WasmPluginBuilder;
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut plugin = WasmPluginBuilder::from_file("path/to/plugin.wasm")?.finish()?;
+    /// if let Err(e) = plugin.call_function::<()>("update") {
+    ///     eprintln!("{}", e);
+    ///     plugin.dump_linear_memory_to_file("plugin.mem")?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn dump_linear_memory_to_file(&self, path: impl AsRef<Path>) -> errors::Result<()> {
+        std::fs::write(path, self.dump_linear_memory())?;
+        Ok(())
+    }
+
+    /// Format `len` bytes of the plugin's linear memory starting at `offset`
+    /// as a hex dump, sixteen bytes to a line with their addresses and ASCII
+    /// on either side. The range is cut short at the end of the memory.
+    pub fn dump_linear_memory_hex(&self, offset: u32, len: u32) -> String {
+        let memory = self.dump_linear_memory();
+        let start = (offset as usize).min(memory.len());
+        let end = start.saturating_add(len as usize).min(memory.len());
+        hex_dump(start, &memory[start..end])
+    }
+
+    /// Try again to free message buffers which couldn't be freed at the end
+    /// of the call which wrote them. That happens when the plugin traps while
+    /// freeing a buffer, which leaves the buffers after it, or when an
+    /// imported function returns a message outside of any call, for example
+    /// to a function called through `ScalarFunction::native`.
+    ///
+    /// If freeing fails again the error is returned and the buffers after
+    /// the one which failed are kept for the next attempt.
+    pub fn collect_garbage(&mut self) -> errors::Result<()> {
+        let buffers = std::mem::take(&mut *self.unfreed.lock().unwrap());
+        self.handle().free_buffers(buffers)
+    }
+
+    /// The number of message buffers waiting for `collect_garbage`. Buffers
+    /// are normally freed at the end of the call which wrote them so anything
+    /// other than 0 points at a plugin which is failing to free its memory.
+    pub fn pending_garbage_count(&self) -> usize {
+        self.unfreed.lock().unwrap().len()
+    }
+
+    /// Reset the plugin's state.
+    ///
+    /// If the plugin exports a reset hook (see `wasm_plugin_guest::reset_function`)
+    /// it is called and the existing instance is kept. Otherwise the plugin is
+    /// re-instantiated from its already compiled module, which discards all of
+    /// its memory and globals but does not require recompiling. The new
+    /// instance is sent the config from `WasmPluginBuilder::with_init_config`
+    /// again.
+    pub fn reset(&mut self) -> errors::Result<()> {
+        if self.has_function("reset") {
+            self.call_function("reset")
+        } else {
+            let module = self.instance.module().clone();
+            self.reinstantiate(&module)
+        }
+    }
+
+    /// Replace the plugin's code with a newly compiled version of it, for
+    /// example after the plugin has been rebuilt, without having to rebuild
+    /// the `WasmPlugin` and its imports.
+    ///
+    /// The new module is instantiated with the same imports, settings and
+    /// init config the plugin was built with. The old instance is discarded
+    /// so all guest state, including its memory and globals, is lost. If the
+    /// new module can't be compiled or instantiated the plugin is left
+    /// running the old one.
+    pub fn reload(&mut self, source: &[u8]) -> errors::Result<()> {
+        let module = interrupt::compile(&self.store, source)?;
+        check_runtime_exports(&module, &self.settings.read().unwrap())?;
+        self.reinstantiate(&module)
+    }
+
+    fn reinstantiate(&mut self, module: &Module) -> errors::Result<()> {
+        self.instance = Instance::new(module, &self.import_object)?;
+        expose_memory(&mut self.instance.exports, &self.settings.read().unwrap());
+        self.scalar_exports = scalar::scalar_exports(&self.instance.exports);
+        self.interrupt_flag = interrupt_flag(&self.instance);
+        // Any pooled buffers lived in the old instance's memory
+        if let Some(pool) = &self.buffer_pool {
+            pool.lock().unwrap().clear();
+        }
+        self.init()
+    }
+}
+
+/// A buffer in a plugin's memory created by `WasmPlugin::stage_buffer`. It is
+/// freed when the handle is dropped.
+pub struct BufferHandle {
+    exports: Exports,
+    memory: Memory,
+    buffer: FatPointer,
+}
+
+impl BufferHandle {
+    /// The number of bytes in the buffer
+    pub fn len(&self) -> usize {
+        self.buffer.len() as usize
+    }
+
+    /// Whether the buffer is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Drop for BufferHandle {
+    fn drop(&mut self) {
+        // The instance the buffer was allocated in is kept alive by the
+        // exports, so this is safe even if the plugin has moved on to a new
+        // one. If freeing fails the plugin is already broken.
+        if let Ok(free) = self
+            .exports
+            .get_native_function::<(u32, u32), ()>("free_message_buffer")
+        {
+            let _ = free.call(self.buffer.ptr(), self.buffer.len());
+        }
+    }
+}
+
+/// A view of a plugin's linear memory, created by `WasmPlugin::memory_view`.
+/// It dereferences to a `MemoryView` of the memory's bytes.
+pub struct MemoryViewGuard<'a> {
+    view: wasmer::MemoryView<'a, u8>,
+}
+
+impl<'a> std::ops::Deref for MemoryViewGuard<'a> {
+    type Target = wasmer::MemoryView<'a, u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.view
+    }
+}
+
+fn check_resource_limits(
+    module: &Module,
+    table_limit: Option<u32>,
+    globals_limit: Option<usize>,
+) -> errors::Result<()> {
+    let info = module.info();
+    if let Some(limit) = table_limit {
+        let requested: usize = info.tables.values().map(|t| t.minimum as usize).sum();
+        if requested > limit as usize {
+            return Err(errors::WasmPluginError::ResourceLimitExceeded {
+                resource: "table elements",
+                requested,
+                limit: limit as usize,
+            });
+        }
+    }
+    if let Some(limit) = globals_limit {
+        if info.globals.len() > limit {
+            return Err(errors::WasmPluginError::ResourceLimitExceeded {
+                resource: "globals",
+                requested: info.globals.len(),
+                limit,
+            });
+        }
+    }
+    Ok(())
+}
+
+type AbortHandler = Arc<dyn Fn(u32, u32, i32, i32) + Send + Sync>;
+
+#[cfg(feature = "tracing")]
+fn debug_abort(env: &Env<()>, message: u32, file: u32, line: i32, column: i32) {
+    let read = |ptr| {
+        env.memory
+            .get_ref()
+            .map(|memory| read_assemblyscript_string(memory, ptr))
+            .unwrap_or_default()
+    };
+    tracing::error!(file = %read(file), line, column, "plugin aborted: {}", read(message));
+}
+
+// AssemblyScript strings are UTF-16 with their length in bytes stored in the
+// four bytes before them
+#[cfg(feature = "tracing")]
+fn read_assemblyscript_string(memory: &Memory, ptr: u32) -> String {
+    let view: wasmer::MemoryView<u8> = memory.view();
+    let ptr = ptr as usize;
+    if ptr < 4 || ptr > view.len() {
+        return String::new();
+    }
+    let mut len = [0; 4];
+    for (dst, src) in len.iter_mut().zip(&view[ptr - 4..ptr]) {
+        *dst = src.get();
     }
+    let end = (ptr + u32::from_le_bytes(len) as usize).min(view.len());
+    let units: Vec<u16> = view[ptr..end]
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0].get(), unit[1].get()]))
+        .collect();
+    String::from_utf16_lossy(&units)
 }
 
 #[cfg(feature = "inject_getrandom")]
 fn getrandom_shim(env: &Env<()>, ptr: u32, len: u32) {
-    if let Some(memory) = env.memory_ref() {
-        let view: MemoryView<u8> = memory.view();
+    if let Some(memory) = env.memory.get_ref() {
+        let view: wasmer::MemoryView<u8> = memory.view();
         let mut buff: Vec<u8> = vec![0; len as usize];
         getrandom::getrandom(&mut buff).unwrap();
         for (dst, src) in view[ptr as usize..ptr as usize + len as usize]
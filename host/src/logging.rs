@@ -0,0 +1,88 @@
+//! Receiving log records from plugins built with wasm_plugin_guest's
+//! `host_logging` feature.
+
+// The name plugins import the logging function under, prefixed like any
+// other import. It must match the one used by wasm_plugin_guest.
+pub(crate) const LOG_IMPORT_NAME: &str = "__log";
+
+// Log records are sent as a tuple, which protocol buffers can't encode, so
+// with `serialize_protobuf` they are sent as a message with the same fields.
+#[cfg(not(feature = "serialize_protobuf"))]
+pub(crate) type LogRecord = (u8, String, String);
+#[cfg(feature = "serialize_protobuf")]
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct LogRecord {
+    #[prost(uint32, tag = "1")]
+    level: u32,
+    #[prost(string, tag = "2")]
+    target: String,
+    #[prost(string, tag = "3")]
+    message: String,
+}
+
+#[cfg(not(feature = "serialize_protobuf"))]
+pub(crate) fn into_parts(record: LogRecord) -> (u8, String, String) {
+    record
+}
+#[cfg(feature = "serialize_protobuf")]
+pub(crate) fn into_parts(record: LogRecord) -> (u8, String, String) {
+    (record.level as u8, record.target, record.message)
+}
+
+/// The severity of a log record sent by a plugin. The levels mean the same
+/// as they do in the `log` and `tracing` crates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    /// Something has failed
+    Error,
+    /// Something may be wrong
+    Warn,
+    /// Useful information
+    Info,
+    /// Information useful when debugging
+    Debug,
+    /// Very detailed information
+    Trace,
+}
+
+impl LogLevel {
+    // Levels are sent over the wire as numbers so they work with every
+    // serialization format
+    pub(crate) fn from_wire(level: u8) -> Self {
+        match level {
+            1 => LogLevel::Error,
+            2 => LogLevel::Warn,
+            3 => LogLevel::Info,
+            4 => LogLevel::Debug,
+            _ => LogLevel::Trace,
+        }
+    }
+}
+
+impl std::fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        })
+    }
+}
+
+// Used until `WasmPluginBuilder::with_logger` replaces it so plugins which log
+// can always be instantiated. Records go to tracing if it's enabled and are
+// dropped otherwise.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn default_logger(record: LogRecord) {
+    let (level, target, message) = into_parts(record);
+    #[cfg(feature = "tracing")]
+    match LogLevel::from_wire(level) {
+        LogLevel::Error => tracing::error!(plugin_target = %target, "{}", message),
+        LogLevel::Warn => tracing::warn!(plugin_target = %target, "{}", message),
+        LogLevel::Info => tracing::info!(plugin_target = %target, "{}", message),
+        LogLevel::Debug => tracing::debug!(plugin_target = %target, "{}", message),
+        LogLevel::Trace => tracing::trace!(plugin_target = %target, "{}", message),
+    }
+}
@@ -0,0 +1,51 @@
+//! Observing the calls made to a plugin, for example to export latency and
+//! error counts to a monitoring system.
+
+use std::time::Duration;
+
+use crate::errors::WasmPluginError;
+
+/// Receives a record of every call made through a `WasmPlugin`, registered
+/// with `WasmPluginBuilder::with_metrics`.
+///
+/// The callbacks run on the calling thread once the plugin has returned, so
+/// they should be cheap, like incrementing a counter or recording into a
+/// histogram. They must not call back into the plugin.
+///
+/// ```rust
+/// use std::sync::atomic::{AtomicU64, Ordering};
+/// use std::time::Duration;
+/// use wasm_plugin_host::{errors::WasmPluginError, PluginMetrics};
+///
+/// #[derive(Default)]
+/// struct Counters {
+///     calls: AtomicU64,
+///     errors: AtomicU64,
+/// }
+///
+/// impl PluginMetrics for Counters {
+///     fn on_call(&self, _fn_name: &str, _duration: Duration, _payload_in: usize, _payload_out: usize) {
+///         self.calls.fetch_add(1, Ordering::Relaxed);
+///     }
+///
+///     fn on_error(&self, _fn_name: &str, _error: &WasmPluginError) {
+///         self.errors.fetch_add(1, Ordering::Relaxed);
+///     }
+/// }
+///
+/// let counters = Counters::default();
+/// counters.on_call("update", Duration::from_micros(40), 16, 8);
+/// assert_eq!(counters.calls.load(Ordering::Relaxed), 1);
+/// ```
+pub trait PluginMetrics: Send + Sync {
+    /// A call to the plugin function `fn_name` succeeded. `duration` covers
+    /// the time spent in the plugin and reading its response, `payload_in`
+    /// and `payload_out` are the sizes of the messages sent and received as
+    /// they were on the wire, so after any compression.
+    fn on_call(&self, fn_name: &str, duration: Duration, payload_in: usize, payload_out: usize);
+
+    /// A call to the plugin function `fn_name` failed with `error`.
+    fn on_error(&self, fn_name: &str, error: &WasmPluginError) {
+        let _ = (fn_name, error);
+    }
+}
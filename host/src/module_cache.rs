@@ -0,0 +1,90 @@
+//! Reusing compiled plugins within a process.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use wasmer::{Module, Store};
+
+use crate::errors;
+
+/// Compiled modules kept in memory, keyed by a hash of their source.
+///
+/// Compiling is by far the most expensive part of loading a plugin. A host
+/// which loads the same plugins over and over, say once for each file it
+/// processes, can share one cache between all of the loads so each plugin is
+/// only compiled the first time. Modules are kept until the cache is dropped
+/// or `clear`ed.
+///
+/// Every module in the cache belongs to the cache's `Store`, which is shared
+/// by all of the plugins loaded through it.
+///
+/// ```rust,no_run
+/// # use wasm_plugin_host::{ModuleCache, WasmPluginBuilder};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let cache = ModuleCache::new();
+/// let source = std::fs::read("path/to/plugin.wasm")?;
+/// for _ in 0..10 {
+///     // Only the first of these compiles the plugin
+///     let plugin = WasmPluginBuilder::from_source_cached(&cache, &source)?.finish()?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct ModuleCache {
+    store: Store,
+    modules: Mutex<HashMap<[u8; 32], Module>>,
+}
+
+impl Default for ModuleCache {
+    fn default() -> Self {
+        Self::with_store(crate::Compiler::Cranelift.store())
+    }
+}
+
+impl ModuleCache {
+    /// Create an empty cache with a default `Store`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an empty cache whose modules are compiled with `store`.
+    pub fn with_store(store: Store) -> Self {
+        Self {
+            store,
+            modules: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The `Store` modules in this cache are compiled with.
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    /// The number of modules in the cache.
+    pub fn len(&self) -> usize {
+        self.modules.lock().unwrap().len()
+    }
+
+    /// Whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove every module from the cache. Plugins already loaded from them
+    /// are unaffected.
+    pub fn clear(&self) {
+        self.modules.lock().unwrap().clear();
+    }
+
+    pub(crate) fn get_or_compile(&self, source: &[u8]) -> errors::Result<Module> {
+        let key = *blake3::hash(source).as_bytes();
+        if let Some(module) = self.modules.lock().unwrap().get(&key) {
+            return Ok(module.clone());
+        }
+        // Compiling can take a while so it isn't done under the lock. Two
+        // threads loading the same new plugin may both compile it, which is
+        // harmless.
+        let module = crate::interrupt::compile(&self.store, source)?;
+        self.modules.lock().unwrap().insert(key, module.clone());
+        Ok(module)
+    }
+}
@@ -0,0 +1,137 @@
+//! Run several instances of a plugin on threads of their own which share a
+//! queue of calls.
+
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+};
+
+use crossbeam_channel as channel;
+
+use crate::{
+    errors,
+    serialization::{self, Deserializable},
+    WasmPlugin, WasmPluginBuilder,
+};
+
+type Job = Box<dyn FnOnce(&WasmPlugin) + Send>;
+
+/// A fixed number of worker threads, each with its own instance of a plugin,
+/// which take calls off a shared queue.
+///
+/// Unlike `PluginService`, calls are run concurrently, one per worker. The
+/// instances are independent so any state a plugin keeps between calls is
+/// only seen by calls which happen to run on the same worker.
+///
+/// The workers stop once the pool is dropped and they have finished the
+/// calls already submitted.
+///
+/// ```rust,no_run
+/// # use wasm_plugin_host::WasmPluginWorkerPool;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let source = std::fs::read("path/to/plugin.wasm")?;
+/// let pool = WasmPluginWorkerPool::new(&source, 4)?;
+/// let responses: Vec<_> = (0..16)
+///     .map(|_| pool.submit::<u32>("next_id".to_string(), None))
+///     .collect();
+/// for response in responses {
+///     let id = response.recv()??;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct WasmPluginWorkerPool {
+    sender: channel::Sender<Job>,
+    workers: usize,
+}
+
+impl WasmPluginWorkerPool {
+    /// Start `workers` threads each running its own instance of the plugin
+    /// compiled from `source`, with the builder's default settings. Any error
+    /// loading the plugin is returned here.
+    pub fn new(source: &[u8], workers: usize) -> errors::Result<Self> {
+        let source = source.to_vec();
+        Self::spawn(workers, move || {
+            WasmPluginBuilder::from_source(&source)?.finish()
+        })
+    }
+
+    /// Start `workers` threads and create each one's plugin on it with
+    /// `load`, which allows the plugins to be configured and given imports.
+    /// The first error from `load` is returned here.
+    pub fn spawn(
+        workers: usize,
+        load: impl Fn() -> errors::Result<WasmPlugin> + Send + Sync + 'static,
+    ) -> errors::Result<Self> {
+        let (sender, receiver) = channel::unbounded::<Job>();
+        let (loaded_sender, loaded) = mpsc::channel();
+        let load = Arc::new(load);
+        for _ in 0..workers {
+            let receiver = receiver.clone();
+            let loaded_sender = loaded_sender.clone();
+            let load = load.clone();
+            thread::spawn(move || {
+                let plugin = match load() {
+                    Ok(plugin) => {
+                        let _ = loaded_sender.send(Ok(()));
+                        plugin
+                    }
+                    Err(e) => {
+                        let _ = loaded_sender.send(Err(e));
+                        return;
+                    }
+                };
+                for job in receiver {
+                    job(&plugin);
+                }
+            });
+        }
+        drop(loaded_sender);
+        for _ in 0..workers {
+            loaded
+                .recv()
+                .map_err(|_| errors::WasmPluginError::ServiceStopped)??;
+        }
+        Ok(Self { sender, workers })
+    }
+
+    /// The number of worker threads.
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// Queue a call to the function `fn_name` exported by the plugin. `args`
+    /// is the already serialized argument, if the function takes one. The
+    /// result is sent to the returned receiver once a worker has made the
+    /// call.
+    pub fn submit<R>(
+        &self,
+        fn_name: String,
+        args: Option<Vec<u8>>,
+    ) -> mpsc::Receiver<errors::Result<R>>
+    where
+        R: Deserializable + Send + 'static,
+    {
+        let (response, receiver) = mpsc::channel();
+        let job: Job = {
+            let response = response.clone();
+            Box::new(move |plugin: &WasmPlugin| {
+                let result = plugin
+                    .call_function_with_message(&fn_name, args.as_deref())
+                    .and_then(|buff| {
+                        serialization::deserialize_return(
+                            &fn_name,
+                            &buff,
+                            plugin.deserialization_limit(),
+                        )
+                    });
+                // The caller may have given up waiting, which is fine
+                let _ = response.send(result);
+            })
+        };
+        if self.sender.send(job).is_err() {
+            let _ = response.send(Err(errors::WasmPluginError::ServiceStopped));
+        }
+        receiver
+    }
+}
@@ -0,0 +1,73 @@
+//! A named collection of plugins which share a common set of imports.
+
+use std::{collections::HashMap, path::Path};
+
+use crate::{errors, serialization::Deserializable, ImportableFn, WasmPlugin, WasmPluginBuilder};
+
+type ImportRegistration = Box<dyn Fn(WasmPluginBuilder) -> WasmPluginBuilder>;
+
+/// Loads plugins and keeps them by name.
+///
+/// Functions imported through the registry are made available to every
+/// plugin loaded after they are registered, which is convenient when all
+/// plugins in an application share the same host API.
+#[derive(Default)]
+pub struct PluginRegistry {
+    plugins: HashMap<String, WasmPlugin>,
+    imports: Vec<ImportRegistration>,
+}
+
+impl PluginRegistry {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Import a function defined in the host into every plugin subsequently
+    /// loaded by this registry. See `WasmPluginBuilder::import_function`.
+    pub fn import_function<Args, F>(&mut self, name: impl ToString, value: F) -> &mut Self
+    where
+        F: ImportableFn<Args> + Clone + Send + 'static,
+    {
+        let name = name.to_string();
+        self.imports
+            .push(Box::new(move |builder: WasmPluginBuilder| {
+                builder.import_function(name.clone(), value.clone())
+            }));
+        self
+    }
+
+    /// Load a plugin off disk and store it under `name`, replacing any plugin
+    /// previously registered with that name.
+    pub fn load(&mut self, name: impl Into<String>, path: impl AsRef<Path>) -> errors::Result<()> {
+        let name = name.into();
+        let builder = self.imports.iter().fold(
+            WasmPluginBuilder::from_file(path)?.named(name.clone()),
+            |builder, import| import(builder),
+        );
+        self.plugins.insert(name, builder.finish()?);
+        Ok(())
+    }
+
+    /// Get the plugin registered under `name`.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut WasmPlugin> {
+        self.plugins.get_mut(name)
+    }
+
+    /// Remove the plugin registered under `name`, returning it if it existed.
+    pub fn unload(&mut self, name: &str) -> Option<WasmPlugin> {
+        self.plugins.remove(name)
+    }
+
+    /// Call a function exported by the plugin registered under `plugin`.
+    /// See `WasmPlugin::call_function`.
+    pub fn call<ReturnType>(&mut self, plugin: &str, function: &str) -> errors::Result<ReturnType>
+    where
+        ReturnType: Deserializable,
+    {
+        self.plugins
+            .get_mut(plugin)
+            .ok_or_else(|| errors::WasmPluginError::PluginNotFound(plugin.to_string()))?
+            .call_function(function)
+    }
+}
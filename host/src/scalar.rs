@@ -0,0 +1,181 @@
+//! Calls which pass numbers as WASM values rather than through message
+//! buffers, for functions exported with `#[export_function(scalar)]` or
+//! `#[export_scalar_function]`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+use wasmer::{
+    Exports, Extern, FromToNativeWasmType, Function, NativeFunc, RuntimeError, WasmTypeList,
+};
+
+use crate::{errors, serialization::Serializable, FatPointer, PluginHandle, Settings};
+
+// The types a scalar export can return. Unsigned integers are passed as the
+// signed WASM type of the same width and the kind says how to read them back.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ScalarKind {
+    I32,
+    U32,
+    I64,
+    U64,
+    F32,
+    F64,
+}
+
+impl ScalarKind {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "i32" => ScalarKind::I32,
+            "u32" => ScalarKind::U32,
+            "i64" => ScalarKind::I64,
+            "u64" => ScalarKind::U64,
+            "f32" => ScalarKind::F32,
+            "f64" => ScalarKind::F64,
+            _ => return None,
+        })
+    }
+
+    // Call `f` and serialize the number it returns, so it can be
+    // deserialized exactly like a message from the function's regular export
+    pub(crate) fn call(self, f: &Function, input: Option<FatPointer>) -> errors::Result<Vec<u8>> {
+        match self {
+            ScalarKind::I32 => call_native::<i32>(f, input)?.serialize(),
+            ScalarKind::U32 => (call_native::<i32>(f, input)? as u32).serialize(),
+            ScalarKind::I64 => call_native::<i64>(f, input)?.serialize(),
+            ScalarKind::U64 => (call_native::<i64>(f, input)? as u64).serialize(),
+            ScalarKind::F32 => call_native::<f32>(f, input)?.serialize(),
+            ScalarKind::F64 => call_native::<f64>(f, input)?.serialize(),
+        }
+    }
+}
+
+fn call_native<T: WasmTypeList>(f: &Function, input: Option<FatPointer>) -> errors::Result<T> {
+    Ok(match input {
+        Some(fat_ptr) => f
+            .native::<(u32, u32), T>()?
+            .call(fat_ptr.ptr(), fat_ptr.len())?,
+        None => f.native::<(), T>()?.call()?,
+    })
+}
+
+/// A function exported by the plugin which takes and returns WASM values
+/// directly, created by `WasmPlugin::scalar_function`. The function is looked
+/// up and its signature checked once, so calling it is as cheap as calling a
+/// Wasmer `NativeFunc`.
+pub struct ScalarFunction<Args, Rets>
+where
+    Args: WasmTypeList,
+    Rets: WasmTypeList,
+{
+    pub(crate) function: NativeFunc<Args, Rets>,
+    pub(crate) exports: Exports,
+    pub(crate) settings: Arc<RwLock<Settings>>,
+    pub(crate) unfreed: Arc<Mutex<Vec<FatPointer>>>,
+}
+
+impl<Args, Rets> ScalarFunction<Args, Rets>
+where
+    Args: ScalarArgs,
+    Rets: WasmTypeList,
+{
+    /// Call the function with `args`, a single value or a tuple of them.
+    pub fn call(&self, args: Args) -> errors::Result<Rets> {
+        let handle = PluginHandle {
+            exports: &self.exports,
+            settings: &self.settings,
+            unfreed: &self.unfreed,
+        };
+        handle.call_native(|| Ok(args.call_native(&self.function)?))
+    }
+
+    /// The underlying Wasmer `NativeFunc`. Calling it directly skips freeing
+    /// the buffers of messages returned by any imports the function calls
+    /// until `WasmPlugin::collect_garbage` is called, so it's best used for
+    /// functions which call none.
+    pub fn native(&self) -> &NativeFunc<Args, Rets> {
+        &self.function
+    }
+}
+
+/// The arguments of a `ScalarFunction`: `()`, a single number or a tuple of
+/// up to eight of them.
+pub trait ScalarArgs: WasmTypeList {
+    #[doc(hidden)]
+    fn call_native<Rets: WasmTypeList>(
+        self,
+        f: &NativeFunc<Self, Rets>,
+    ) -> Result<Rets, RuntimeError>;
+}
+
+// Wasmer takes a single argument as itself rather than as a 1-tuple
+macro_rules! impl_scalar_arg {
+    ($($ty:ty),*) => {
+        $(
+            impl ScalarArgs for $ty {
+                fn call_native<Rets: WasmTypeList>(
+                    self,
+                    f: &NativeFunc<Self, Rets>,
+                ) -> Result<Rets, RuntimeError> {
+                    f.call(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_scalar_arg!(i8, u8, i16, u16, i32, u32, i64, u64, f32, f64);
+
+macro_rules! impl_scalar_args {
+    ($($arg:ident),*) => {
+        #[allow(non_snake_case)]
+        impl<$($arg: FromToNativeWasmType),*> ScalarArgs for ($($arg),*) {
+            fn call_native<Rets: WasmTypeList>(
+                self,
+                f: &NativeFunc<Self, Rets>,
+            ) -> Result<Rets, RuntimeError> {
+                let ($($arg),*) = self;
+                f.call($($arg),*)
+            }
+        }
+    };
+}
+
+impl_scalar_args!();
+impl_scalar_args!(A1, A2);
+impl_scalar_args!(A1, A2, A3);
+impl_scalar_args!(A1, A2, A3, A4);
+impl_scalar_args!(A1, A2, A3, A4, A5);
+impl_scalar_args!(A1, A2, A3, A4, A5, A6);
+impl_scalar_args!(A1, A2, A3, A4, A5, A6, A7);
+impl_scalar_args!(A1, A2, A3, A4, A5, A6, A7, A8);
+
+// A scalar export standing in for a function's regular export
+#[derive(Clone, Debug)]
+pub(crate) struct ScalarExport {
+    pub(crate) export_name: String,
+    pub(crate) kind: ScalarKind,
+}
+
+// Find the scalar exports in `exports`, keyed by the name of the regular
+// export each one stands in for. They are named
+// `wasm_plugin_exported_KIND__ORIGINAL_NAME`.
+pub(crate) fn scalar_exports(exports: &Exports) -> HashMap<String, ScalarExport> {
+    exports
+        .iter()
+        .filter(|(_, export)| matches!(export, Extern::Function(_)))
+        .filter_map(|(export_name, _)| {
+            let (kind, fn_name) = export_name
+                .strip_prefix("wasm_plugin_exported_")?
+                .split_once("__")?;
+            let kind = ScalarKind::from_name(kind)?;
+            Some((
+                format!("wasm_plugin_exported__{}", fn_name),
+                ScalarExport {
+                    export_name: export_name.clone(),
+                    kind,
+                },
+            ))
+        })
+        .collect()
+}
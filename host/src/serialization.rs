@@ -1,3 +1,5 @@
+use std::convert::TryFrom;
+
 use crate::errors;
 
 pub trait Serializable {
@@ -6,13 +8,24 @@ pub trait Serializable {
 #[cfg(feature = "serialize_bincode")]
 impl<T: serde::Serialize> Serializable for T {
     fn serialize(&self) -> errors::Result<Vec<u8>> {
-        bincode::serialize(self).map_err(|_| errors::WasmPluginError::SerializationError)
+        bincode::serialize(self)
+            .map_err(|e| errors::WasmPluginError::SerializationError(e.to_string()))
     }
 }
 #[cfg(feature = "serialize_json")]
 impl<T: serde::Serialize> Serializable for T {
     fn serialize(&self) -> errors::Result<Vec<u8>> {
-        serde_json::to_vec(self).map_err(|_| errors::WasmPluginError::SerializationError)
+        serde_json::to_vec(self)
+            .map_err(|e| errors::WasmPluginError::SerializationError(e.to_string()))
+    }
+}
+#[cfg(feature = "serialize_cbor")]
+impl<T: serde::Serialize> Serializable for T {
+    fn serialize(&self) -> errors::Result<Vec<u8>> {
+        let mut buffer = vec![];
+        ciborium::ser::into_writer(self, &mut buffer)
+            .map_err(|e| errors::WasmPluginError::SerializationError(e.to_string()))?;
+        Ok(buffer)
     }
 }
 #[cfg(feature = "serialize_nanoserde_json")]
@@ -21,30 +34,228 @@ impl<T: nanoserde::SerJson> Serializable for T {
         Ok(nanoserde::SerJson::serialize_json(self).as_bytes().to_vec())
     }
 }
+#[cfg(feature = "serialize_protobuf")]
+impl<T: prost::Message> Serializable for T {
+    fn serialize(&self) -> errors::Result<Vec<u8>> {
+        Ok(self.encode_to_vec())
+    }
+}
 
+/// Types which can be received from the plugin.
+///
+/// `Option<T>` can be used as an argument or return value like any other
+/// type. `None` and empty collections are always encoded as a non-empty
+/// message so they can't be mistaken for the empty message sent in place of
+/// zero sized values like `()`:
+///
+/// ```rust
+/// # use wasm_plugin_host::serialization::{Deserializable, Serializable};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let message = None::<Vec<u8>>.serialize()?;
+/// assert!(!message.is_empty());
+/// assert_eq!(Option::<Vec<u8>>::deserialize(&message)?, None);
+///
+/// let message = Vec::<u8>::new().serialize()?;
+/// assert!(!message.is_empty());
+/// assert_eq!(Vec::<u8>::deserialize(&message)?, Vec::<u8>::new());
+///
+/// let message = String::new().serialize()?;
+/// assert!(!message.is_empty());
+/// assert_eq!(String::deserialize(&message)?, "");
+///
+/// let message = Some(vec![1u8, 2, 3]).serialize()?;
+/// assert_eq!(Option::<Vec<u8>>::deserialize(&message)?, Some(vec![1, 2, 3]));
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Deserializing never trusts a length prefix in the data to decide how much
+/// to allocate, so a malicious message claiming to hold a huge collection
+/// fails cleanly rather than exhausting memory:
+///
+/// ```rust
+/// # use wasm_plugin_host::serialization::Deserializable;
+/// let mut message = u64::MAX.to_le_bytes().to_vec();
+/// message.extend_from_slice(&[0; 16]);
+/// assert!(Vec::<u64>::deserialize(&message).is_err());
+/// assert!(String::deserialize(&message).is_err());
+/// ```
+///
+/// Functions can return several values at once as a tuple, without wrapping
+/// them in a struct:
+///
+/// ```rust
+/// # use wasm_plugin_host::serialization::{Deserializable, Serializable};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let value = ("hello".to_string(), vec![1, -2, 3], true);
+/// let message = value.serialize()?;
+/// assert_eq!(<(String, Vec<i32>, bool)>::deserialize(&message)?, value);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// With the serde based formats `Result<T, E>` is a value like any other, so
+/// a function which may fail can return its error to the other side rather
+/// than trapping. Both variants survive the trip:
+///
+/// ```rust
+/// # use wasm_plugin_host::serialization::{Deserializable, Serializable};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let message = Ok::<u32, String>(7).serialize()?;
+/// assert_eq!(Result::<u32, String>::deserialize(&message)?, Ok(7));
+///
+/// let message = Err::<u32, String>("no such texture".to_string()).serialize()?;
+/// assert_eq!(
+///     Result::<u32, String>::deserialize(&message)?,
+///     Err("no such texture".to_string())
+/// );
+///
+/// // Zero sized success values still produce a message
+/// let message = Ok::<(), String>(()).serialize()?;
+/// assert!(!message.is_empty());
+/// assert_eq!(Result::<(), String>::deserialize(&message)?, Ok(()));
+/// # Ok(())
+/// # }
+/// ```
 pub trait Deserializable {
     fn deserialize(data: &[u8]) -> errors::Result<Self>
     where
         Self: Sized;
+
+    /// Deserialize `data` reading at most `limit` bytes of it, which also
+    /// bounds the size of any collections it claims to contain. Formats which
+    /// don't have such limits ignore it.
+    #[doc(hidden)]
+    fn deserialize_with_limit(data: &[u8], limit: u64) -> errors::Result<Self>
+    where
+        Self: Sized,
+    {
+        let _ = limit;
+        Self::deserialize(data)
+    }
+
+    /// Construct a zero sized value, such as `()`, for which the plugin
+    /// didn't write any data.
+    #[doc(hidden)]
+    fn deserialize_empty() -> errors::Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::deserialize(&[])
+    }
 }
 #[cfg(feature = "serialize_bincode")]
 impl<T: serde::de::DeserializeOwned + Clone> Deserializable for T {
     fn deserialize(data: &[u8]) -> errors::Result<Self> {
-        bincode::deserialize(data).map_err(|_| errors::WasmPluginError::DeserializationError)
+        Self::deserialize_with_limit(data, crate::DEFAULT_DESERIALIZATION_LIMIT)
+    }
+
+    fn deserialize_with_limit(data: &[u8], limit: u64) -> errors::Result<Self> {
+        use bincode::Options;
+        // The same encoding as `bincode::deserialize`, plus the limit
+        bincode::options()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(limit)
+            .deserialize(data)
+            .map_err(|e| errors::WasmPluginError::deserialization::<T>(data, e))
+    }
+
+    fn deserialize_empty() -> errors::Result<Self> {
+        deserialize_unit()
     }
 }
 #[cfg(feature = "serialize_json")]
 impl<T: serde::de::DeserializeOwned + Clone> Deserializable for T {
     fn deserialize(data: &[u8]) -> errors::Result<Self> {
-        serde_json::from_slice(data).map_err(|_| errors::WasmPluginError::DeserializationError)
+        serde_json::from_slice(data)
+            .map_err(|e| errors::WasmPluginError::deserialization::<T>(data, e))
     }
+
+    fn deserialize_empty() -> errors::Result<Self> {
+        deserialize_unit()
+    }
+}
+#[cfg(feature = "serialize_cbor")]
+impl<T: serde::de::DeserializeOwned + Clone> Deserializable for T {
+    fn deserialize(data: &[u8]) -> errors::Result<Self> {
+        ciborium::de::from_reader(data)
+            .map_err(|e| errors::WasmPluginError::deserialization::<T>(data, e))
+    }
+
+    fn deserialize_empty() -> errors::Result<Self> {
+        deserialize_unit()
+    }
+}
+#[cfg(any(
+    feature = "serialize_bincode",
+    feature = "serialize_json",
+    feature = "serialize_cbor"
+))]
+fn deserialize_unit<T: serde::de::DeserializeOwned>() -> errors::Result<T> {
+    use serde::de::IntoDeserializer;
+    let deserializer: serde::de::value::UnitDeserializer<serde::de::value::Error> =
+        ().into_deserializer();
+    T::deserialize(deserializer).map_err(|e| errors::WasmPluginError::deserialization::<T>(&[], e))
 }
 #[cfg(feature = "serialize_nanoserde_json")]
 impl<T: nanoserde::DeJson> Deserializable for T {
     fn deserialize(data: &[u8]) -> errors::Result<Self> {
         nanoserde::DeJson::deserialize_json(
-            std::str::from_utf8(data).map_err(|_| errors::WasmPluginError::DeserializationError)?,
+            std::str::from_utf8(data)
+                .map_err(|e| errors::WasmPluginError::deserialization::<T>(data, e))?,
         )
-        .map_err(|_| errors::WasmPluginError::DeserializationError)
+        .map_err(|e| errors::WasmPluginError::deserialization::<T>(data, e))
+    }
+}
+#[cfg(feature = "serialize_protobuf")]
+impl<T: prost::Message + Default> Deserializable for T {
+    fn deserialize(data: &[u8]) -> errors::Result<Self> {
+        T::decode(data).map_err(|e| errors::WasmPluginError::deserialization::<T>(data, e))
+    }
+}
+
+/// Join separately serialized arguments into one message for a function
+/// exported with `#[export_function(argument_list)]`. Each argument is
+/// preceded by its length as a little endian `u32`.
+pub(crate) fn frame_arguments(args: &[Vec<u8>]) -> errors::Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(args.iter().map(|arg| arg.len() + 4).sum());
+    for arg in args {
+        let len =
+            u32::try_from(arg.len()).map_err(|_| errors::WasmPluginError::MessageTooLarge {
+                size: arg.len(),
+                limit: u32::MAX as usize,
+            })?;
+        message.extend_from_slice(&len.to_le_bytes());
+        message.extend_from_slice(arg);
+    }
+    Ok(message)
+}
+
+/// Deserialize the value returned by a call to a plugin function.
+///
+/// An empty message is only valid for zero sized return types, which are
+/// constructed without going through the wire format. Every other type,
+/// including `None` and empty collections, is serialized to a non-empty
+/// message so an empty one means the plugin function didn't return the value
+/// the call site expected.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+#[doc(hidden)]
+pub fn deserialize_return<T: Deserializable>(
+    fn_name: &str,
+    data: &[u8],
+    limit: u64,
+) -> errors::Result<T> {
+    let result = if !data.is_empty() {
+        T::deserialize_with_limit(data, limit)
+    } else if std::mem::size_of::<T>() == 0 {
+        T::deserialize_empty()
+    } else {
+        Err(errors::WasmPluginError::EmptyReturnValue)
+    };
+    #[cfg(feature = "tracing")]
+    if let Err(e) = &result {
+        tracing::warn!(fn_name, error = %e, "failed to deserialize return value");
     }
+    result
 }
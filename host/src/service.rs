@@ -0,0 +1,115 @@
+//! Run a plugin on a thread of its own and call it from anywhere.
+
+use std::{sync::mpsc, thread};
+
+use crate::{
+    errors,
+    serialization::{self, Deserializable, Serializable},
+    WasmPlugin,
+};
+
+struct Request {
+    fn_name: String,
+    message: Option<Vec<u8>>,
+    response: mpsc::Sender<errors::Result<Vec<u8>>>,
+}
+
+/// A handle to a plugin which runs on a dedicated thread.
+///
+/// The plugin is created and owned by that thread so it never has to cross
+/// threads itself. Handles are cheap to clone and can be sent anywhere; each
+/// call serializes its argument, sends it to the plugin's thread and waits for
+/// the response. Calls from different handles are run one at a time in the
+/// order they arrive.
+///
+/// The thread stops once every handle has been dropped.
+///
+/// ```rust,no_run
+/// # use wasm_plugin_host::{PluginService, WasmPluginBuilder};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let service = PluginService::spawn(|| {
+///     WasmPluginBuilder::from_file("path/to/plugin.wasm")?.finish()
+/// })?;
+/// let worker = service.clone();
+/// std::thread::spawn(move || {
+///     let response: String = worker.call("hello", &"world".to_string()).unwrap();
+/// });
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct PluginService {
+    sender: mpsc::Sender<Request>,
+    deserialization_limit: u64,
+}
+
+impl PluginService {
+    /// Start a thread, create the plugin on it with `load` and return a
+    /// handle to it. Any error from `load` is returned here.
+    pub fn spawn(
+        load: impl FnOnce() -> errors::Result<WasmPlugin> + Send + 'static,
+    ) -> errors::Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Request>();
+        let (loaded_sender, loaded) = mpsc::channel();
+        thread::spawn(move || {
+            let plugin = match load() {
+                Ok(plugin) => {
+                    let _ = loaded_sender.send(Ok(plugin.deserialization_limit()));
+                    plugin
+                }
+                Err(e) => {
+                    let _ = loaded_sender.send(Err(e));
+                    return;
+                }
+            };
+            for request in receiver {
+                let response =
+                    plugin.call_function_with_message(&request.fn_name, request.message.as_deref());
+                // The caller may have given up waiting, which is fine
+                let _ = request.response.send(response);
+            }
+        });
+        let deserialization_limit = loaded
+            .recv()
+            .map_err(|_| errors::WasmPluginError::ServiceStopped)??;
+        Ok(Self {
+            sender,
+            deserialization_limit,
+        })
+    }
+
+    /// Call a function exported by the plugin with a single argument. See
+    /// `WasmPlugin::call_function_with_argument`.
+    pub fn call<ReturnType, Args>(&self, fn_name: &str, args: &Args) -> errors::Result<ReturnType>
+    where
+        Args: Serializable,
+        ReturnType: Deserializable,
+    {
+        let buff = self.request(fn_name, Some(args.serialize()?))?;
+        serialization::deserialize_return(fn_name, &buff, self.deserialization_limit)
+    }
+
+    /// Call a function exported by the plugin which takes no arguments. See
+    /// `WasmPlugin::call_function`.
+    pub fn call_without_argument<ReturnType>(&self, fn_name: &str) -> errors::Result<ReturnType>
+    where
+        ReturnType: Deserializable,
+    {
+        let buff = self.request(fn_name, None)?;
+        serialization::deserialize_return(fn_name, &buff, self.deserialization_limit)
+    }
+
+    fn request(&self, fn_name: &str, message: Option<Vec<u8>>) -> errors::Result<Vec<u8>> {
+        let (response, receiver) = mpsc::channel();
+        self.sender
+            .send(Request {
+                fn_name: fn_name.to_string(),
+                message,
+                response,
+            })
+            .map_err(|_| errors::WasmPluginError::ServiceStopped)?;
+        receiver
+            .recv()
+            .map_err(|_| errors::WasmPluginError::ServiceStopped)?
+    }
+}
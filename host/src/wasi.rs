@@ -0,0 +1,58 @@
+//! Configuration for plugins compiled for `wasm32-wasi`.
+
+use std::path::PathBuf;
+
+use wasmer::{ImportObject, Module};
+use wasmer_wasi::WasiState;
+
+use crate::errors;
+
+/// The WASI environment provided to a plugin built with
+/// `WasmPluginBuilder::with_wasi`.
+#[derive(Clone, Debug)]
+pub struct WasiConfig {
+    program_name: String,
+    args: Vec<String>,
+    envs: Vec<(String, String)>,
+    preopen_dirs: Vec<PathBuf>,
+}
+
+impl WasiConfig {
+    /// Create an empty configuration. `program_name` is passed to the plugin
+    /// as its first argument.
+    pub fn new(program_name: impl Into<String>) -> Self {
+        Self {
+            program_name: program_name.into(),
+            args: vec![],
+            envs: vec![],
+            preopen_dirs: vec![],
+        }
+    }
+
+    /// Add a command line argument.
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Set an environment variable.
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.envs.push((key.into(), value.into()));
+        self
+    }
+
+    /// Give the plugin read and write access to a directory on the host.
+    pub fn preopen_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.preopen_dirs.push(path.into());
+        self
+    }
+
+    pub(crate) fn import_object(&self, module: &Module) -> errors::Result<ImportObject> {
+        let mut state = WasiState::new(&self.program_name);
+        state
+            .args(&self.args)
+            .envs(self.envs.iter().map(|(k, v)| (k, v)))
+            .preopen_dirs(&self.preopen_dirs)?;
+        Ok(state.finalize()?.import_object(module)?)
+    }
+}
@@ -0,0 +1,54 @@
+//! Zero copy access to values returned by plugins, using rkyv.
+
+use rkyv::{api::high::HighValidator, bytecheck::CheckBytes, rancor, util::AlignedVec, Archive};
+
+use crate::errors;
+
+/// The archived form of a `T` returned by `WasmPlugin::call_function_zero_copy`.
+/// It dereferences to `T::Archived`, which can be read in place without
+/// deserializing the whole value.
+///
+/// The archive is copied out of the plugin's memory into an aligned buffer
+/// owned by the `ZeroCopy` and validated before it is handed out, so a
+/// misbehaving plugin can't produce an archive which reads out of bounds. It
+/// stays valid however the plugin is used afterwards.
+///
+/// The plugin must archive the same type with a compatible rkyv version and
+/// the same format features, which is what `wasm_plugin_guest::ZeroCopy`
+/// does.
+pub struct ZeroCopy<T: Archive> {
+    bytes: AlignedVec,
+    _phantom: std::marker::PhantomData<T>,
+}
+
+impl<T> ZeroCopy<T>
+where
+    T: Archive,
+    T::Archived: for<'a> CheckBytes<HighValidator<'a, rancor::Error>>,
+{
+    pub(crate) fn new(data: &[u8]) -> errors::Result<Self> {
+        let mut bytes = AlignedVec::with_capacity(data.len());
+        bytes.extend_from_slice(data);
+        rkyv::access::<T::Archived, rancor::Error>(&bytes)
+            .map_err(|e| errors::WasmPluginError::deserialization::<T>(data, e))?;
+        Ok(ZeroCopy {
+            bytes,
+            _phantom: Default::default(),
+        })
+    }
+
+    /// The archive's raw bytes
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl<T: Archive> std::ops::Deref for ZeroCopy<T> {
+    type Target = T::Archived;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The bytes were validated as a `T::Archived` when the
+        // `ZeroCopy` was created and haven't been modified since
+        unsafe { rkyv::access_unchecked::<T::Archived>(&self.bytes) }
+    }
+}
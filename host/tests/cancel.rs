@@ -0,0 +1,74 @@
+#![cfg(feature = "serialize_bincode")]
+
+mod common;
+
+use std::time::Duration;
+
+use wasm_plugin_host::{errors::WasmPluginError, CancelToken};
+
+// `spin` never calls an import so it can only be stopped by interrupting it
+const PLUGIN: &str = r#"
+  (func (export "wasm_plugin_exported__spin") (result i64)
+    (loop (br 0))
+    i64.const 0)
+  ;; Returns 42 as a bincode encoded i32
+  (func (export "wasm_plugin_exported__answer") (result i64)
+    (local $ptr i32)
+    i32.const 4
+    call $alloc
+    local.tee $ptr
+    i32.const 42
+    i32.store
+    local.get $ptr
+    i64.extend_i32_u
+    i64.const 0x400000000
+    i64.or)
+"#;
+
+fn cancel_soon() -> CancelToken {
+    let token = CancelToken::new();
+    let canceller = token.clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        canceller.cancel();
+    });
+    token
+}
+
+#[test]
+fn a_plugin_which_never_calls_an_import_is_stopped() {
+    let mut plugin = common::plugin(PLUGIN);
+    let result = plugin.call_function_with_cancel_token::<i32>("spin", &cancel_soon());
+    assert!(
+        matches!(result, Err(WasmPluginError::Cancelled)),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn a_cancelled_plugin_can_still_be_called() {
+    let mut plugin = common::plugin(PLUGIN);
+    let _ = plugin.call_function_with_cancel_token::<i32>("spin", &cancel_soon());
+    let answer: i32 = plugin.call_function("answer").unwrap();
+    assert_eq!(answer, 42);
+    let answer: i32 = plugin
+        .call_function_with_cancel_token("answer", &CancelToken::new())
+        .unwrap();
+    assert_eq!(answer, 42);
+}
+
+#[test]
+fn a_token_cancelled_up_front_is_reported_as_cancelled() {
+    let mut plugin = common::plugin(PLUGIN);
+    let token = CancelToken::new();
+    token.cancel();
+    for _ in 0..2 {
+        let result = plugin.call_function_with_cancel_token::<i32>("answer", &token);
+        assert!(
+            matches!(result, Err(WasmPluginError::Cancelled)),
+            "{:?}",
+            result
+        );
+    }
+}
@@ -0,0 +1,81 @@
+// Shared support for the integration tests, which run small plugins written
+// in WAT rather than building guest crates to WASM.
+
+#![allow(dead_code)]
+
+use wasm_plugin_host::{WasmPlugin, WasmPluginBuilder};
+
+// Wasmer 1.0's VM links against the stack probe which older versions of the
+// standard library exported. Provide one so the test binaries link.
+#[cfg(target_arch = "x86_64")]
+#[unsafe(naked)]
+#[no_mangle]
+pub unsafe extern "C" fn __rust_probestack() {
+    core::arch::naked_asm!(
+        "push rbp",
+        "mov rbp, rsp",
+        "mov r11, rax",
+        "cmp r11, 0x1000",
+        "jbe 3f",
+        "2:",
+        "sub rsp, 0x1000",
+        "test qword ptr [rsp], rsp",
+        "sub r11, 0x1000",
+        "cmp r11, 0x1000",
+        "ja 2b",
+        "3:",
+        "sub rsp, r11",
+        "test qword ptr [rsp], rsp",
+        "add rsp, rax",
+        "leave",
+        "ret",
+    )
+}
+
+// The exports every plugin needs. Messages are allocated with a bump
+// allocator starting at 1024, which grows the memory as needed and starts
+// again from the beginning once every buffer has been freed. The numbers of
+// buffers allocated and freed are exported as the globals `allocs` and
+// `frees`.
+const RUNTIME: &str = r#"
+  (memory (export "memory") 1)
+  (global $next (mut i32) (i32.const 1024))
+  (global $live (mut i32) (i32.const 0))
+  (global $allocs (export "allocs") (mut i32) (i32.const 0))
+  (global $frees (export "frees") (mut i32) (i32.const 0))
+  (func $alloc (export "allocate_message_buffer") (param $len i32) (result i32)
+    (local $ptr i32)
+    (global.set $allocs (i32.add (global.get $allocs) (i32.const 1)))
+    (local.set $ptr (global.get $next))
+    (global.set $next (i32.add (local.get $ptr) (local.get $len)))
+    (global.set $live (i32.add (global.get $live) (i32.const 1)))
+    (if (i32.gt_u (global.get $next) (i32.mul (memory.size) (i32.const 65536)))
+      (then
+        (drop (memory.grow
+          (i32.add
+            (i32.shr_u
+              (i32.sub (global.get $next) (i32.mul (memory.size) (i32.const 65536)))
+              (i32.const 16))
+            (i32.const 1))))))
+    (local.get $ptr))
+  (func (export "free_message_buffer") (param i32 i32)
+    (global.set $frees (i32.add (global.get $frees) (i32.const 1)))
+    (global.set $live (i32.sub (global.get $live) (i32.const 1)))
+    (if (i32.eqz (global.get $live))
+      (then (global.set $next (i32.const 1024)))))
+"#;
+
+// WAT for a module containing the runtime exports followed by `body`, which
+// can use `$alloc` to allocate messages
+pub fn module(imports: &str, body: &str) -> String {
+    format!("(module {} {} {})", imports, RUNTIME, body)
+}
+
+// A builder for a plugin made from `body`, see `module`
+pub fn builder(imports: &str, body: &str) -> WasmPluginBuilder {
+    WasmPluginBuilder::from_source(module(imports, body).as_bytes()).unwrap()
+}
+
+pub fn plugin(body: &str) -> WasmPlugin {
+    builder("", body).finish().unwrap()
+}
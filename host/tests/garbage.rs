@@ -0,0 +1,87 @@
+#![cfg(feature = "serialize_bincode")]
+
+mod common;
+
+use wasm_plugin_host::WasmPluginBuilder;
+
+#[test]
+fn nothing_is_pending_after_a_call() {
+    let mut plugin = common::builder(
+        r#"(import "env" "wasm_plugin_imported__value" (func $value (result i64)))"#,
+        r#"(func (export "wasm_plugin_exported__echo") (result i64)
+             call $value)"#,
+    )
+    .import_function("value", || 42u32)
+    .finish()
+    .unwrap();
+    let _: u32 = plugin.call_function("echo").unwrap();
+    assert_eq!(plugin.pending_garbage_count(), 0);
+}
+
+// Calling a `NativeFunc` directly happens outside of any call the host
+// knows about
+#[test]
+fn a_message_returned_outside_of_a_call_is_collected() {
+    let mut plugin = common::builder(
+        r#"(import "env" "wasm_plugin_imported__value" (func $value (result i64)))"#,
+        r#"(func (export "get") (result i32)
+             call $value
+             i32.wrap_i64)"#,
+    )
+    .import_function("value", || 42u32)
+    .finish()
+    .unwrap();
+    let get = plugin.scalar_function::<(), i32>("get").unwrap();
+    get.native().call().unwrap();
+    assert_eq!(plugin.pending_garbage_count(), 1);
+    plugin.collect_garbage().unwrap();
+    assert_eq!(plugin.pending_garbage_count(), 0);
+    let frees: i32 = plugin.get_global("frees").unwrap();
+    assert_eq!(frees, 1);
+}
+
+// A plugin whose `free_message_buffer` traps while `broken` is set, and
+// whose `twice` function gets two messages from an import
+const BREAKABLE: &str = r#"
+(module
+  (import "env" "wasm_plugin_imported__value" (func $value (result i64)))
+  (memory (export "memory") 1)
+  (global $next (mut i32) (i32.const 1024))
+  (global (export "broken") (mut i32) (i32.const 0))
+  (global $frees (export "frees") (mut i32) (i32.const 0))
+  (func (export "allocate_message_buffer") (param $len i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (global.get $next))
+    (global.set $next (i32.add (local.get $ptr) (local.get $len)))
+    (local.get $ptr))
+  (func (export "free_message_buffer") (param i32 i32)
+    (if (global.get 1) (then unreachable))
+    (global.set $frees (i32.add (global.get $frees) (i32.const 1))))
+  (func (export "wasm_plugin_exported__twice") (result i64)
+    call $value
+    drop
+    call $value))
+"#;
+
+#[test]
+fn buffers_which_could_not_be_freed_are_collected_later() {
+    let mut plugin = WasmPluginBuilder::from_source(BREAKABLE.as_bytes())
+        .unwrap()
+        .import_function("value", || 42u32)
+        .finish()
+        .unwrap();
+    plugin.set_global("broken", 1).unwrap();
+    assert!(plugin.call_function::<u32>("twice").is_err());
+    // The first buffer trapped, the second is still waiting
+    assert_eq!(plugin.pending_garbage_count(), 1);
+    assert!(plugin.collect_garbage().is_err());
+    assert_eq!(plugin.pending_garbage_count(), 0);
+
+    plugin.set_global("broken", 1).unwrap();
+    assert!(plugin.call_function::<u32>("twice").is_err());
+    plugin.set_global("broken", 0).unwrap();
+    plugin.collect_garbage().unwrap();
+    assert_eq!(plugin.pending_garbage_count(), 0);
+    let frees: i32 = plugin.get_global("frees").unwrap();
+    assert_eq!(frees, 1);
+}
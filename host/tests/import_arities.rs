@@ -0,0 +1,77 @@
+#![cfg(feature = "serialize_bincode")]
+
+mod common;
+
+use wasm_plugin_host::WasmPluginBuilder;
+
+// Each export calls an import with a bincode encoded tuple of arguments
+// from a data segment and returns whatever the import returned
+const IMPORTS: &str = r#"
+  (import "env" "wasm_plugin_imported__zero" (func $zero (result i64)))
+  (import "env" "wasm_plugin_imported__one" (func $one (param i32 i32) (result i64)))
+  (import "env" "wasm_plugin_imported__two" (func $two (param i32 i32) (result i64)))
+  (import "env" "wasm_plugin_imported__three" (func $three (param i32 i32) (result i64)))
+  (import "env" "wasm_plugin_imported__four" (func $four (param i32 i32) (result i64)))
+"#;
+
+const PLUGIN: &str = r#"
+  ;; 5u32
+  (data (i32.const 256) "\05\00\00\00")
+  ;; ("ab".to_string(), 3u32)
+  (data (i32.const 320) "\02\00\00\00\00\00\00\00ab\03\00\00\00")
+  ;; (2u32, 3u32, 4u32)
+  (data (i32.const 384) "\02\00\00\00\03\00\00\00\04\00\00\00")
+  ;; (1u8, 2u16, 3u32, 4u64)
+  (data (i32.const 448) "\01\02\00\03\00\00\00\04\00\00\00\00\00\00\00")
+  (func (export "wasm_plugin_exported__zero") (result i64)
+    call $zero)
+  (func (export "wasm_plugin_exported__one") (result i64)
+    (call $one (i32.const 256) (i32.const 4)))
+  (func (export "wasm_plugin_exported__two") (result i64)
+    (call $two (i32.const 320) (i32.const 14)))
+  (func (export "wasm_plugin_exported__three") (result i64)
+    (call $three (i32.const 384) (i32.const 12)))
+  (func (export "wasm_plugin_exported__four") (result i64)
+    (call $four (i32.const 448) (i32.const 15)))
+"#;
+
+fn builder() -> WasmPluginBuilder {
+    common::builder(IMPORTS, PLUGIN)
+        .import_function("zero", || 10u32)
+        .import_function("one", |a: u32| a * 2)
+        .import_function("two", |s: String, n: u32| s.repeat(n as usize))
+        .import_function("three", |a: u32, b: u32, c: u32| a * b * c)
+        .import_function("four", |a: u8, b: u16, c: u32, d: u64| {
+            a as u64 + b as u64 + c as u64 + d
+        })
+}
+
+#[test]
+fn an_import_can_take_no_arguments() {
+    let mut plugin = builder().finish().unwrap();
+    assert_eq!(plugin.call_function::<u32>("zero").unwrap(), 10);
+}
+
+#[test]
+fn an_import_can_take_one_argument() {
+    let mut plugin = builder().finish().unwrap();
+    assert_eq!(plugin.call_function::<u32>("one").unwrap(), 10);
+}
+
+#[test]
+fn an_import_can_take_two_arguments() {
+    let mut plugin = builder().finish().unwrap();
+    assert_eq!(plugin.call_function::<String>("two").unwrap(), "ababab");
+}
+
+#[test]
+fn an_import_can_take_three_arguments() {
+    let mut plugin = builder().finish().unwrap();
+    assert_eq!(plugin.call_function::<u32>("three").unwrap(), 24);
+}
+
+#[test]
+fn an_import_can_take_four_arguments() {
+    let mut plugin = builder().finish().unwrap();
+    assert_eq!(plugin.call_function::<u64>("four").unwrap(), 10);
+}
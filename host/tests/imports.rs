@@ -0,0 +1,55 @@
+mod common;
+
+use wasm_plugin_host::errors::WasmPluginError;
+
+fn blend(a: u32, b: u32) -> u32 {
+    (a >> 1) + (b >> 1)
+}
+
+#[test]
+fn importing_a_native_function_twice_is_reported() {
+    let result = common::builder("", "")
+        .import_native_function("blend", blend)
+        .import_native_function("blend", |a: u32, _b: u32| a)
+        .finish();
+    assert!(
+        matches!(&result, Err(WasmPluginError::DuplicateImport(name)) if name == "env.blend"),
+        "{:?}",
+        result.err()
+    );
+}
+
+#[test]
+fn a_native_function_is_called_directly() {
+    let mut plugin = common::builder(
+        r#"(import "env" "blend" (func $blend (param i32 i32) (result i32)))"#,
+        r#"(func (export "run") (param i32 i32) (result i32)
+             local.get 0
+             local.get 1
+             call $blend)"#,
+    )
+    .import_native_function("blend", blend)
+    .finish()
+    .unwrap();
+    let blended: i32 = plugin.call_function_scalar("run", (10i32, 20i32)).unwrap();
+    assert_eq!(blended, 15);
+}
+
+// The plugin returns the message the import sent it as its own return value,
+// so the same buffer is both garbage from the import and the call's result
+#[cfg(feature = "serialize_bincode")]
+#[test]
+fn an_import_result_echoed_back_by_the_plugin_is_read_before_being_freed() {
+    let mut plugin = common::builder(
+        r#"(import "env" "wasm_plugin_imported__value" (func $value (result i64)))"#,
+        r#"(func (export "wasm_plugin_exported__echo") (result i64)
+             call $value)"#,
+    )
+    .import_function("value", || 42u32)
+    .finish()
+    .unwrap();
+    let echoed: u32 = plugin.call_function("echo").unwrap();
+    assert_eq!(echoed, 42);
+    let frees: i32 = plugin.get_global("frees").unwrap();
+    assert_eq!(frees, 1, "the buffer should be freed exactly once");
+}
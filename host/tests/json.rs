@@ -0,0 +1,77 @@
+#![cfg(feature = "serialize_json")]
+
+mod common;
+
+use wasm_plugin_host::serialization::{Deserializable, Serializable};
+
+// The same fixture is checked against wasm_plugin_guest's serialization in
+// its own tests, so both sides agree on the encoding
+const FIXTURE: &str =
+    r#"{"name":"intro","layers":[{"id":1,"tags":["sky"]},{"id":2,"tags":[]}],"origin":[3,-4]}"#;
+
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+struct Scene {
+    name: String,
+    layers: Vec<Layer>,
+    origin: Option<(i32, i32)>,
+}
+
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+struct Layer {
+    id: u32,
+    tags: Vec<String>,
+}
+
+fn scene() -> Scene {
+    Scene {
+        name: "intro".to_string(),
+        layers: vec![
+            Layer {
+                id: 1,
+                tags: vec!["sky".to_string()],
+            },
+            Layer {
+                id: 2,
+                tags: vec![],
+            },
+        ],
+        origin: Some((3, -4)),
+    }
+}
+
+// Copies its argument into a new message and returns it
+const ECHO: &str = r#"
+  (func (export "wasm_plugin_exported__echo") (param $ptr i32) (param $len i32) (result i64)
+    (local $out i32)
+    (local $i i32)
+    (local.set $out (call $alloc (local.get $len)))
+    (block $done
+      (loop $copy
+        (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+        (i32.store8
+          (i32.add (local.get $out) (local.get $i))
+          (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $copy)))
+    (i64.or
+      (i64.shl (i64.extend_i32_u (local.get $len)) (i64.const 32))
+      (i64.extend_i32_u (local.get $out))))
+"#;
+
+#[test]
+fn a_nested_struct_is_encoded_as_json() {
+    assert_eq!(
+        String::from_utf8(scene().serialize().unwrap()).unwrap(),
+        FIXTURE
+    );
+    assert_eq!(Scene::deserialize(FIXTURE.as_bytes()).unwrap(), scene());
+}
+
+#[test]
+fn a_nested_struct_round_trips_through_a_plugin() {
+    let plugin = common::plugin(ECHO);
+    let echoed: Scene = plugin
+        .call_function_with_argument("echo", &scene())
+        .unwrap();
+    assert_eq!(echoed, scene());
+}
@@ -0,0 +1,102 @@
+#![cfg(feature = "serialize_bincode")]
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use wasm_plugin_host::{errors::WasmPluginError, PluginMetrics};
+
+const PLUGIN: &str = r#"
+  (func (export "wasm_plugin_exported__spin") (result i64)
+    (loop (br 0))
+    i64.const 0)
+  (func (export "wasm_plugin_exported__echo") (param $ptr i32) (param $len i32) (result i64)
+    (local $out i32)
+    (local $i i32)
+    (local.set $out (call $alloc (local.get $len)))
+    (block $done
+      (loop $copy
+        (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+        (i32.store8
+          (i32.add (local.get $out) (local.get $i))
+          (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $copy)))
+    (i64.or
+      (i64.shl (i64.extend_i32_u (local.get $len)) (i64.const 32))
+      (i64.extend_i32_u (local.get $out))))
+"#;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Event {
+    Call(String, usize, usize),
+    Error(String, String),
+}
+
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<Event>>>);
+
+impl PluginMetrics for Recorder {
+    fn on_call(&self, fn_name: &str, _duration: Duration, payload_in: usize, payload_out: usize) {
+        self.0
+            .lock()
+            .unwrap()
+            .push(Event::Call(fn_name.to_string(), payload_in, payload_out));
+    }
+
+    fn on_error(&self, fn_name: &str, error: &WasmPluginError) {
+        self.0
+            .lock()
+            .unwrap()
+            .push(Event::Error(fn_name.to_string(), error.to_string()));
+    }
+}
+
+fn plugin(recorder: &Recorder) -> wasm_plugin_host::WasmPlugin {
+    common::builder("", PLUGIN)
+        .with_metrics(recorder.clone())
+        .finish()
+        .unwrap()
+}
+
+#[test]
+fn a_timed_out_call_is_reported_as_an_error() {
+    let recorder = Recorder::default();
+    let mut plugin = plugin(&recorder);
+    let _ = plugin.call_function_with_timeout::<i32>("spin", Duration::from_millis(50));
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        vec![Event::Error(
+            "spin".to_string(),
+            WasmPluginError::Timeout.to_string()
+        )]
+    );
+}
+
+#[test]
+fn each_call_in_a_batch_is_reported() {
+    let recorder = Recorder::default();
+    let plugin = plugin(&recorder);
+    let echoed: Vec<u32> = plugin.call_function_batch("echo", &[1u32, 2, 3]).unwrap();
+    assert_eq!(echoed, vec![1, 2, 3]);
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        vec![Event::Call("echo".to_string(), 4, 4); 3]
+    );
+}
+
+#[test]
+fn a_batch_of_a_missing_function_is_reported_as_an_error() {
+    let recorder = Recorder::default();
+    let plugin = plugin(&recorder);
+    assert!(plugin
+        .call_function_batch::<u32, u32>("missing", &[1])
+        .is_err());
+    let events = recorder.0.lock().unwrap();
+    assert!(
+        matches!(events.as_slice(), [Event::Error(name, _)] if name == "missing"),
+        "{:?}",
+        events
+    );
+}
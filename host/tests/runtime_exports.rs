@@ -0,0 +1,50 @@
+mod common;
+
+use wasm_plugin_host::{errors::WasmPluginError, WasmPluginBuilder};
+
+fn finish(wat: &str) -> Result<(), WasmPluginError> {
+    WasmPluginBuilder::from_source(wat.as_bytes())?
+        .finish()
+        .map(|_| ())
+}
+
+// The name of the missing export `finish` reported
+fn missing_export(result: Result<(), WasmPluginError>) -> Option<String> {
+    match result {
+        Err(WasmPluginError::MissingRuntimeExport(name)) => Some(name),
+        _ => None,
+    }
+}
+
+#[test]
+fn a_plugin_with_the_runtime_exports_loads() {
+    finish(&common::module("", "")).unwrap();
+}
+
+#[test]
+fn an_allocator_with_the_wrong_signature_is_rejected() {
+    let result = finish(
+        r#"(module
+          (memory (export "memory") 1)
+          (func (export "allocate_message_buffer") (param i64) (result i32) i32.const 0)
+          (func (export "free_message_buffer") (param i32 i32)))"#,
+    );
+    assert_eq!(
+        missing_export(result).as_deref(),
+        Some("allocate_message_buffer")
+    );
+}
+
+#[test]
+fn a_free_function_with_the_wrong_signature_is_rejected() {
+    let result = finish(
+        r#"(module
+          (memory (export "memory") 1)
+          (func (export "allocate_message_buffer") (param i32) (result i32) i32.const 0)
+          (func (export "free_message_buffer") (param i32)))"#,
+    );
+    assert_eq!(
+        missing_export(result).as_deref(),
+        Some("free_message_buffer")
+    );
+}
@@ -0,0 +1,48 @@
+mod common;
+
+use wasm_plugin_host::errors::WasmPluginError;
+
+const PLUGIN: &str = r#"
+  (func (export "add") (param i32 i32) (result i32)
+    local.get 0
+    local.get 1
+    i32.add)
+  (func (export "sqrt") (param f64) (result f64)
+    local.get 0
+    f64.sqrt)
+  (func (export "seven") (result i64)
+    i64.const 7)
+"#;
+
+#[test]
+fn scalar_functions_of_different_arities_can_be_called() {
+    let mut plugin = common::plugin(PLUGIN);
+    let sum: i32 = plugin.call_function_scalar("add", (2i32, 3i32)).unwrap();
+    assert_eq!(sum, 5);
+    let root: f64 = plugin.call_function_scalar("sqrt", 9.0f64).unwrap();
+    assert_eq!(root, 3.0);
+    let seven: i64 = plugin.call_function_scalar("seven", ()).unwrap();
+    assert_eq!(seven, 7);
+}
+
+#[test]
+fn a_scalar_function_can_be_kept_and_called_repeatedly() {
+    let plugin = common::plugin(PLUGIN);
+    let add = plugin.scalar_function::<(i32, i32), i32>("add").unwrap();
+    for i in 0..100 {
+        assert_eq!(add.call((i, 1)).unwrap(), i + 1);
+    }
+}
+
+#[test]
+fn calling_a_scalar_function_with_the_wrong_types_is_a_type_mismatch() {
+    let mut plugin = common::plugin(PLUGIN);
+    let result = plugin.call_function_scalar::<_, i32>("add", (2i64, 3i64));
+    match result {
+        Err(WasmPluginError::TypeMismatch { name, found, .. }) => {
+            assert_eq!(name, "add");
+            assert_eq!(found, "[I32, I32] -> [I32]");
+        }
+        result => panic!("expected a type mismatch, got {:?}", result),
+    }
+}
@@ -0,0 +1,48 @@
+#![cfg(feature = "serialize_bincode")]
+
+mod common;
+
+use std::time::Duration;
+
+use wasm_plugin_host::errors::WasmPluginError;
+
+const PLUGIN: &str = r#"
+  (func (export "wasm_plugin_exported__spin") (result i64)
+    (loop (br 0))
+    i64.const 0)
+  ;; Returns 42 as a bincode encoded i32
+  (func (export "wasm_plugin_exported__answer") (result i64)
+    (local $ptr i32)
+    i32.const 4
+    call $alloc
+    local.tee $ptr
+    i32.const 42
+    i32.store
+    local.get $ptr
+    i64.extend_i32_u
+    i64.const 0x400000000
+    i64.or)
+"#;
+
+#[test]
+fn a_plugin_stuck_in_a_loop_is_stopped() {
+    let mut plugin = common::plugin(PLUGIN);
+    let result = plugin.call_function_with_timeout::<i32>("spin", Duration::from_millis(50));
+    assert!(
+        matches!(result, Err(WasmPluginError::Timeout)),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn a_stopped_plugin_can_still_be_called() {
+    let mut plugin = common::plugin(PLUGIN);
+    let _ = plugin.call_function_with_timeout::<i32>("spin", Duration::from_millis(50));
+    let answer: i32 = plugin.call_function("answer").unwrap();
+    assert_eq!(answer, 42);
+    let answer: i32 = plugin
+        .call_function_with_timeout("answer", Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(answer, 42);
+}
@@ -0,0 +1,80 @@
+#![cfg(all(feature = "tracing", feature = "serialize_bincode"))]
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use tracing::{span, Subscriber};
+use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
+use wasm_plugin_host::WasmPluginBuilder;
+
+// The name of a span and the name of its parent
+type SpanName = (&'static str, Option<&'static str>);
+
+// Records every span created
+#[derive(Clone, Default)]
+struct Spans(Arc<Mutex<Vec<SpanName>>>);
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Spans {
+    fn on_new_span(&self, _attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).unwrap();
+        let parent = span.parent().map(|parent| parent.name());
+        self.0.lock().unwrap().push((span.name(), parent));
+    }
+}
+
+fn value_plugin(builder: impl FnOnce(WasmPluginBuilder) -> WasmPluginBuilder) -> u32 {
+    let mut plugin = builder(common::builder(
+        r#"(import "env" "wasm_plugin_imported__value" (func $value (result i64)))"#,
+        r#"(func (export "wasm_plugin_exported__echo") (result i64)
+             call $value)"#,
+    ))
+    .import_function("value", || 42u32)
+    .finish()
+    .unwrap();
+    plugin.call_function("echo").unwrap()
+}
+
+// Call the plugin and return the spans created during the call
+fn traced_call(builder: impl FnOnce(WasmPluginBuilder) -> WasmPluginBuilder) -> Vec<SpanName> {
+    let spans = Spans::default();
+    let subscriber = tracing_subscriber::registry().with(spans.clone());
+    let value = tracing::subscriber::with_default(subscriber, || value_plugin(builder));
+    assert_eq!(value, 42);
+    let spans = spans.0.lock().unwrap().clone();
+    spans
+}
+
+#[test]
+fn an_import_can_be_called_with_the_tracing_feature() {
+    assert_eq!(value_plugin(|builder| builder), 42);
+}
+
+#[test]
+fn a_traced_import_can_be_called() {
+    assert_eq!(value_plugin(|builder| builder.with_import_tracing()), 42);
+}
+
+#[test]
+fn a_traced_import_runs_in_a_span_inside_the_call() {
+    let spans = traced_call(|builder| builder.with_import_tracing());
+    assert!(
+        spans.contains(&("wasm_plugin::import", Some("wasm_plugin::call"))),
+        "{:?}",
+        spans
+    );
+}
+
+#[test]
+fn imports_are_not_traced_unless_asked() {
+    let spans = traced_call(|builder| builder);
+    assert!(
+        spans.iter().any(|(name, _)| *name == "wasm_plugin::call"),
+        "{:?}",
+        spans
+    );
+    assert!(
+        spans.iter().all(|(name, _)| *name != "wasm_plugin::import"),
+        "{:?}",
+        spans
+    );
+}
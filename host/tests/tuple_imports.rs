@@ -0,0 +1,51 @@
+#![cfg(any(
+    feature = "serialize_bincode",
+    feature = "serialize_json",
+    feature = "serialize_cbor"
+))]
+
+mod common;
+
+use wasm_plugin_host::serialization::Serializable;
+
+// WAT for a function `name` which sends `args`, serialized the way the host
+// serializes a tuple argument for `call_function_with_argument`, to the
+// import of the same name and returns what the import returned
+fn forward(name: &str, address: u32, args: impl Serializable) -> (String, String) {
+    let message = args.serialize().unwrap();
+    let data: String = message.iter().map(|b| format!("\\{:02x}", b)).collect();
+    let import = format!(
+        r#"(import "env" "wasm_plugin_imported__{name}" (func ${name} (param i32 i32) (result i64)))"#,
+        name = name
+    );
+    let body = format!(
+        r#"(data (i32.const {address}) "{data}")
+           (func (export "wasm_plugin_exported__{name}") (result i64)
+             (call ${name} (i32.const {address}) (i32.const {len})))"#,
+        name = name,
+        address = address,
+        data = data,
+        len = message.len()
+    );
+    (import, body)
+}
+
+#[test]
+fn a_two_tuple_reaches_a_two_argument_closure() {
+    let (imports, body) = forward("add", 256, (2i32, 3i32));
+    let mut plugin = common::builder(&imports, &body)
+        .import_function("add", |a: i32, b: i32| a + b)
+        .finish()
+        .unwrap();
+    assert_eq!(plugin.call_function::<i32>("add").unwrap(), 5);
+}
+
+#[test]
+fn a_three_tuple_reaches_a_three_argument_closure() {
+    let (imports, body) = forward("interpolate", 256, (0.0f64, 10.0f64, 0.25f64));
+    let mut plugin = common::builder(&imports, &body)
+        .import_function("interpolate", |x: f64, y: f64, t: f64| x + (y - x) * t)
+        .finish()
+        .unwrap();
+    assert_eq!(plugin.call_function::<f64>("interpolate").unwrap(), 2.5);
+}
@@ -0,0 +1,183 @@
+#![doc(html_root_url = "https://docs.rs/wasm_plugin_test/0.2.0")]
+#![deny(missing_docs)]
+
+//! Helpers for testing code which calls into plugins hosted with
+//! `wasm_plugin_host`, without building the plugins to WASM.
+//!
+//! A `MockPlugin` has the same `call_function` and
+//! `call_function_with_argument` methods as `WasmPlugin` but runs Rust
+//! closures registered under the functions' names instead. Arguments and
+//! return values still go through the host's serialization so a mismatch
+//! between the types the host sends and the ones a handler expects shows up
+//! as the same error a real plugin would cause.
+//!
+//! ```rust
+//! use wasm_plugin_test::MockPlugin;
+//!
+//! let mut plugin = MockPlugin::new();
+//! plugin.register("hello", || "Hello, world!".to_string());
+//! plugin.register("double", |x: u32| x * 2);
+//!
+//! let greeting: String = plugin.call_function("hello").unwrap();
+//! assert_eq!(greeting, "Hello, world!");
+//! let doubled: u32 = plugin.call_function_with_argument("double", &21u32).unwrap();
+//! assert_eq!(doubled, 42);
+//! ```
+//!
+//! Code which should work with both real and mock plugins can take a closure
+//! or a small trait of its own implemented for `WasmPlugin` and `MockPlugin`.
+
+use std::collections::HashMap;
+
+use wasm_plugin_host::{
+    errors::{self, FunctionNotFound, WasmPluginError},
+    serialization::{self, Deserializable, Serializable},
+    DEFAULT_DESERIALIZATION_LIMIT,
+};
+
+type Handler = Box<dyn FnMut(Option<&[u8]>) -> errors::Result<Vec<u8>>>;
+
+/// A stand-in for a `WasmPlugin` whose functions are Rust closures.
+#[derive(Default)]
+pub struct MockPlugin {
+    handlers: HashMap<String, Handler>,
+}
+
+impl MockPlugin {
+    /// Create a mock with no functions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `handler` as the function `fn_name`, replacing any function
+    /// already registered under that name.
+    ///
+    /// The handler can take no arguments or a single one, which can be a
+    /// tuple for functions which take several.
+    ///
+    /// ```rust
+    /// # use wasm_plugin_test::MockPlugin;
+    /// let mut plugin = MockPlugin::new();
+    /// plugin.register("add", |(a, b): (i32, i32)| a + b);
+    ///
+    /// let sum: i32 = plugin.call_function_with_argument("add", &(1, 2)).unwrap();
+    /// assert_eq!(sum, 3);
+    /// // Called the way a real plugin's function would fail to be called
+    /// assert!(plugin.call_function::<i32>("add").is_err());
+    /// assert!(plugin.call_function::<i32>("subtract").is_err());
+    /// ```
+    pub fn register<Args>(
+        &mut self,
+        fn_name: &str,
+        handler: impl MockFn<Args> + 'static,
+    ) -> &mut Self {
+        let name = fn_name.to_string();
+        let mut handler = handler;
+        self.handlers.insert(
+            fn_name.to_string(),
+            Box::new(move |argument| {
+                let expected = handler.arity();
+                let got = if argument.is_some() { 1 } else { 0 };
+                if expected != got {
+                    return Err(WasmPluginError::ArityMismatch {
+                        function: name.clone(),
+                        expected,
+                        got,
+                    });
+                }
+                handler.call(argument)
+            }),
+        );
+        self
+    }
+
+    /// Whether a function has been registered as `fn_name`.
+    pub fn has_function(&self, fn_name: &str) -> bool {
+        self.handlers.contains_key(fn_name)
+    }
+
+    /// Call the function `fn_name` with `args`, as
+    /// `WasmPlugin::call_function_with_argument` does.
+    pub fn call_function_with_argument<ReturnType, Args>(
+        &mut self,
+        fn_name: &str,
+        args: &Args,
+    ) -> errors::Result<ReturnType>
+    where
+        Args: Serializable,
+        ReturnType: Deserializable,
+    {
+        let message = args.serialize()?;
+        let buff = self.call_handler(fn_name, Some(&message))?;
+        serialization::deserialize_return(fn_name, &buff, DEFAULT_DESERIALIZATION_LIMIT)
+    }
+
+    /// Call the function `fn_name`, which takes no arguments, as
+    /// `WasmPlugin::call_function` does.
+    pub fn call_function<ReturnType>(&mut self, fn_name: &str) -> errors::Result<ReturnType>
+    where
+        ReturnType: Deserializable,
+    {
+        let buff = self.call_handler(fn_name, None)?;
+        serialization::deserialize_return(fn_name, &buff, DEFAULT_DESERIALIZATION_LIMIT)
+    }
+
+    fn call_handler(&mut self, fn_name: &str, argument: Option<&[u8]>) -> errors::Result<Vec<u8>> {
+        if let Some(handler) = self.handlers.get_mut(fn_name) {
+            return handler(argument);
+        }
+        let mut available: Vec<String> = self.handlers.keys().cloned().collect();
+        available.sort();
+        Err(WasmPluginError::FunctionNotFound(FunctionNotFound {
+            function: fn_name.to_string(),
+            export_name: format!("wasm_plugin_exported__{}", fn_name),
+            plugin: None,
+            available,
+        }))
+    }
+}
+
+/// A closure which can be registered as a function of a `MockPlugin`.
+///
+/// This is implemented for closures taking no arguments or one argument
+/// which can be deserialized and returning a value which can be serialized.
+/// `Args` only exists to keep those implementations apart.
+pub trait MockFn<Args> {
+    #[doc(hidden)]
+    fn arity(&self) -> usize;
+    #[doc(hidden)]
+    fn call(&mut self, argument: Option<&[u8]>) -> errors::Result<Vec<u8>>;
+}
+
+#[doc(hidden)]
+pub struct NoArgs;
+
+impl<ReturnType, F> MockFn<NoArgs> for F
+where
+    F: FnMut() -> ReturnType,
+    ReturnType: Serializable,
+{
+    fn arity(&self) -> usize {
+        0
+    }
+
+    fn call(&mut self, _argument: Option<&[u8]>) -> errors::Result<Vec<u8>> {
+        self().serialize()
+    }
+}
+
+impl<Arg, ReturnType, F> MockFn<Arg> for F
+where
+    F: FnMut(Arg) -> ReturnType,
+    Arg: Deserializable,
+    ReturnType: Serializable,
+{
+    fn arity(&self) -> usize {
+        1
+    }
+
+    fn call(&mut self, argument: Option<&[u8]>) -> errors::Result<Vec<u8>> {
+        let argument = Arg::deserialize(argument.unwrap_or_default())?;
+        self(argument).serialize()
+    }
+}